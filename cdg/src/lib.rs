@@ -37,14 +37,15 @@ pub struct Tile {
 }
 
 impl Tile {
-    /// Convert a tile from the subchannel data found in a Tile command
-    fn from(data: &[u8]) -> Self {
+    /// Convert a tile from the subchannel data found in a Tile
+    /// command. Returns None if the data is the wrong size.
+    fn from(data: &[u8]) -> Option<Self> {
         if data.len() != 16 {
-            panic!("Data is the wrong size");
+            return None;
         }
         let mut content = [0; 12];
         iter_copy(content[..].iter_mut(), data[4..16].iter().map(|x| x & 0x3F));
-        Tile{
+        Some(Tile{
             pos: (data[3] & 0x3F, data[2] & 0x1F),
             color: (data[0] & 0x0F, data[1] & 0x0F), 
             content: content,
@@ -52,7 +53,7 @@ impl Tile {
             // have access to the real specs, so I don't know if it's
             // accurate.
             channel: (data[0] & 0x30) >> 2 | (data[1] & 0x30) >> 4,
-        }
+        })
     }
 
     /// Return the CLUT index of the pixel at x,y
@@ -124,6 +125,11 @@ impl RgbColor {
         RgbColor((data0 as u16 & 0x3F) << 6 | (data1 as u16 & 0x3F))
     }
 
+    /// The inverse of `from_subchannel`, with the P and Q bits clear
+    pub fn to_subchannel(&self) -> [u8; 2] {
+        [(self.0 >> 6) as u8 & 0x3F, self.0 as u8 & 0x3F]
+    }
+
     /// Convert from an RGB triplet. The individual channels are each
     /// truncated to four bits.
     pub fn from_rgb(r: u8, g: u8, b: u8) -> RgbColor {
@@ -182,9 +188,9 @@ pub enum Command {
     LoadPalette{offset: u8, clut: [RgbColor; 8]},
 }
 
-fn parse_scroll(data: &[u8], is_copy: bool) -> Command {
+fn parse_scroll(data: &[u8], is_copy: bool) -> Option<Command> {
     if data.len() != 16 {
-        panic!("INvalid data length");
+        return None;
     }
     let color = if is_copy { None } else { Some(data[0] & 0xF) };
     let h_scroll_cmd = ScrollCommand::from_u8(data[1]);
@@ -192,16 +198,16 @@ fn parse_scroll(data: &[u8], is_copy: bool) -> Command {
     let v_scroll_cmd = ScrollCommand::from_u8(data[2]);
     let v_scroll_off = data[2] & 0x0F;
         
-    Command::Scroll{
+    Some(Command::Scroll{
         color: color,
         cmd: (h_scroll_cmd, v_scroll_cmd),
         offset: (h_scroll_off, v_scroll_off),
-    }
+    })
 }
 
 fn parse_clut(data: &[u8]) -> [RgbColor; 8] {
     let mut result = [RgbColor::from_subchannel(0,0); 8];
-    iter_copy(result.iter_mut(), data.chunks(2).map(|c| RgbColor::from_subchannel(c[0], c[1])));
+    iter_copy(result.iter_mut(), data.chunks(2).filter(|c| c.len() == 2).map(|c| RgbColor::from_subchannel(c[0], c[1])));
     result
 }
 
@@ -223,10 +229,10 @@ pub fn decode_subchannel_cmd(block: &[u8]) -> Option<Command> {
     match block[1] & 0x3f {
         1 => Some(Command::MemoryPreset{color: data[0] & 0xF, repeat: data[1] & 0xF}),
         2 => Some(Command::BorderPreset{color: data[0] & 0xF}),
-        6 => Some(Command::TileNormal{tile: Tile::from(data)}),
-        38 => Some(Command::TileXOR{tile: Tile::from(data)}),
-        20 => Some(parse_scroll(data, false)),
-        24 => Some(parse_scroll(data, true)),
+        6 => Tile::from(data).map(|tile| Command::TileNormal{tile: tile}),
+        38 => Tile::from(data).map(|tile| Command::TileXOR{tile: tile}),
+        20 => parse_scroll(data, false),
+        24 => parse_scroll(data, true),
         28 => Some(Command::SetTransparent{color: data[0] & 0xF}),
         30 => Some(Command::LoadPalette{offset: 0, clut: parse_clut(data)}),
         31 => Some(Command::LoadPalette{offset: 8, clut: parse_clut(data)}),
@@ -242,10 +248,9 @@ pub struct SectorIter<'a> {
 }
 
 impl <'a> SectorIter<'a> {
-    /// Create a new SectorIter from a sector buffer. The buffer must be at least 96 bytes long, and it must be a multiple of 24 bytes.
+    /// Create a new SectorIter from a sector buffer. The buffer is
+    /// normally 96 bytes long; a trailing partial block is ignored.
     pub fn new(sector: &'a [u8]) -> Self {
-        assert!(sector.len() >= 96);
-        assert!(sector.len() % 24 == 0);
         SectorIter{
             sector_iter: sector.chunks(24),
         }
@@ -255,10 +260,14 @@ impl <'a> Iterator for SectorIter<'a> {
     type Item = Command;
     
     fn next(&mut self) -> Option<Self::Item> {
-        match self.sector_iter.next() {
-            None => None,
-            Some(cmd) => decode_subchannel_cmd(cmd).or_else(|| self.next())
+        // A partial block at the end decodes to None like any other
+        // invalid command
+        while let Some(block) = self.sector_iter.next() {
+            if let Some(cmd) = decode_subchannel_cmd(block) {
+                return Some(cmd);
+            }
         }
+        None
     }
 }
 
@@ -305,7 +314,22 @@ impl <R: Read> SubchannelStreamIter<R> {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
     }
+
+    #[test]
+    fn short_and_garbage_sectors_decode_without_panicking() {
+        let mut sector = [0xFFu8; 100];
+        for (i, b) in sector.iter_mut().enumerate() {
+            *b = (i * 37) as u8;
+        }
+        sector[0] = 9;
+        sector[1] = 6;
+        assert_eq!(SectorIter::new(&sector[..30]).count(), 1);
+        assert_eq!(SectorIter::new(&sector[..10]).count(), 0);
+        assert!(decode_subchannel_cmd(&sector[..23]).is_none());
+    }
 }
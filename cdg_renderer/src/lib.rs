@@ -197,6 +197,32 @@ impl CdgInterpreter {
         }
     }
 
+    /// The current color lookup table
+    pub fn palette(&self) -> &[RgbColor; 16] {
+        &self.clut
+    }
+
+    /// The palette index of the pixel at (x, y) on screen
+    pub fn color_index(&self, x: usize, y: usize) -> u8 {
+        self.content[self.map_pxrow(y)][self.map_pxcol(x)]
+    }
+
+    /// The palette index that is drawn transparent, if any
+    pub fn transparent_color(&self) -> Option<u8> {
+        if self.transparent < 16 { Some(self.transparent) } else { None }
+    }
+
+    /// The palette index of the border
+    pub fn border_color(&self) -> u8 {
+        self.border
+    }
+
+    /// How far scanout starts into the first tile, in pixels across
+    /// and down
+    pub fn scroll_offset(&self) -> (u8, u8) {
+        (self.pixel_shift.x as u8, self.pixel_shift.y as u8)
+    }
+
     pub fn dirty(&self) -> Option<Rectangle<u16>> {
         self.dirty
     }
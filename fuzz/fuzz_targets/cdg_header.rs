@@ -0,0 +1,21 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate ogk;
+
+use ogk::cdg::CdgHeader;
+
+// The first packet is the main header; the rest are fed through as
+// extra headers and then as data packets
+fuzz_target!(|data: &[u8]| {
+    let mut packets = data.split(|b| *b == 0xFF);
+    let mut header = match packets.next().and_then(CdgHeader::from_bytes) {
+        Some(header) => header,
+        None => return,
+    };
+    let _ = header.to_bytes();
+    for packet in packets {
+        if !header.process_header(packet) {
+            let _ = header.decode_packet(packet);
+        }
+    }
+});
@@ -0,0 +1,34 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate ogk;
+
+use std::io::Cursor;
+use ogk::file::OgkFile;
+
+// Everything a player would do with a file: list the streams, read
+// their metadata and contents, and seek
+fuzz_target!(|data: &[u8]| {
+    let mut file = match OgkFile::from_reader(Cursor::new(data)) {
+        Ok(file) => file,
+        Err(_) => return,
+    };
+    let _ = file.duration();
+    for i in 0..file.streams().len() {
+        let _ = file.metadata(i).count();
+        if let Some(commands) = file.commands(i) {
+            for command in commands {
+                if command.is_err() {
+                    break;
+                }
+            }
+        }
+        if let Some(frames) = file.frames(i) {
+            for frame in frames {
+                if frame.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    let _ = file.seek(1000_000);
+});
@@ -0,0 +1,14 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate ogk;
+
+use ogk::mp3::{self,AudioRole,FrameHeader,Mp3Header,OggMp3Decoder};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Mp3Header::parse(data);
+    let _ = OggMp3Decoder::new(data);
+    let _ = AudioRole::from_header(data);
+    let _ = FrameHeader::parse(data);
+    let _ = mp3::parse_info_frame(data);
+    let _ = mp3::id3v2_text(data, b"TIT2");
+});
@@ -0,0 +1,10 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate ogk;
+
+use ogk::ogg::OggPageSource;
+
+fuzz_target!(|data: &[u8]| {
+    let mut source = OggPageSource::from_slice(data);
+    while let Ok(Some(_)) = source.next_page() {}
+});
@@ -0,0 +1,8 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate cdg;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = cdg::decode_subchannel_cmd(data);
+    for _ in cdg::SectorIter::new(data) {}
+});
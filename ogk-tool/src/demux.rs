@@ -0,0 +1,365 @@
+use std::cell::Cell;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self,Write,BufWriter};
+use std::path::{Path,PathBuf};
+use std::rc::Rc;
+
+use ogk::ogg::{BitstreamDecoder,OggDemux};
+use ogk::cdg::{CdgGranule,CdgHeader,PacketType};
+use ogk::mp3::{Mp3Granule,OggMp3Decoder};
+use ogk::registry::Registry;
+
+/// If a page claims to be further than this many sectors (an hour)
+/// past the data we've seen, believe the data rather than the page.
+pub const MAX_CDG_GAP: u64 = 75 * 60 * 60;
+
+/// An output file. Write errors are reported once, after which
+/// further writes are dropped.
+struct Output {
+    name: OsString,
+    writer: BufWriter<fs::File>,
+    ok: bool,
+    /// Shared by all outputs, so that the caller can tell if any failed
+    failed: Rc<Cell<bool>>,
+}
+
+impl Output {
+    fn create(name: OsString, overwrite: bool, failed: Rc<Cell<bool>>) -> Option<Self> {
+        let mut options = fs::OpenOptions::new();
+        if overwrite {
+            options.write(true).create(true).truncate(true);
+        } else {
+            options.write(true).create_new(true);
+        }
+        match options.open(&name) {
+            Ok(file) => {
+                println!("Writing {:?}", name);
+                Some(Output{
+                    name: name,
+                    writer: BufWriter::new(file),
+                    ok: true,
+                    failed: failed,
+                })
+            },
+            Err(e) => {
+                println!("Failed to create {:?}: {}", name, e);
+                failed.set(true);
+                None
+            },
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        if self.ok {
+            let result = self.writer.write_all(data);
+            self.check(result);
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.ok {
+            let result = self.writer.flush();
+            self.check(result);
+        }
+    }
+
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(e) = result {
+            println!("Failed to write {:?}: {}", self.name, e);
+            self.ok = false;
+            self.failed.set(true);
+        }
+    }
+}
+
+/// Turns an OggCDG stream back into a raw .cdg file
+struct CdgExtractor {
+    header: CdgHeader,
+    out: Output,
+    /// Sectors written so far
+    sectors: u64,
+}
+
+impl CdgExtractor {
+    fn pad_to(&mut self, sector: u64) {
+        if sector > self.sectors + MAX_CDG_GAP {
+            println!("{:?}: ignoring implausible granule position for sector {}", self.out.name, sector);
+            return;
+        }
+        while self.sectors < sector {
+            self.out.write(&[0; 96]);
+            self.sectors += 1;
+        }
+    }
+}
+
+impl BitstreamDecoder for CdgExtractor {
+    fn map_granule(&self, granule: u64) -> u64 {
+        CdgGranule::from_u64(granule).micros()
+    }
+
+    fn num_headers(&self) -> usize { self.header.num_headers() }
+
+    fn process_header(&mut self, header: &[u8]) {
+        if !self.header.process_header(header) {
+            println!("{:?}: skipping CDG header packet of unknown type", self.out.name);
+        }
+    }
+
+    fn process_packet(&mut self, packet: &[u8], last_granule: u64) -> u64 {
+        if packet.len() < 2 {
+            println!("{:?}: skipping truncated CDG packet", self.out.name);
+            return last_granule;
+        }
+        match self.header.decode_packet(packet) {
+            Some((PacketType::Command, sectors)) => {
+                let written = (sectors.len() as u64 + 95) / 96;
+                self.out.write(&sectors);
+                self.out.write(&vec![0; (written * 96) as usize - sectors.len()]);
+                self.sectors += written;
+                // Make up any shortfall with empty sectors, so that
+                // everything after stays in time
+                let declared = packet[1] as u64;
+                if declared > written {
+                    let end = self.sectors + declared - written;
+                    self.pad_to(end);
+                }
+            },
+            // Keyframes are derived data; a .cdg has no place for them
+            Some((PacketType::Keyframe, _)) => (),
+            Some((typ, _)) => println!("{:?}: skipping CDG packet of unknown type {}", self.out.name, typ.to_u8()),
+            None => println!("{:?}: skipping CDG packet that failed to decompress", self.out.name),
+        }
+        CdgGranule{sector: self.sectors, keyframe: CdgGranule::from_u64(last_granule).keyframe}.to_u64()
+    }
+
+    fn page_done(&mut self, granule: u64) {
+        // Only ever moves forward; pages lost before this one become
+        // empty sectors
+        if granule != !0 {
+            self.pad_to(CdgGranule::from_u64(granule).sector);
+        }
+    }
+
+    fn notice_gap(&mut self) {}
+
+    fn finish(&mut self) {
+        self.out.finish();
+    }
+}
+
+/// Turns an OggMP3 stream back into an .mp3 file
+struct Mp3Extractor {
+    out: Output,
+    clock: Mp3Granule,
+    samples_per_frame: u32,
+    aux_headers: usize,
+    /// Auxiliary headers that belong at the start of the file and are
+    /// still to come
+    file_headers: usize,
+}
+
+impl BitstreamDecoder for Mp3Extractor {
+    fn map_granule(&self, granule: u64) -> u64 {
+        self.clock.micros(granule)
+    }
+
+    fn num_headers(&self) -> usize { self.aux_headers + 1 }
+
+    fn process_header(&mut self, header: &[u8]) {
+        // The tag and info frame headers come first, in the order
+        // they appeared in the original file
+        if self.file_headers > 0 {
+            self.file_headers -= 1;
+            self.out.write(header);
+        }
+    }
+
+    fn process_packet(&mut self, packet: &[u8], last_granule: u64) -> u64 {
+        self.out.write(packet);
+        last_granule + self.samples_per_frame as u64
+    }
+
+    fn notice_gap(&mut self) {}
+
+    fn finish(&mut self) {
+        self.out.finish();
+    }
+}
+
+/// Hands out output files as streams turn up
+struct Outputs {
+    prefix: PathBuf,
+    overwrite: bool,
+    cdg_count: Cell<usize>,
+    mp3_count: Cell<usize>,
+    failed: Rc<Cell<bool>>,
+}
+
+impl Outputs {
+    /// The first file of each type is PREFIX.ext, the next
+    /// PREFIX.2.ext and so on
+    fn create(&self, extension: &str, count: &Cell<usize>) -> Option<Output> {
+        count.set(count.get() + 1);
+        let mut name = self.prefix.as_os_str().to_owned();
+        if count.get() > 1 {
+            name.push(format!(".{}", count.get()));
+        }
+        name.push(".");
+        name.push(extension);
+        Output::create(name, self.overwrite, self.failed.clone())
+    }
+}
+
+fn start_cdg(header: &[u8], outputs: &Outputs) -> Option<Box<BitstreamDecoder>> {
+    let cdg_header = match CdgHeader::from_bytes(header) {
+        Some(cdg_header) => cdg_header,
+        None => {
+            println!("Skipping OggCDG stream with an unsupported header");
+            return None;
+        },
+    };
+    outputs.create("cdg", &outputs.cdg_count).map(|out| {
+        Box::new(CdgExtractor{
+            header: cdg_header,
+            out: out,
+            sectors: 0,
+        }) as Box<BitstreamDecoder>
+    })
+}
+
+fn start_mp3(header: &[u8], outputs: &Outputs) -> Option<Box<BitstreamDecoder>> {
+    let frames = match OggMp3Decoder::new(header) {
+        Some(frames) => frames,
+        None => {
+            println!("Skipping OggMP3 stream with an unsupported header");
+            return None;
+        },
+    };
+    let flags = frames.header().flags;
+    let representative = frames.representative();
+    outputs.create("mp3", &outputs.mp3_count).map(|out| {
+        Box::new(Mp3Extractor{
+            out: out,
+            clock: frames.clock(),
+            samples_per_frame: representative.samples(),
+            aux_headers: frames.header().aux_headers,
+            file_headers: (flags & 1) as usize + (flags >> 3 & 1) as usize,
+        }) as Box<BitstreamDecoder>
+    })
+}
+
+/// The built-in codecs, with extractors as their decoders
+fn registry(outputs: Rc<Outputs>) -> Registry {
+    let mut registry = Registry::builtin();
+    let cdg_outputs = outputs.clone();
+    registry.set_decoder("OggCDG", Box::new(move |header| start_cdg(header, &cdg_outputs).map(|decoder| (decoder, ()))));
+    registry.set_decoder("OggMP3", Box::new(move |header| start_mp3(header, &outputs).map(|decoder| (decoder, ()))));
+    registry
+}
+
+/// Write each stream of an OGK file to `prefix` plus the extension
+/// for its format. Existing files are only replaced if `overwrite` is
+/// set. Returns false if anything went wrong.
+pub fn demux(input: &Path, prefix: &Path, overwrite: bool) -> bool {
+    let file = match fs::File::open(input) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("Failed to open OGK file {:?}: {}", input, e);
+            return false;
+        },
+    };
+    let failed = Rc::new(Cell::new(false));
+    let outputs = Rc::new(Outputs{
+        prefix: prefix.to_owned(),
+        overwrite: overwrite,
+        cdg_count: Cell::new(0),
+        mp3_count: Cell::new(0),
+        failed: failed.clone(),
+    });
+    let registry = registry(outputs);
+    let demux = OggDemux::new_tolerant(io::BufReader::new(file), move |header| {
+        match registry.find(header) {
+            None => println!("Skipping stream of unknown type"),
+            Some(codec) => if let Err(e) = (codec.parse_header)(header) {
+                println!("Skipping {} stream: {}", codec.name, e);
+            },
+        }
+        registry.identify(header)
+    });
+    let mut demux = match demux {
+        Ok(demux) => demux,
+        Err(e) => {
+            println!("Failed to read {:?}: {}", input, e);
+            return false;
+        },
+    };
+    while !demux.is_eof() {
+        if let Err(e) = demux.pump_page() {
+            println!("Failed to read {:?}: {}", input, e);
+            return false;
+        }
+    }
+    let diagnostics = demux.diagnostics();
+    if diagnostics != Default::default() {
+        println!("{:?} is damaged: {:?}", input, diagnostics);
+    }
+    !failed.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::fs;
+    use ogk::ogg;
+    use ogk::cdg::{CdgGranule,CdgHeader,Compression};
+    use test_util::{self,PacketCoder};
+    use super::*;
+
+    #[test]
+    fn round_trips_to_the_muxer_input() {
+        // The tag and info frame come back in front of the audio, in
+        // their original order
+        let mp3 = test_util::mp3(40, true, true);
+        let cdg = test_util::cdg(200);
+        let dir = test_util::scratch_dir("demux-round-trip");
+        let input = dir.join("song.ogk");
+        fs::write(&input, test_util::mux_song(mp3.clone(), cdg.clone(), true)).unwrap();
+        assert!(demux(&input, &dir.join("out"), false));
+        assert!(fs::read(dir.join("out.mp3")).unwrap() == mp3);
+        assert!(fs::read(dir.join("out.cdg")).unwrap() == cdg);
+        // Existing files are left alone without --force
+        assert!(!demux(&input, &dir.join("out"), false));
+    }
+
+    #[test]
+    fn pads_packets_that_declare_more_sectors_than_they_hold() {
+        let mut header = CdgHeader::new();
+        header.compression = Compression::None;
+        let packet = |declared: u8, sectors: &[u8], end: u64| {
+            let mut content = vec![0, declared];
+            content.extend_from_slice(sectors);
+            ogg::Packet{
+                content: content,
+                timestamp: CdgGranule{sector: end, keyframe: 0}.to_u64(),
+            }
+        };
+        let cdg = test_util::cdg(3);
+        let packets: VecDeque<_> = vec![
+            packet(5, &cdg[..2 * 96], 5),
+            packet(1, &cdg[2 * 96..], 6),
+        ].into_iter().collect();
+        let dir = test_util::scratch_dir("demux-padding");
+        let input = dir.join("short.ogk");
+        fs::write(&input, test_util::mux(vec![Box::new(PacketCoder{
+            headers: vec![header.to_bytes()],
+            packets: packets,
+        })], false)).unwrap();
+        assert!(demux(&input, &dir.join("out"), false));
+        let mut expected = cdg[..2 * 96].to_vec();
+        expected.extend(vec![0; 3 * 96]);
+        expected.extend_from_slice(&cdg[2 * 96..]);
+        assert!(fs::read(dir.join("out.cdg")).unwrap() == expected);
+    }
+}
@@ -0,0 +1,966 @@
+//! Cutting and joining OGK files. The audio is never decoded: MP3
+//! frames are copied whole, and the encoder delay and padding in the
+//! stream header tell players which samples to drop.
+
+use std::cell::RefCell;
+use std::cmp::{max,min};
+use std::collections::{HashMap,HashSet};
+use std::fs;
+use std::io::{self,Cursor,Write};
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
+use std::vec;
+
+use cdg::SectorIter;
+use cdg_renderer::CdgInterpreter;
+use ogk::ogg::{self,BitstreamCoder,BitstreamDecoder,OggDemux,OggPageSource,OgkMux,Page,SerialSource,PAGE_BOS};
+use ogk::cdg::{CdgGranule,CdgHeader,Compression,OggCdgCoder,PacketType};
+use ogk::index::{self,SeekIndex};
+use ogk::mp3::{self,GaplessInfo,Mp3Granule,Mp3Header};
+use ogk::registry::Registry;
+
+use demux::MAX_CDG_GAP;
+
+/// An OggMP3 stream, read into memory
+struct Mp3Track {
+    header: Mp3Header,
+    /// The representative frame header, as stored in the stream header
+    pseudoheader: [u8; 4],
+    tag: Option<Vec<u8>>,
+    frames: Vec<Vec<u8>>,
+}
+
+impl Mp3Track {
+    fn samples_per_frame(&self) -> u64 {
+        self.header.samples_per_frame as u64
+    }
+
+    /// The granule position of the end of the audio, padding excluded
+    fn end(&self) -> u64 {
+        (self.frames.len() as u64 * self.samples_per_frame()).saturating_sub(self.header.gapless.padding as u64)
+    }
+}
+
+/// An OggCDG stream, read into memory as the raw .cdg it holds
+struct CdgTrack {
+    compression: Compression,
+    dictionary: Option<Vec<u8>>,
+    sectors: Vec<u8>,
+}
+
+impl CdgTrack {
+    fn len(&self) -> u64 {
+        self.sectors.len() as u64 / 96
+    }
+}
+
+/// Every stream of a single-link OGK file
+struct Song {
+    mp3: Vec<Mp3Track>,
+    cdg: Vec<CdgTrack>,
+    /// Whether the file has a seek index
+    indexed: bool,
+}
+
+/// Reads an OggMP3 stream into its `Mp3Track`
+struct Mp3Reader {
+    song: Rc<RefCell<Song>>,
+    index: usize,
+    clock: Mp3Granule,
+    aux_headers: usize,
+    /// Header packets after the first seen so far
+    headers_seen: usize,
+}
+
+impl BitstreamDecoder for Mp3Reader {
+    fn map_granule(&self, granule: u64) -> u64 {
+        self.clock.micros(granule)
+    }
+
+    fn num_headers(&self) -> usize { self.aux_headers + 1 }
+
+    fn process_header(&mut self, header: &[u8]) {
+        // The tag header comes first, if there is one
+        let track = &mut self.song.borrow_mut().mp3[self.index];
+        if self.headers_seen == 0 && track.header.flags & 1 != 0 {
+            track.tag = Some(header.to_owned());
+        }
+        self.headers_seen += 1;
+    }
+
+    fn process_packet(&mut self, packet: &[u8], last_granule: u64) -> u64 {
+        let track = &mut self.song.borrow_mut().mp3[self.index];
+        track.frames.push(packet.to_owned());
+        last_granule + track.samples_per_frame()
+    }
+
+    fn notice_gap(&mut self) {}
+
+    fn finish(&mut self) {}
+}
+
+/// Reads an OggCDG stream into its `CdgTrack`
+struct CdgReader {
+    song: Rc<RefCell<Song>>,
+    index: usize,
+    header: CdgHeader,
+}
+
+impl CdgReader {
+    fn pad_to(&mut self, sector: u64) {
+        let track = &mut self.song.borrow_mut().cdg[self.index];
+        if sector > track.len() + MAX_CDG_GAP {
+            println!("Ignoring implausible granule position for sector {}", sector);
+        } else if sector > track.len() {
+            track.sectors.resize(sector as usize * 96, 0);
+        }
+    }
+}
+
+impl BitstreamDecoder for CdgReader {
+    fn map_granule(&self, granule: u64) -> u64 {
+        CdgGranule::from_u64(granule).micros()
+    }
+
+    fn num_headers(&self) -> usize { self.header.num_headers() }
+
+    fn process_header(&mut self, header: &[u8]) {
+        if !self.header.process_header(header) {
+            println!("Skipping CDG header packet of unknown type");
+        }
+        self.song.borrow_mut().cdg[self.index].dictionary = self.header.dictionary.clone();
+    }
+
+    fn process_packet(&mut self, packet: &[u8], last_granule: u64) -> u64 {
+        if packet.len() < 2 {
+            println!("Skipping truncated CDG packet");
+            return last_granule;
+        }
+        match self.header.decode_packet(packet) {
+            Some((PacketType::Command, sectors)) => {
+                let end = {
+                    let track = &mut self.song.borrow_mut().cdg[self.index];
+                    track.sectors.extend_from_slice(&sectors);
+                    let written = (track.sectors.len() + 95) / 96;
+                    track.sectors.resize(written * 96, 0);
+                    track.len() + (packet[1] as u64).saturating_sub((sectors.len() as u64 + 95) / 96)
+                };
+                // As in demux, make up any shortfall with empty sectors
+                self.pad_to(end);
+            },
+            Some((PacketType::Keyframe, _)) => (),
+            Some((typ, _)) => println!("Skipping CDG packet of unknown type {}", typ.to_u8()),
+            None => println!("Skipping CDG packet that failed to decompress"),
+        }
+        let sector = self.song.borrow().cdg[self.index].len();
+        CdgGranule{sector: sector, keyframe: CdgGranule::from_u64(last_granule).keyframe}.to_u64()
+    }
+
+    fn page_done(&mut self, granule: u64) {
+        if granule != !0 {
+            self.pad_to(CdgGranule::from_u64(granule).sector);
+        }
+    }
+
+    fn notice_gap(&mut self) {}
+
+    fn finish(&mut self) {}
+}
+
+fn start_mp3(header: &[u8], song: &Rc<RefCell<Song>>) -> Option<Box<BitstreamDecoder>> {
+    let parsed = match Mp3Header::from_bytes(header) {
+        Some(parsed) if parsed.flags & 4 == 0 && parsed.samples_per_frame != 0 && parsed.sample_frequency != 0 => parsed,
+        _ => {
+            println!("Dropping OggMP3 stream with an unsupported header");
+            return None;
+        },
+    };
+    let mut pseudoheader = [0; 4];
+    pseudoheader.copy_from_slice(&header[12..16]);
+    let mut tracks = song.borrow_mut();
+    tracks.mp3.push(Mp3Track{
+        header: parsed,
+        pseudoheader: pseudoheader,
+        tag: None,
+        frames: Vec::new(),
+    });
+    Some(Box::new(Mp3Reader{
+        song: song.clone(),
+        index: tracks.mp3.len() - 1,
+        clock: Mp3Granule{sample_rate: parsed.sample_frequency, delay: parsed.gapless.delay},
+        aux_headers: parsed.aux_headers,
+        headers_seen: 0,
+    }))
+}
+
+fn start_cdg(header: &[u8], song: &Rc<RefCell<Song>>) -> Option<Box<BitstreamDecoder>> {
+    let parsed = match CdgHeader::from_bytes(header) {
+        Some(parsed) => parsed,
+        None => {
+            println!("Dropping OggCDG stream with an unsupported header");
+            return None;
+        },
+    };
+    let mut tracks = song.borrow_mut();
+    tracks.cdg.push(CdgTrack{
+        compression: parsed.compression,
+        dictionary: None,
+        sectors: Vec::new(),
+    });
+    Some(Box::new(CdgReader{
+        song: song.clone(),
+        index: tracks.cdg.len() - 1,
+        header: parsed,
+    }))
+}
+
+/// Read every stream of `input` into memory. Streams that can't be
+/// edited are dropped, with a message. Chained files are refused, as
+/// there'd be no telling which song to edit.
+fn read_song(input: &Path) -> Option<Song> {
+    let file = match fs::File::open(input) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("Failed to open OGK file {:?}: {}", input, e);
+            return None;
+        },
+    };
+    let song = Rc::new(RefCell::new(Song{
+        mp3: Vec::new(),
+        cdg: Vec::new(),
+        indexed: false,
+    }));
+    let mut registry = Registry::builtin();
+    let mp3_song = song.clone();
+    registry.set_decoder("OggMP3", Box::new(move |header| start_mp3(header, &mp3_song).map(|decoder| (decoder, ()))));
+    let cdg_song = song.clone();
+    registry.set_decoder("OggCDG", Box::new(move |header| start_cdg(header, &cdg_song).map(|decoder| (decoder, ()))));
+    let demux = OggDemux::new_tolerant(io::BufReader::new(file), move |header| {
+        match registry.find(header) {
+            None => println!("Dropping stream of unknown type"),
+            Some(codec) => if let Err(e) = (codec.parse_header)(header) {
+                println!("Dropping {} stream: {}", codec.name, e);
+            },
+        }
+        registry.identify(header)
+    });
+    let mut demux = match demux {
+        Ok(demux) => demux,
+        Err(e) => {
+            println!("Failed to read {:?}: {}", input, e);
+            return None;
+        },
+    };
+    while !demux.is_eof() {
+        if let Err(e) = demux.pump_page() {
+            println!("Failed to read {:?}: {}", input, e);
+            return None;
+        }
+    }
+    if demux.link() > 0 {
+        println!("{:?} is a chained file; split it into its songs first", input);
+        return None;
+    }
+    let diagnostics = demux.diagnostics();
+    if diagnostics != Default::default() {
+        println!("Warning: {:?} is damaged: {:?}", input, diagnostics);
+    }
+    let mut song = mem::replace(&mut *song.borrow_mut(), Song{mp3: Vec::new(), cdg: Vec::new(), indexed: false});
+    song.indexed = demux.index_serial().is_some();
+    Some(song)
+}
+
+/// Muxes the frames of an `Mp3Track` back into an OggMP3 stream
+struct FrameCoder {
+    headers: Vec<Vec<u8>>,
+    frames: vec::IntoIter<Vec<u8>>,
+    samples_per_frame: u64,
+    /// Granule position at the end of the last frame
+    position: u64,
+    clock: Mp3Granule,
+}
+
+impl FrameCoder {
+    /// The info frame isn't kept, as its frame count and seek table
+    /// no longer match; its delay and padding are in `track.header`.
+    fn new(track: Mp3Track) -> Self {
+        let header = track.header;
+        let has_tag = track.tag.is_some() as u8;
+        let mut packet = b"OggMP3\0\0".to_vec();
+        packet.push(mp3::MP3_MAJOR_VERSION);
+        packet.push(mp3::MP3_MINOR_VERSION);
+        packet.push(header.flags & 2 | has_tag);
+        packet.push(has_tag);
+        packet.extend_from_slice(&track.pseudoheader);
+        for &value in &[header.sample_frequency, header.samples_per_frame, header.gapless.delay, header.gapless.padding] {
+            packet.extend((0..4).map(|byte| (value >> (byte * 8)) as u8));
+        }
+        packet.push(header.role.to_u8());
+        let mut headers = vec![packet];
+        headers.extend(track.tag);
+        FrameCoder{
+            headers: headers,
+            frames: track.frames.into_iter(),
+            samples_per_frame: header.samples_per_frame as u64,
+            position: 0,
+            clock: Mp3Granule{sample_rate: header.sample_frequency, delay: header.gapless.delay},
+        }
+    }
+}
+
+impl BitstreamCoder for FrameCoder {
+    fn headers(&self) -> Vec<Vec<u8>> {
+        self.headers.clone()
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<ogg::Packet>> {
+        let frame = self.frames.next();
+        if frame.is_some() {
+            self.position += self.samples_per_frame;
+        }
+        Ok(frame.map(|frame| ogg::Packet{
+            content: frame,
+            timestamp: self.position,
+        }))
+    }
+
+    fn map_granule(&self, granule: u64) -> u64 {
+        self.clock.micros(granule)
+    }
+}
+
+fn write_song(song: Song, output: &Path) -> bool {
+    let mut mux = OgkMux::new();
+    for track in song.mp3 {
+        mux.add_stream(Box::new(FrameCoder::new(track)));
+    }
+    for track in song.cdg {
+        let mut coder = OggCdgCoder::new(Cursor::new(track.sectors));
+        if let Err(e) = coder.set_compression(track.compression, track.dictionary) {
+            println!("Failed to set up CDG compression: {}", e);
+            return false;
+        }
+        mux.add_stream(Box::new(coder));
+    }
+    mux.set_write_index(song.indexed);
+    let mut ofile = match fs::File::create(output) {
+        Ok(file) => io::BufWriter::new(file),
+        Err(e) => {
+            println!("Failed to create {:?}: {}", output, e);
+            return false;
+        },
+    };
+    if let Err(e) = mux.write_to(&mut ofile).and_then(|_| ofile.flush()) {
+        println!("Failed to write {:?}: {}", output, e);
+        return false;
+    }
+    true
+}
+
+/// A CD+G subchannel packet
+fn command(instruction: u8, data: &[u8]) -> [u8; 24] {
+    let mut packet = [0; 24];
+    packet[0] = 9;
+    packet[1] = instruction;
+    packet[4..4 + data.len()].copy_from_slice(data);
+    packet
+}
+
+/// A tile block command. Bits of `rows` that are set get the second
+/// color.
+fn tile(instruction: u8, pos: (u8, u8), colors: (u8, u8), rows: [u8; 12]) -> [u8; 24] {
+    let mut data = [colors.0, colors.1, pos.1, pos.0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    data[4..].copy_from_slice(&rows);
+    command(instruction, &data)
+}
+
+/// The rows of a tile bitmap, with the bits set for the pixels that
+/// `wanted` picks
+fn tile_rows<F: Fn(u8) -> bool>(pixels: &[[u8; 6]; 12], wanted: F) -> [u8; 12] {
+    let mut rows = [0; 12];
+    for (row, line) in rows.iter_mut().zip(pixels.iter()) {
+        for (x, &pixel) in line.iter().enumerate() {
+            if wanted(pixel) {
+                *row |= 0x20 >> x;
+            }
+        }
+    }
+    rows
+}
+
+/// The commands that draw the interpreter's picture from scratch: the
+/// palette, the border and scroll offset, a memory preset to the most
+/// common color, then a tile block for each tile that differs from it. Tile blocks only have two
+/// colors, so any others are XORed in a bit at a time.
+fn redraw(interp: &CdgInterpreter) -> Vec<[u8; 24]> {
+    let mut packets = Vec::new();
+    for &(instruction, offset) in &[(30, 0), (31, 8)] {
+        let mut data = [0; 16];
+        for (i, color) in interp.palette()[offset..offset + 8].iter().enumerate() {
+            data[i * 2..i * 2 + 2].copy_from_slice(&color.to_subchannel());
+        }
+        packets.push(command(instruction, &data));
+    }
+    if let Some(color) = interp.transparent_color() {
+        packets.push(command(28, &[color]));
+    }
+    packets.push(command(2, &[interp.border_color()]));
+    let (x, y) = interp.scroll_offset();
+    if (x, y) != (0, 0) {
+        // A copying scroll that doesn't move any tiles
+        packets.push(command(24, &[0, x, y]));
+    }
+    let mut counts = [0; 16];
+    for y in 0..216 {
+        for x in 0..300 {
+            counts[interp.color_index(x, y) as usize & 0xF] += 1;
+        }
+    }
+    let background = (0..16).max_by_key(|&color| counts[color as usize]).unwrap();
+    packets.push(command(1, &[background, 0]));
+
+    for row in 0..18 {
+        for col in 0..50 {
+            let mut pixels = [[0; 6]; 12];
+            let mut counts = [0; 16];
+            for (y, line) in pixels.iter_mut().enumerate() {
+                for (x, pixel) in line.iter_mut().enumerate() {
+                    *pixel = interp.color_index(col * 6 + x, row * 12 + y) & 0xF;
+                    counts[*pixel as usize] += 1;
+                }
+            }
+            if counts[background as usize] == 72 {
+                continue;
+            }
+            let mut colors: Vec<u8> = (0..16).filter(|&color| counts[color as usize] > 0).collect();
+            colors.sort_by(|a, b| counts[*b as usize].cmp(&counts[*a as usize]));
+            let base = colors[0];
+            let fore = *colors.get(1).unwrap_or(&base);
+            let pos = (col as u8, row as u8);
+            packets.push(tile(6, pos, (base, fore), tile_rows(&pixels, |pixel| pixel == fore && fore != base)));
+            for bit in 0..4 {
+                let plane = 1 << bit;
+                let rows = tile_rows(&pixels, |pixel| pixel != fore && (pixel ^ base) & plane != 0);
+                if rows.iter().any(|&row| row != 0) {
+                    packets.push(tile(38, pos, (0, plane), rows));
+                }
+            }
+        }
+    }
+    packets
+}
+
+/// Run the commands in sectors `from` to `to` of a raw .cdg
+fn replay(interp: &mut CdgInterpreter, sectors: &[u8], from: u64, to: u64) {
+    for sector in sectors.chunks(96).skip(from as usize).take(to.saturating_sub(from) as usize) {
+        for command in SectorIter::new(sector) {
+            interp.handle_cmd(command);
+        }
+    }
+}
+
+/// Sectors that put the picture a stream has at sector `start` on a
+/// blank screen, found by replaying the stream as a keyframe would
+/// be. They take the place of the stream's own first few sectors from
+/// `start`, so they draw the picture as it is at the end of those
+/// instead. Returns the sectors and how many they replace.
+fn preamble(sectors: &[u8], start: u64) -> (Vec<u8>, u64) {
+    let mut interp = CdgInterpreter::new();
+    replay(&mut interp, sectors, 0, start);
+    let mut covered = 0;
+    loop {
+        let packets = redraw(&interp);
+        let needed = (packets.len() as u64 + 3) / 4;
+        if needed <= covered {
+            let mut preamble = Vec::with_capacity(covered as usize * 96);
+            for packet in &packets {
+                preamble.extend_from_slice(packet);
+            }
+            preamble.resize(covered as usize * 96, 0);
+            return (preamble, covered);
+        }
+        // The picture moves on while the preamble is drawn
+        replay(&mut interp, sectors, start + covered, start + needed);
+        covered = needed;
+    }
+}
+
+/// Sector `n` of a raw .cdg, or an empty one past the end
+fn sector(sectors: &[u8], n: u64) -> &[u8] {
+    let start = n as usize * 96;
+    if start + 96 <= sectors.len() {
+        &sectors[start..start + 96]
+    } else {
+        &[0; 96]
+    }
+}
+
+/// Keep the audio from sector `start` to `end`. Whole frames are
+/// kept, starting a frame early so that the decoder has the bit
+/// reservoir and overlap it needs; the delay and padding drop
+/// whatever is outside the cut. None if the audio ends before `start`.
+fn cut_mp3(mut track: Mp3Track, start: u64, end: Option<u64>) -> Option<Mp3Track> {
+    let samples_per_frame = track.samples_per_frame();
+    let rate = track.header.sample_frequency as u64;
+    let delay = track.header.gapless.delay as u64;
+    let audio_end = track.end();
+    // Granule positions of the first sample kept and the one after
+    // the last
+    let first = (start.saturating_mul(rate) / 75).saturating_add(delay);
+    let last = min(end.map_or(audio_end, |end| (end.saturating_mul(rate) / 75).saturating_add(delay)), audio_end);
+    if first >= last {
+        return None;
+    }
+    let first_frame = (first / samples_per_frame).saturating_sub(1);
+    let end_frame = (last + samples_per_frame - 1) / samples_per_frame;
+    track.frames.truncate(end_frame as usize);
+    track.frames = track.frames.split_off(first_frame as usize);
+    track.header.gapless = GaplessInfo{
+        delay: (first - first_frame * samples_per_frame) as u32,
+        padding: (end_frame * samples_per_frame - last) as u32,
+    };
+    Some(track)
+}
+
+/// Keep the graphics from sector `start` to `end`, beginning with a
+/// preamble that draws the picture at `start`
+fn cut_cdg(mut track: CdgTrack, start: u64, end: Option<u64>) -> CdgTrack {
+    let (mut sectors, covered) = if start == 0 {
+        (Vec::new(), 0)
+    } else {
+        preamble(&track.sectors, start)
+    };
+    // The preamble is kept whole even if the cut is shorter
+    let stop = max(min(end.unwrap_or(!0), track.len()), start + covered);
+    for n in start + covered..stop {
+        sectors.extend_from_slice(sector(&track.sectors, n));
+    }
+    track.sectors = sectors;
+    track
+}
+
+/// The sector a time in µs falls in, for any time at all
+fn to_sectors(micros: u64) -> u64 {
+    micros / 1000_000 * 75 + micros % 1000_000 * 75 / 1000_000
+}
+
+/// Copy the part of `input` from `from` to `to`, in µs, to `output`.
+/// Both are rounded down to a sector (1/75 s). Returns false if
+/// anything went wrong.
+pub fn cut(input: &Path, output: &Path, from: u64, to: Option<u64>) -> bool {
+    let song = match read_song(input) {
+        Some(song) => song,
+        None => return false,
+    };
+    let start = to_sectors(from);
+    let end = to.map(to_sectors);
+    if end.map_or(false, |end| end <= start) {
+        println!("The cut must end after it starts");
+        return false;
+    }
+    let mut cut = Song{
+        mp3: Vec::new(),
+        cdg: Vec::new(),
+        indexed: song.indexed,
+    };
+    for track in song.mp3 {
+        match cut_mp3(track, start, end) {
+            Some(track) => cut.mp3.push(track),
+            None => {
+                println!("The audio in {:?} ends before the cut starts", input);
+                return false;
+            },
+        }
+    }
+    for track in song.cdg {
+        cut.cdg.push(cut_cdg(track, start, end));
+    }
+    write_song(cut, output)
+}
+
+/// Join two tracks of audio, dropping the frames at the seam that are
+/// all padding of `track` or all delay of `other`. As when cutting,
+/// the last frame of the delay is kept for the bit reservoir. Returns
+/// the granule position where `other`'s audio now starts.
+fn join_mp3(track: &mut Mp3Track, other: Mp3Track) -> u64 {
+    let samples_per_frame = track.samples_per_frame();
+    let padding_frames = min(track.header.gapless.padding as u64 / samples_per_frame, track.frames.len() as u64);
+    let delay_frames = (other.header.gapless.delay as u64 / samples_per_frame).saturating_sub(1);
+    let delay_frames = min(delay_frames, other.frames.len() as u64);
+    let kept = track.frames.len() as u64 - padding_frames;
+    track.frames.truncate(kept as usize);
+    track.frames.extend(other.frames.into_iter().skip(delay_frames as usize));
+    track.header.gapless.padding = other.header.gapless.padding;
+    kept * samples_per_frame + other.header.gapless.delay as u64 - delay_frames * samples_per_frame
+}
+
+/// Add `next` to the end of `song`. Audio streams are matched up by
+/// role and graphics streams in order. Returns false if they don't
+/// match.
+///
+/// The audio isn't decoded, so whatever padding and delay doesn't
+/// fill a whole frame is still heard at each seam: up to a frame of
+/// the padding, and up to two of the delay.
+fn append(song: &mut Song, next: Song, input: &Path) -> bool {
+    if next.mp3.len() != song.mp3.len() || next.cdg.len() != song.cdg.len() {
+        println!("{:?} doesn't have the same streams as the files before it", input);
+        return false;
+    }
+    let mut next_mp3: Vec<_> = next.mp3.into_iter().map(Some).collect();
+    // Where `next` starts, in sectors. The first audio stream sets the
+    // time; the graphics have to fit around it.
+    let mut next_start = None;
+    for track in &mut song.mp3 {
+        let role = track.header.role;
+        let found = next_mp3.iter().position(|other| other.as_ref().map_or(false, |other| other.header.role == role));
+        let other = match found.and_then(|i| next_mp3[i].take()) {
+            Some(other) => other,
+            None => {
+                println!("{:?} has no {} audio to join to the files before it", input, role.name());
+                return false;
+            },
+        };
+        if !other.header.representative.is_compatible(&track.header.representative)
+            || other.header.representative.channels() != track.header.representative.channels()
+            || other.header.samples_per_frame != track.header.samples_per_frame
+        {
+            println!("The {} audio in {:?} is in a different format from the files before it", role.name(), input);
+            return false;
+        }
+        let rate = track.header.sample_frequency as u64;
+        let time = join_mp3(track, other).saturating_sub(track.header.gapless.delay as u64);
+        let start = (time * 75 + rate / 2) / rate;
+        match next_start {
+            None => next_start = Some(start),
+            Some(reference) if start != reference => println!("Warning: the {} audio before {:?} doesn't last as long as the rest, so it will be out of step", role.name(), input),
+            Some(_) => (),
+        }
+    }
+    let next_start = next_start.unwrap_or_else(|| song.cdg.iter().map(CdgTrack::len).max().unwrap_or(0));
+    for (track, other) in song.cdg.iter_mut().zip(next.cdg) {
+        // The new song starts from a blank screen, whatever the last
+        // one left behind
+        let (preamble, covered) = preamble(&other.sectors, 0);
+        track.sectors.resize(next_start as usize * 96, 0);
+        track.sectors.extend(preamble);
+        for n in covered..other.len() {
+            track.sectors.extend_from_slice(sector(&other.sectors, n));
+        }
+    }
+    song.indexed |= next.indexed;
+    true
+}
+
+/// Join `inputs` into one song in `output`, one after another. Every
+/// input must have the same streams. Returns false if anything went
+/// wrong.
+pub fn concat(inputs: &[&Path], output: &Path) -> bool {
+    let mut song = match read_song(inputs[0]) {
+        Some(song) => song,
+        None => return false,
+    };
+    for input in &inputs[1..] {
+        let next = match read_song(input) {
+            Some(next) => next,
+            None => return false,
+        };
+        if !append(&mut song, next, input) {
+            return false;
+        }
+    }
+    write_song(song, output)
+}
+
+/// Give the streams of one input to `chain` whose serials an earlier
+/// input used new ones, and fix up its indexes to match. Every page
+/// keeps its size, so the index offsets still hold. None if nothing
+/// clashed, so the input can be copied as it is.
+fn renumber(data: &[u8], used: &mut HashSet<u32>) -> io::Result<Option<Vec<u8>>> {
+    let mut source = OggPageSource::from_slice(data);
+    let mut pages = Vec::new();
+    while let Some(page) = try!(source.next_page().map_err(Into::<io::Error>::into)) {
+        pages.push(Page{
+            flags: page.flags,
+            granule_position: page.granule_position,
+            stream_serial: page.stream_serial,
+            page_sequence: page.page_sequence,
+            segment_table: page.segment_table.to_vec(),
+            content: page.content.to_vec(),
+        });
+    }
+    let serials: HashSet<u32> = pages.iter().map(|page| page.stream_serial).collect();
+    let mut clashes: Vec<u32> = serials.intersection(used).cloned().collect();
+    used.extend(serials.iter().cloned());
+    if clashes.is_empty() {
+        return Ok(None);
+    }
+    // New serials come from the old ones, so chaining the same files
+    // always gives the same output
+    clashes.sort();
+    let mut renumbered = HashMap::new();
+    for serial in clashes {
+        let new_serial = SerialSource::Hashed(serial as u64).pick(&[], used);
+        used.insert(new_serial);
+        renumbered.insert(serial, new_serial);
+    }
+    let new_serial = |serial| *renumbered.get(&serial).unwrap_or(&serial);
+
+    let indexes: Vec<u32> = pages.iter()
+        .filter(|page| page.flags.intersects(PAGE_BOS) && index::parse_header(&page.content).is_ok())
+        .map(|page| page.stream_serial)
+        .collect();
+    for serial in indexes {
+        let index_pages: Vec<usize> = (0..pages.len()).filter(|&i| pages[i].stream_serial == serial && !pages[i].flags.intersects(PAGE_BOS)).collect();
+        let mut packet = Vec::new();
+        for &i in &index_pages {
+            packet.extend_from_slice(&pages[i].content);
+        }
+        let old = try!(SeekIndex::parse(&packet).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unreadable seek index")));
+        let mut new = SeekIndex::new();
+        for stream in old.serials() {
+            for entry in old.entries(stream) {
+                new.add(new_serial(stream), *entry);
+            }
+        }
+        let packet = new.to_bytes();
+        let mut rest = &packet[..];
+        for &i in &index_pages {
+            let (content, tail) = rest.split_at(min(pages[i].content.len(), rest.len()));
+            pages[i].content = content.to_vec();
+            rest = tail;
+        }
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    for page in &mut pages {
+        page.stream_serial = new_serial(page.stream_serial);
+        try!(page.write_to(&mut out));
+    }
+    Ok(Some(out))
+}
+
+/// Write `inputs` to `output` one after another, as the links of a
+/// chained file. Streams are renumbered where their serials clash with
+/// an earlier link's. Returns false if anything went wrong.
+pub fn chain<W: Write>(inputs: &[&Path], output: &mut W) -> bool {
+    let mut used = HashSet::new();
+    for input in inputs {
+        let data = match fs::read(input) {
+            Ok(data) => data,
+            Err(e) => {
+                println!("Failed to open OGK file {:?}: {}", input, e);
+                return false;
+            },
+        };
+        let written = match renumber(&data, &mut used) {
+            Ok(Some(renumbered)) => output.write_all(&renumbered),
+            Ok(None) => output.write_all(&data),
+            Err(e) => {
+                println!("Failed to read OGK file {:?}: {}", input, e);
+                return false;
+            },
+        };
+        if let Err(e) = written {
+            println!("Failed to write output file: {}", e);
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use cdg::RgbColor;
+    use test_util;
+    use super::*;
+
+    // The test songs have 1152 samples per frame at 44.1 kHz, so 588
+    // samples to a sector, and an Info frame giving a delay of 1105
+    // samples and padding of 731
+    const FRAME: u64 = 1152;
+    const DELAY: u64 = 1105;
+    const PADDING: u64 = 731;
+
+    fn song(test: &str, frames: usize, sectors: usize) -> Song {
+        let path = test_util::scratch_dir(test).join("song.ogk");
+        fs::write(&path, test_util::mux_song(test_util::mp3(frames, false, true), test_util::cdg(sectors), false)).unwrap();
+        read_song(&path).unwrap()
+    }
+
+    type Picture = (Vec<RgbColor>, Option<u8>, u8, (u8, u8), Vec<u8>);
+
+    /// Everything about the interpreter's state that a redraw has to
+    /// reproduce
+    fn picture(interp: &CdgInterpreter) -> Picture {
+        let mut pixels = Vec::with_capacity(300 * 216);
+        for y in 0..216 {
+            for x in 0..300 {
+                pixels.push(interp.color_index(x, y));
+            }
+        }
+        (interp.palette().to_vec(), interp.transparent_color(), interp.border_color(), interp.scroll_offset(), pixels)
+    }
+
+    fn picture_at(sectors: &[u8], from: u64, to: u64) -> Picture {
+        let mut interp = CdgInterpreter::new();
+        replay(&mut interp, sectors, from, to);
+        picture(&interp)
+    }
+
+    #[test]
+    fn cut_mp3_keeps_whole_frames_and_trims_with_delay_and_padding() {
+        let track = || song("edit-cut-mp3", 100, 1).mp3.remove(0);
+        let audio_end = 100 * FRAME - PADDING;
+        // (start, end, first frame kept, frames kept, delay, padding)
+        let cases = [
+            // The whole song comes back as it was
+            (0, None, 0, 100, DELAY, PADDING),
+            // 1 s to 2 s is samples 45205 to 89305 of the stream,
+            // inside frames 39 and 77, and a frame is kept before
+            (75, Some(150), 38, 40, 45205 - 38 * FRAME, 78 * FRAME - 89305),
+            // A cut that ends inside the padding stops where the
+            // audio does
+            (150, Some(194), 76, 24, 89305 - 76 * FRAME, 100 * FRAME - audio_end),
+            (150, None, 76, 24, 89305 - 76 * FRAME, 100 * FRAME - audio_end),
+        ];
+        for &(start, end, first_frame, frames, delay, padding) in &cases {
+            let cut = cut_mp3(track(), start, end).unwrap();
+            assert_eq!(cut.frames.len(), frames);
+            assert_eq!(cut.frames[0][4] as u64, first_frame);
+            assert_eq!((cut.header.gapless.delay as u64, cut.header.gapless.padding as u64), (delay, padding));
+            // What's left plays from `start` to `end`
+            let played = frames as u64 * FRAME - delay - padding;
+            assert_eq!(played, min(end.map_or(!0, |end| end * 588 + DELAY), audio_end) - start * 588 - DELAY);
+        }
+        // Nothing is left past the end of the audio, however far
+        assert!(cut_mp3(track(), 193, None).is_none());
+        assert!(cut_mp3(track(), to_sectors(!0), None).is_none());
+        assert_eq!(cut_mp3(track(), 0, Some(to_sectors(!0))).unwrap().frames.len(), 100);
+    }
+
+    /// A .cdg whose tiles end up with more than two colors each, so
+    /// that redrawing them takes XORs, and that moves the border and
+    /// scroll offset
+    fn colorful_cdg() -> Vec<u8> {
+        let mut cdg = test_util::cdg(400);
+        for n in 0..50u8 {
+            let mut sector = [0; 96];
+            let data = [0, n % 15 + 1, 0, n, 0x15, 0x2A, 0x15, 0x2A, 0x3F, 0, 0x3F, 0, 0x21, 0x12, 0x0C, 0x33];
+            sector[..24].copy_from_slice(&test_util::command(38, &data));
+            cdg.extend_from_slice(&sector);
+        }
+        // A border and a scroll offset, which last until the end
+        let mut sector = [0; 96];
+        sector[..24].copy_from_slice(&test_util::command(2, &[5]));
+        sector[24..48].copy_from_slice(&test_util::command(24, &[0, 3, 7]));
+        cdg.extend_from_slice(&sector);
+        cdg.extend(test_util::cdg(100));
+        cdg
+    }
+
+    #[test]
+    fn preamble_redraws_the_picture() {
+        let cdg = colorful_cdg();
+        let len = cdg.len() as u64 / 96;
+        for &start in &[1, 100, 420, 450, 451, 500, len] {
+            let (preamble, covered) = preamble(&cdg, start);
+            assert_eq!(preamble.len() as u64, covered * 96);
+            let mut redrawn = CdgInterpreter::new();
+            replay(&mut redrawn, &preamble, 0, covered);
+            assert!(picture(&redrawn) == picture_at(&cdg, 0, start + covered), "at sector {}", start);
+            // and the stream carries on from there as before
+            replay(&mut redrawn, &cdg, start + covered, len);
+            assert!(picture(&redrawn) == picture_at(&cdg, 0, len), "after sector {}", start);
+        }
+    }
+
+    #[test]
+    fn append_starts_the_next_graphics_with_its_audio() {
+        let next_cdg = song("edit-append-next", 1, 120).cdg.remove(0).sectors;
+        // (padding of the first song, delay of the next, frames
+        // dropped from each, samples of audio before the next)
+        let cases = [
+            // Nothing to drop: the next audio starts 100 frames in
+            (PADDING, DELAY, 0, 0, 100 * FRAME),
+            // Two frames of padding go, and one of the delay, as the
+            // last is kept for the bit reservoir
+            (PADDING + 2 * FRAME, DELAY + 2 * FRAME, 2, 1, 98 * FRAME + FRAME),
+        ];
+        for &(padding, delay, padding_frames, delay_frames, start) in &cases {
+            let mut first = song("edit-append-first", 100, 150);
+            let mut next = song("edit-append-next", 100, 120);
+            first.mp3[0].header.gapless.padding = padding as u32;
+            next.mp3[0].header.gapless.delay = delay as u32;
+            assert!(append(&mut first, next, Path::new("next.ogk")));
+
+            let track = &first.mp3[0];
+            assert_eq!(track.frames.len() as u64, 200 - padding_frames - delay_frames);
+            assert_eq!(track.frames[99 - padding_frames as usize][4] as u64, 99 - padding_frames);
+            assert_eq!(track.frames[100 - padding_frames as usize][4] as u64, delay_frames);
+            assert_eq!(track.header.gapless.delay as u64, DELAY);
+            assert_eq!(track.header.gapless.padding as u64, PADDING);
+
+            let sectors = &first.cdg[0].sectors;
+            let next_start = (start * 75 + 22050) / 44100;
+            assert_eq!(sectors.len() as u64, (next_start + 120) * 96);
+            assert!(sectors[150 * 96..next_start as usize * 96].iter().all(|&byte| byte == 0));
+            assert!(picture_at(sectors, next_start, next_start + 120) == picture_at(&next_cdg, 0, 120));
+        }
+    }
+
+    #[test]
+    fn chain_renumbers_streams_that_clash_with_earlier_links() {
+        let dir = test_util::scratch_dir("edit-chain");
+        let input = dir.join("song.ogk");
+        let song = test_util::mux_song(test_util::mp3(40, false, true), test_util::cdg(100), true);
+        fs::write(&input, &song).unwrap();
+        let mut chained = Vec::new();
+        assert!(chain(&[&input, &input, &input], &mut chained));
+        // The first link is copied as it was, and the rest are the
+        // same size
+        assert_eq!(chained.len(), song.len() * 3);
+        assert!(chained[..song.len()] == song[..]);
+
+        let mut links: Vec<(u64, Vec<Page>)> = Vec::new();
+        let mut source = OggPageSource::from_slice(&chained);
+        while let Some((offset, page)) = source.next_page_at().unwrap() {
+            if offset as usize % song.len() == 0 {
+                links.push((offset, Vec::new()));
+            }
+            links.last_mut().unwrap().1.push(Page{
+                flags: page.flags,
+                granule_position: page.granule_position,
+                stream_serial: page.stream_serial,
+                page_sequence: page.page_sequence,
+                segment_table: page.segment_table.to_vec(),
+                content: page.content.to_vec(),
+            });
+        }
+        assert_eq!(source.crc_failures(), 0);
+        let mut used = HashSet::new();
+        for &(start, ref pages) in &links {
+            let serials: HashSet<u32> = pages.iter().map(|page| page.stream_serial).collect();
+            assert_eq!(serials.len(), 3);
+            assert!(serials.is_disjoint(&used));
+            used.extend(serials);
+            // Each index names its own link's streams, and still
+            // points at their pages
+            let index_serial = pages.iter().find(|page| index::parse_header(&page.content).is_ok()).unwrap().stream_serial;
+            let mut packet = Vec::new();
+            for page in pages.iter().filter(|page| page.stream_serial == index_serial && !page.flags.intersects(PAGE_BOS)) {
+                packet.extend_from_slice(&page.content);
+            }
+            let index = SeekIndex::parse(&packet).unwrap();
+            assert_eq!(index.serials().len(), 2);
+            for serial in index.serials() {
+                for entry in index.entries(serial) {
+                    let mut at = OggPageSource::from_slice(&chained[(start + entry.offset) as usize..]);
+                    let page = at.next_page().unwrap().unwrap();
+                    assert_eq!((page.stream_serial, page.granule_position), (serial, entry.granule));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,458 @@
+use std::collections::{BTreeMap,HashMap,HashSet};
+use std::collections::hash_map::Entry;
+use std::fs;
+use std::io::{self,Cursor,Read,BufWriter};
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex,mpsc};
+use std::thread;
+
+use zip;
+use ogk::ogg::OgkMux;
+use ogk::mp3::{self,OggMP3Coder};
+use ogk::cdg::OggCdgCoder;
+
+/// Where an input file lives
+#[derive(Clone,Debug)]
+enum Location {
+    File(PathBuf),
+    Zip{archive: PathBuf, entry: String},
+}
+
+/// The zip archives a worker has open, so that each one's directory
+/// is only read once
+#[derive(Default)]
+struct Archives(HashMap<PathBuf, zip::ZipArchive<fs::File>>);
+
+impl Archives {
+    fn open(&mut self, path: &Path) -> io::Result<&mut zip::ZipArchive<fs::File>> {
+        match self.0.entry(path.to_owned()) {
+            Entry::Occupied(archive) => Ok(archive.into_mut()),
+            Entry::Vacant(slot) => {
+                let file = try!(fs::File::open(path));
+                Ok(slot.insert(try!(zip::ZipArchive::new(file).map_err(zip_error))))
+            },
+        }
+    }
+}
+
+impl Location {
+    fn read(&self, archives: &mut Archives) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        match *self {
+            Location::File(ref path) => {
+                try!(try!(fs::File::open(path)).read_to_end(&mut contents));
+            },
+            Location::Zip{ref archive, ref entry} => {
+                let archive = try!(archives.open(archive));
+                let mut entry = try!(archive.by_name(entry).map_err(zip_error));
+                try!(entry.read_to_end(&mut contents));
+            },
+        }
+        Ok(contents)
+    }
+
+    fn describe(&self) -> String {
+        match *self {
+            Location::File(ref path) => path.display().to_string(),
+            Location::Zip{ref archive, ref entry} => format!("{}:{}", archive.display(), entry),
+        }
+    }
+}
+
+fn zip_error(e: zip::result::ZipError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// An MP3 and CDG that belong together
+struct Pair {
+    /// The shared file name, without extension
+    stem: String,
+    mp3: Location,
+    cdg: Location,
+}
+
+/// Sorts input files into pairs
+#[derive(Default)]
+struct Pairing {
+    /// Keyed by containing directory or archive folder, then by
+    /// lowercased file stem
+    slots: BTreeMap<(String, String), (String, Option<Location>, Option<Location>)>,
+    unmatched: Vec<String>,
+    /// Directories already walked, so that symlinks back up the tree
+    /// don't bring their files in again
+    visited: HashSet<PathBuf>,
+}
+
+impl Pairing {
+    /// Consider a file; `name` is its path within `container`
+    fn add(&mut self, container: &str, name: &str, location: Location) {
+        let (dir, file) = match name.rfind(|c| c == '/' || c == '\\') {
+            Some(i) => (&name[..i], &name[i+1..]),
+            None => ("", name),
+        };
+        let (stem, extension) = match file.rfind('.') {
+            Some(i) => (&file[..i], file[i+1..].to_lowercase()),
+            None => return,
+        };
+        if extension != "mp3" && extension != "cdg" {
+            return;
+        }
+        let key = (format!("{}/{}", container, dir), stem.to_lowercase());
+        let slot = self.slots.entry(key).or_insert_with(|| (stem.to_owned(), None, None));
+        let place = if extension == "mp3" { &mut slot.1 } else { &mut slot.2 };
+        if place.is_some() {
+            // Song.cdg and SONG.CDG in the same place; we can't tell
+            // which is meant
+            self.unmatched.push(format!("{} (duplicate name)", location.describe()));
+        } else {
+            *place = Some(location);
+        }
+    }
+
+    fn add_archive(&mut self, archive: &Path) -> io::Result<()> {
+        let file = try!(fs::File::open(archive));
+        let mut zip = try!(zip::ZipArchive::new(file).map_err(zip_error));
+        let container = archive.display().to_string();
+        for i in 0..zip.len() {
+            let entry = try!(zip.by_index(i).map_err(zip_error));
+            if entry.is_dir() {
+                continue;
+            }
+            let name = entry.name().to_owned();
+            self.add(&container, &name, Location::Zip{archive: archive.to_owned(), entry: name.clone()});
+        }
+        Ok(())
+    }
+
+    /// Add a zip archive, a directory (recursively) or a single file
+    fn add_path(&mut self, path: &Path) -> io::Result<()> {
+        if try!(fs::metadata(path)).is_dir() {
+            if !self.visited.insert(try!(fs::canonicalize(path))) {
+                return Ok(());
+            }
+            let mut entries = try!(fs::read_dir(path).and_then(|dir| dir.collect::<io::Result<Vec<_>>>()));
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                if let Err(e) = self.add_path(&entry.path()) {
+                    self.unmatched.push(format!("{} ({})", entry.path().display(), e));
+                }
+            }
+            Ok(())
+        } else if is_zip(path) {
+            self.add_archive(path)
+        } else {
+            let container = path.parent().map_or_else(String::new, |parent| parent.display().to_string());
+            let name = path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+            self.add(&container, &name, Location::File(path.to_owned()));
+            Ok(())
+        }
+    }
+
+    fn into_pairs(mut self) -> (Vec<Pair>, Vec<String>) {
+        let mut pairs = Vec::new();
+        for (_, (stem, mp3, cdg)) in self.slots {
+            match (mp3, cdg) {
+                (Some(mp3), Some(cdg)) => pairs.push(Pair{stem: stem, mp3: mp3, cdg: cdg}),
+                (Some(only), None) => self.unmatched.push(format!("{} (no CDG)", only.describe())),
+                (None, Some(only)) => self.unmatched.push(format!("{} (no MP3)", only.describe())),
+                (None, None) => (),
+            }
+        }
+        (pairs, self.unmatched)
+    }
+}
+
+fn is_zip(path: &Path) -> bool {
+    path.extension().map_or(false, |extension| extension.to_string_lossy().to_lowercase() == "zip")
+}
+
+/// Guess "Artist - Title" from a file name. Disc ids such as
+/// "SC8123-05 - Artist - Title" are dropped.
+fn name_from_stem(stem: &str) -> Option<String> {
+    let parts: Vec<&str> = stem.split(" - ").map(str::trim).filter(|part| !part.is_empty()).collect();
+    if parts.len() >= 2 {
+        Some(format!("{} - {}", parts[parts.len() - 2], parts[parts.len() - 1]))
+    } else {
+        None
+    }
+}
+
+fn name_from_tag(tag: &[u8]) -> Option<String> {
+    match (mp3::id3v2_text(tag, b"TPE1"), mp3::id3v2_text(tag, b"TIT2")) {
+        (Some(artist), Some(title)) => Some(format!("{} - {}", artist, title)),
+        _ => None,
+    }
+}
+
+/// Replace anything that's awkward in a file name
+fn sanitize(name: &str) -> String {
+    let name: String = name.chars().map(|c| match c {
+        '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+        c if c.is_control() => '_',
+        c => c,
+    }).collect();
+    name.trim_matches(|c| c == ' ' || c == '.').to_owned()
+}
+
+/// Output names handed out so far this run
+struct Claims {
+    dir: PathBuf,
+    overwrite: bool,
+    taken: Mutex<HashSet<PathBuf>>,
+}
+
+impl Claims {
+    /// Create the output file for `name`, adding " (2)" and so on if
+    /// it's taken
+    fn create(&self, name: &str) -> io::Result<(PathBuf, fs::File)> {
+        let mut taken = self.taken.lock().unwrap();
+        for n in 1.. {
+            let file_name = if n == 1 { format!("{}.ogk", name) } else { format!("{} ({}).ogk", name, n) };
+            let path = self.dir.join(file_name);
+            if taken.contains(&path) {
+                continue;
+            }
+            let mut options = fs::OpenOptions::new();
+            if self.overwrite {
+                options.write(true).create(true).truncate(true);
+            } else {
+                options.write(true).create_new(true);
+            }
+            match options.open(&path) {
+                Ok(file) => {
+                    taken.insert(path.clone());
+                    return Ok((path, file));
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!()
+    }
+}
+
+fn import_pair(pair: &Pair, claims: &Claims, archives: &mut Archives) -> Result<PathBuf, String> {
+    let mp3 = try!(pair.mp3.read(archives).map_err(|e| format!("{}: {}", pair.mp3.describe(), e)));
+    let cdg = try!(pair.cdg.read(archives).map_err(|e| format!("{}: {}", pair.cdg.describe(), e)));
+    let mp3 = try!(OggMP3Coder::new(Cursor::new(mp3)).map_err(|e| format!("{}: {}", pair.mp3.describe(), e)));
+    let name = mp3.id3v2_tag().and_then(name_from_tag)
+        .or_else(|| name_from_stem(&pair.stem))
+        .unwrap_or_else(|| pair.stem.clone());
+    let name = match sanitize(&name) {
+        ref name if name.is_empty() => "untitled".to_owned(),
+        name => name,
+    };
+    let (path, file) = try!(claims.create(&name).map_err(|e| format!("{}: {}", name, e)));
+    let mut mux = OgkMux::new();
+    mux.add_stream(Box::new(mp3));
+    mux.add_stream(Box::new(OggCdgCoder::new(Cursor::new(cdg))));
+    if let Err(e) = mux.write_to(BufWriter::new(file)) {
+        let _ = fs::remove_file(&path);
+        return Err(format!("{}: {}", path.display(), e));
+    }
+    Ok(path)
+}
+
+/// Mux every MP3+G pair found in `inputs` (zip archives, directories
+/// or loose files) into `out_dir`, using `jobs` threads. Prints a
+/// summary; returns false if any pair failed.
+pub fn import(inputs: &[&Path], out_dir: &Path, jobs: usize, overwrite: bool) -> bool {
+    let mut pairing = Pairing::default();
+    let mut failures = Vec::new();
+    for input in inputs {
+        if let Err(e) = pairing.add_path(input) {
+            failures.push(format!("{}: {}", input.display(), e));
+        }
+    }
+    let (pairs, unmatched) = pairing.into_pairs();
+    let pair_count = pairs.len();
+
+    let queue = Arc::new(Mutex::new(pairs.into_iter()));
+    let claims = Arc::new(Claims{
+        dir: out_dir.to_owned(),
+        overwrite: overwrite,
+        taken: Mutex::new(HashSet::new()),
+    });
+    let (results, finished) = mpsc::channel();
+    let workers: Vec<_> = (0..jobs.max(1)).map(|_| {
+        let queue = queue.clone();
+        let claims = claims.clone();
+        let results = results.clone();
+        thread::spawn(move || {
+            let mut archives = Archives::default();
+            loop {
+                // Don't hold the lock while muxing
+                let next = queue.lock().unwrap().next();
+                match next {
+                    Some(pair) => {
+                        let result = import_pair(&pair, &claims, &mut archives);
+                        results.send((pair.mp3.describe(), result)).unwrap();
+                    },
+                    None => break,
+                }
+            }
+        })
+    }).collect();
+    drop(results);
+
+    let mut imported = 0;
+    for (source, result) in finished {
+        match result {
+            Ok(path) => {
+                println!("{} -> {}", source, path.display());
+                imported += 1;
+            },
+            Err(e) => {
+                println!("Failed to import {}: {}", source, e);
+                failures.push(e);
+            },
+        }
+    }
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    println!();
+    println!("Imported {} of {} pairs", imported, pair_count);
+    if !unmatched.is_empty() {
+        println!("Unmatched files:");
+        for file in &unmatched {
+            println!("  {}", file);
+        }
+    }
+    if !failures.is_empty() {
+        println!("Failures:");
+        for failure in &failures {
+            println!("  {}", failure);
+        }
+    }
+    failures.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Cursor,Write};
+    use std::os::unix;
+    use zip;
+    use test_util;
+    use super::*;
+
+    /// An ID3v2.3 tag giving the artist and title
+    fn tag(artist: &str, title: &str) -> Vec<u8> {
+        let mut frames = Vec::new();
+        for &(id, text) in &[(b"TPE1", artist), (b"TIT2", title)] {
+            frames.extend_from_slice(id);
+            frames.extend_from_slice(&[0, 0, 0, text.len() as u8 + 1, 0, 0, 0]);
+            frames.extend_from_slice(text.as_bytes());
+        }
+        let mut tag = b"ID3\x03\x00\x00\x00\x00\x00".to_vec();
+        tag.push(frames.len() as u8);
+        tag.extend(frames);
+        tag
+    }
+
+    /// A song pack: loose pairs with and without tags, odd files out,
+    /// a subdirectory with a name that's already taken, a symlink
+    /// back up the tree and a zip
+    fn song_pack(test: &str) -> PathBuf {
+        let dir = test_util::scratch_dir(test);
+        let input = dir.join("in");
+        fs::create_dir_all(input.join("sub")).unwrap();
+        let mp3 = test_util::mp3(10, false, false);
+        let cdg = test_util::cdg(10);
+        let mut tagged = tag("Tag Artist", "Tag Title");
+        tagged.extend_from_slice(&mp3);
+        for &(name, contents) in &[
+            ("Artist - Song.mp3", &mp3),
+            ("artist - song.CDG", &cdg),
+            ("Tagged.mp3", &tagged),
+            ("tagged.cdg", &cdg),
+            ("Lonely.mp3", &mp3),
+            ("Other.cdg", &cdg),
+            ("DUP.CDG", &cdg),
+            ("Dup.cdg", &cdg),
+            ("dup.mp3", &mp3),
+            ("notes.txt", &cdg),
+            ("sub/Artist - Song.mp3", &mp3),
+            ("sub/Artist - Song.cdg", &cdg),
+        ] {
+            fs::write(input.join(name), contents).unwrap();
+        }
+        unix::fs::symlink("..", input.join("sub/loop")).unwrap();
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for &(name, contents) in &[
+            ("disc/SC8123-05 - Zip Artist - Zip Song.mp3", &mp3),
+            ("disc/SC8123-05 - Zip Artist - Zip Song.cdg", &cdg),
+        ] {
+            zip.start_file(name, zip::write::FileOptions::default()).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        fs::write(input.join("pack.zip"), zip.finish().unwrap().into_inner()).unwrap();
+        dir
+    }
+
+    #[test]
+    fn pairs_files_by_name_and_reports_the_rest() {
+        let dir = song_pack("import-pairing");
+        let input = dir.join("in");
+        let mut pairing = Pairing::default();
+        pairing.add_path(&input).unwrap();
+        let (pairs, unmatched) = pairing.into_pairs();
+        let stems: Vec<&str> = pairs.iter().map(|pair| &pair.stem[..]).collect();
+        assert_eq!(stems, vec!["Artist - Song", "DUP", "Tagged", "SC8123-05 - Zip Artist - Zip Song", "Artist - Song"]);
+        let unmatched: HashSet<String> = unmatched.into_iter().collect();
+        let expected: HashSet<String> = vec![
+            format!("{} (duplicate name)", input.join("Dup.cdg").display()),
+            format!("{} (no CDG)", input.join("Lonely.mp3").display()),
+            format!("{} (no MP3)", input.join("Other.cdg").display()),
+        ].into_iter().collect();
+        assert_eq!(unmatched, expected);
+
+        // Both halves of the zipped pair come from one open archive
+        let mut archives = Archives::default();
+        let zipped = &pairs[3];
+        assert_eq!(zipped.mp3.read(&mut archives).unwrap(), test_util::mp3(10, false, false));
+        assert_eq!(zipped.cdg.read(&mut archives).unwrap(), test_util::cdg(10));
+        assert_eq!(archives.0.len(), 1);
+    }
+
+    #[test]
+    fn names_songs_from_tags_or_file_names() {
+        let dir = song_pack("import-naming");
+        let out = dir.join("out");
+        fs::create_dir_all(&out).unwrap();
+        assert!(import(&[&dir.join("in")], &out, 1, false));
+        let mut names: Vec<String> = fs::read_dir(&out).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        assert_eq!(names, vec![
+            "Artist - Song (2).ogk",
+            "Artist - Song.ogk",
+            "DUP.ogk",
+            "Tag Artist - Tag Title.ogk",
+            "Zip Artist - Zip Song.ogk",
+        ]);
+    }
+
+    #[test]
+    fn name_from_stem_drops_disc_ids() {
+        assert_eq!(name_from_stem("Artist - Title"), Some("Artist - Title".to_owned()));
+        assert_eq!(name_from_stem("SC8123-05 - Artist - Title"), Some("Artist - Title".to_owned()));
+        assert_eq!(name_from_stem(" - Artist -  - Title "), Some("Artist - Title".to_owned()));
+        assert_eq!(name_from_stem("Title"), None);
+    }
+
+    #[test]
+    fn claims_number_names_that_are_taken() {
+        let dir = test_util::scratch_dir("import-claims");
+        fs::write(dir.join("Song.ogk"), b"already here").unwrap();
+        let claims = Claims{dir: dir.clone(), overwrite: false, taken: Mutex::new(HashSet::new())};
+        assert_eq!(claims.create("Song").unwrap().0, dir.join("Song (2).ogk"));
+        assert_eq!(claims.create("Song").unwrap().0, dir.join("Song (3).ogk"));
+        assert_eq!(fs::read(dir.join("Song.ogk")).unwrap(), b"already here");
+        // Overwriting only reuses files from before this run
+        let claims = Claims{dir: dir.clone(), overwrite: true, taken: Mutex::new(HashSet::new())};
+        assert_eq!(claims.create("Song").unwrap().0, dir.join("Song.ogk"));
+        assert_eq!(claims.create("Song").unwrap().0, dir.join("Song (2).ogk"));
+    }
+}
@@ -0,0 +1,469 @@
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use ogk::ogg::{BitstreamDecoder,DemuxDiagnostics,DemuxEvent,OggDemux};
+use ogk::cdg::{CdgGranule,CdgHeader,Compression};
+use ogk::mp3::{AudioRole,ChannelMode,Mp3Granule,Mp3Header};
+use ogk::registry::Registry;
+
+enum Codec {
+    Cdg {
+        compression: Compression,
+        sectors_per_packet: usize,
+    },
+    Mp3 {
+        sample_rate: u32,
+        samples_per_frame: u32,
+        channel_mode: ChannelMode,
+        delay: u32,
+        padding: u32,
+        role: AudioRole,
+    },
+    Unknown,
+}
+
+/// What we've learned about a stream so far
+struct StreamInfo {
+    link: u32,
+    serial: u32,
+    /// As registered, or "unknown"
+    codec_name: &'static str,
+    codec: Codec,
+    version: (u8, u8),
+    /// Why a stream of a known codec can't be read
+    unusable: Option<String>,
+    headers: usize,
+    pages: u64,
+    packets: u64,
+    /// Bytes of packet data, not counting headers
+    bytes: u64,
+    keyframes: u64,
+    last_granule: u64,
+}
+
+impl StreamInfo {
+    /// Duration in µs, if the codec is known
+    fn duration(&self) -> Option<u64> {
+        match self.codec {
+            Codec::Cdg{..} => Some(CdgGranule::from_u64(self.last_granule).micros()),
+            Codec::Mp3{sample_rate, delay, padding, ..} => {
+                let samples = self.last_granule.saturating_sub(delay as u64 + padding as u64);
+                Some(samples.saturating_mul(1000_000) / sample_rate as u64)
+            },
+            Codec::Unknown => None,
+        }
+    }
+
+    /// Average bitrate in bits per second
+    fn bitrate(&self) -> Option<u64> {
+        match self.duration() {
+            Some(duration) if duration > 0 => Some(self.bytes * 8 * 1000_000 / duration),
+            _ => None,
+        }
+    }
+}
+
+type SharedInfo = Rc<RefCell<StreamInfo>>;
+
+/// Counts what goes by; decodes nothing
+struct InfoDecoder(SharedInfo);
+
+impl BitstreamDecoder for InfoDecoder {
+    fn map_granule(&self, granule: u64) -> u64 {
+        let info = self.0.borrow();
+        match info.codec {
+            Codec::Cdg{..} => CdgGranule::from_u64(granule).micros(),
+            Codec::Mp3{sample_rate, delay, ..} => Mp3Granule{sample_rate: sample_rate, delay: delay}.micros(granule),
+            // Don't hold the other streams back
+            Codec::Unknown => !0,
+        }
+    }
+
+    fn num_headers(&self) -> usize {
+        self.0.borrow().headers
+    }
+
+    fn process_header(&mut self, _: &[u8]) {}
+
+    fn process_packet(&mut self, packet: &[u8], last_granule: u64) -> u64 {
+        let mut info = self.0.borrow_mut();
+        info.packets += 1;
+        info.bytes += packet.len() as u64;
+        if let Codec::Cdg{..} = info.codec {
+            if packet.first() == Some(&1) {
+                info.keyframes += 1;
+            }
+        }
+        last_granule
+    }
+
+    fn page_done(&mut self, granule: u64) {
+        let mut info = self.0.borrow_mut();
+        info.pages += 1;
+        if granule != !0 {
+            info.last_granule = granule;
+        }
+    }
+
+    fn notice_gap(&mut self) {}
+
+    fn finish(&mut self) {}
+}
+
+fn start_stream(registry: &Registry, header: &[u8]) -> (Box<BitstreamDecoder>, SharedInfo) {
+    let registered = registry.find(header);
+    let parsed = registered.map(|codec| (codec.parse_header)(header));
+    let mut codec = Codec::Unknown;
+    // Streams that are unknown or unusable get no details
+    let name = registered.map(|codec| codec.name);
+    if name == Some("OggCDG") {
+        if let Ok(cdg_header) = CdgHeader::parse(header) {
+            codec = Codec::Cdg{
+                compression: cdg_header.compression,
+                sectors_per_packet: cdg_header.sectors_per_packet,
+            };
+        }
+    } else if name == Some("OggMP3") {
+        if let Ok(mp3_header) = Mp3Header::parse(header) {
+            let representative = mp3_header.representative;
+            codec = Codec::Mp3{
+                sample_rate: representative.sample_rate,
+                samples_per_frame: representative.samples(),
+                channel_mode: representative.channel_mode,
+                delay: mp3_header.gapless.delay,
+                padding: mp3_header.gapless.padding,
+                role: mp3_header.role,
+            };
+        }
+    }
+    let version = if header.len() >= 10 { (header[8], header[9]) } else { (0, 0) };
+    let info = Rc::new(RefCell::new(StreamInfo{
+        link: 0,
+        serial: 0,
+        codec_name: registered.map_or("unknown", |codec| codec.name),
+        codec: codec,
+        version: version,
+        unusable: match parsed {
+            Some(Err(ref e)) => Some(e.to_string()),
+            _ => None,
+        },
+        headers: parsed.and_then(Result::ok).map_or(1, |parsed| parsed.num_headers),
+        // The BOS page never reaches the decoder
+        pages: 1,
+        packets: 0,
+        bytes: 0,
+        keyframes: 0,
+        last_granule: 0,
+    }));
+    (Box::new(InfoDecoder(info.clone())), info)
+}
+
+/// Everything found in a file, as far as it could be read
+struct Report {
+    streams: Vec<SharedInfo>,
+    /// The link and serial of each seek index
+    indexes: Vec<(u32, u32)>,
+    diagnostics: DemuxDiagnostics,
+    last_recovered_error: Option<String>,
+    /// Why reading stopped early, if it did
+    error: Option<String>,
+}
+
+fn print_text(input: &Path, report: &Report) {
+    println!("{}:", input.display());
+    for info in &report.streams {
+        let info = info.borrow();
+        println!("Stream {:08x} (link {}): {} {}.{}", info.serial, info.link, info.codec_name, info.version.0, info.version.1);
+        match info.codec {
+            Codec::Cdg{compression, sectors_per_packet} => {
+                println!("  Compression: {:?}", compression);
+                println!("  Sectors per packet: {}", sectors_per_packet);
+                println!("  Keyframes: {}", info.keyframes);
+            },
+            Codec::Mp3{sample_rate, samples_per_frame, channel_mode, delay, padding, role} => {
+                println!("  Role: {}", role.name());
+                println!("  Sample rate: {} Hz", sample_rate);
+                println!("  Samples per frame: {}", samples_per_frame);
+                println!("  Channel mode: {:?}", channel_mode);
+                println!("  Encoder delay: {}, padding: {}", delay, padding);
+            },
+            Codec::Unknown => (),
+        }
+        if let Some(ref unusable) = info.unusable {
+            println!("  Unusable: {}", unusable);
+        }
+        println!("  Pages: {}, packets: {}, bytes: {}", info.pages, info.packets, info.bytes);
+        if let Some(duration) = info.duration() {
+            println!("  Duration: {}.{:03} s", duration / 1000_000, duration / 1000 % 1000);
+        }
+        if let Some(bitrate) = info.bitrate() {
+            println!("  Bitrate: {} bit/s", bitrate);
+        }
+    }
+    for &(link, serial) in &report.indexes {
+        println!("Seek index {:08x} (link {})", serial, link);
+    }
+    if report.diagnostics != Default::default() {
+        println!("Damage: {:?}", report.diagnostics);
+    }
+    if let Some(ref desc) = report.last_recovered_error {
+        println!("Last damage ignored: {}", desc);
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_option(value: Option<u64>) -> String {
+    value.map_or_else(|| "null".to_owned(), |value| value.to_string())
+}
+
+/// The report as one JSON document, whether or not the file could be
+/// read to the end
+fn to_json(input: &Path, report: &Report) -> String {
+    let mut out = String::new();
+    write!(out, "{{\"file\": {}, \"error\": {}, \"streams\": [", json_string(&input.to_string_lossy()),
+           report.error.as_ref().map_or_else(|| "null".to_owned(), |error| json_string(error))).unwrap();
+    for (i, info) in report.streams.iter().enumerate() {
+        let info = info.borrow();
+        if i != 0 {
+            out.push_str(", ");
+        }
+        write!(out, "{{\"link\": {}, \"serial\": {}, \"codec\": {}, \"version\": \"{}.{}\"",
+               info.link, info.serial, json_string(info.codec_name), info.version.0, info.version.1).unwrap();
+        match info.codec {
+            Codec::Cdg{compression, sectors_per_packet} => {
+                write!(out, ", \"compression\": {}, \"sectors_per_packet\": {}, \"keyframes\": {}",
+                       json_string(&format!("{:?}", compression)), sectors_per_packet, info.keyframes).unwrap();
+            },
+            Codec::Mp3{sample_rate, samples_per_frame, channel_mode, delay, padding, role} => {
+                write!(out, ", \"role\": {}, \"sample_rate\": {}, \"samples_per_frame\": {}, \"channel_mode\": {}, \"delay\": {}, \"padding\": {}",
+                       json_string(role.name()), sample_rate, samples_per_frame, json_string(&format!("{:?}", channel_mode)), delay, padding).unwrap();
+            },
+            Codec::Unknown => (),
+        }
+        if let Some(ref unusable) = info.unusable {
+            write!(out, ", \"unusable\": {}", json_string(unusable)).unwrap();
+        }
+        write!(out, ", \"pages\": {}, \"packets\": {}, \"bytes\": {}, \"duration_us\": {}, \"bitrate\": {}}}",
+               info.pages, info.packets, info.bytes, json_option(info.duration()), json_option(info.bitrate())).unwrap();
+    }
+    out.push_str("], \"indexes\": [");
+    for (i, &(link, serial)) in report.indexes.iter().enumerate() {
+        if i != 0 {
+            out.push_str(", ");
+        }
+        write!(out, "{{\"link\": {}, \"serial\": {}}}", link, serial).unwrap();
+    }
+    let diagnostics = &report.diagnostics;
+    write!(out, "], \"diagnostics\": {{\"crc_failures\": {}, \"resync_bytes\": {}, \"resyncs\": {}, \"sequence_gaps\": {}, \"orphan_pages\": {}, \"misplaced_bos_pages\": {}, \"recovered_errors\": {}, \"last_recovered_error\": {}}}}}",
+           diagnostics.crc_failures, diagnostics.resync_bytes, diagnostics.resyncs, diagnostics.sequence_gaps,
+           diagnostics.orphan_pages, diagnostics.misplaced_bos_pages, diagnostics.recovered_errors,
+           report.last_recovered_error.as_ref().map_or_else(|| "null".to_owned(), |desc| json_string(desc))).unwrap();
+    out
+}
+
+/// Note the link and serial of the streams that just started, and
+/// of the link's index
+fn collect_streams<R: io::Read>(demux: &mut OggDemux<R, SharedInfo>, streams: &mut Vec<SharedInfo>, indexes: &mut Vec<(u32, u32)>) {
+    let link = demux.link();
+    indexes.extend(demux.index_serial().map(|serial| (link, serial)));
+    let mut new_streams : Vec<_> = demux.streams().map(|(serial, info)| {
+        {
+            let mut info = info.borrow_mut();
+            info.link = link;
+            info.serial = serial;
+        }
+        info.clone()
+    }).collect();
+    new_streams.sort_by_key(|info| info.borrow().serial);
+    streams.extend(new_streams);
+}
+
+/// Read through a file, noting what's in it
+fn probe<R: io::Read>(reader: R) -> Report {
+    let mut report = Report{
+        streams: Vec::new(),
+        indexes: Vec::new(),
+        diagnostics: Default::default(),
+        last_recovered_error: None,
+        error: None,
+    };
+    let registry = Registry::builtin();
+    let mut demux = match OggDemux::new_tolerant(reader, move |header| Some(start_stream(&registry, header))) {
+        Ok(demux) => demux,
+        Err(e) => {
+            report.error = Some(e.to_string());
+            return report;
+        },
+    };
+    collect_streams(&mut demux, &mut report.streams, &mut report.indexes);
+    while !demux.is_eof() {
+        if let Err(e) = demux.pump_page() {
+            report.error = Some(e.to_string());
+            break;
+        }
+        while let Some(event) = demux.next_event() {
+            match event {
+                DemuxEvent::NewLink{..} => collect_streams(&mut demux, &mut report.streams, &mut report.indexes),
+            }
+        }
+    }
+    report.diagnostics = demux.diagnostics();
+    report.last_recovered_error = demux.last_recovered_error().map(str::to_owned);
+    report
+}
+
+/// Describe each stream of an OGK file. With `json`, exactly one JSON
+/// document goes to stdout, even if the file can't be read; messages
+/// go to stderr. Returns false if the file couldn't be read.
+pub fn info(input: &Path, json: bool) -> bool {
+    let report = match fs::File::open(input) {
+        Ok(file) => probe(io::BufReader::new(file)),
+        Err(e) => Report{
+            streams: Vec::new(),
+            indexes: Vec::new(),
+            diagnostics: Default::default(),
+            last_recovered_error: None,
+            error: Some(format!("failed to open: {}", e)),
+        },
+    };
+    if json {
+        if let Some(ref error) = report.error {
+            eprintln!("Failed to read {:?}: {}", input, error);
+        }
+        println!("{}", to_json(input, &report));
+    } else {
+        print_text(input, &report);
+        if let Some(ref error) = report.error {
+            println!("Failed to read {:?}: {}", input, error);
+        }
+    }
+    report.error.is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::Path;
+    use test_util;
+    use super::*;
+
+    fn skip_space(json: &[u8], pos: &mut usize) {
+        while json.get(*pos).map_or(false, |c| b" \t\r\n".contains(c)) {
+            *pos += 1;
+        }
+    }
+
+    fn string(json: &[u8], pos: &mut usize) -> bool {
+        if json.get(*pos) != Some(&b'"') {
+            return false;
+        }
+        *pos += 1;
+        loop {
+            match json.get(*pos) {
+                Some(&b'"') => {
+                    *pos += 1;
+                    return true;
+                },
+                Some(&b'\\') => *pos += if json.get(*pos + 1) == Some(&b'u') { 6 } else { 2 },
+                Some(&c) if c >= 0x20 => *pos += 1,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Just enough of a JSON parser to tell whether a value is valid
+    fn value(json: &[u8], pos: &mut usize) -> bool {
+        skip_space(json, pos);
+        let close = match json.get(*pos) {
+            Some(&b'{') => b'}',
+            Some(&b'[') => b']',
+            Some(&b'"') => return string(json, pos),
+            Some(_) => {
+                let start = *pos;
+                while json.get(*pos).map_or(false, |c| c.is_ascii_alphanumeric() || b"+-.".contains(c)) {
+                    *pos += 1;
+                }
+                let token = String::from_utf8_lossy(&json[start..*pos]).into_owned();
+                return ["null", "true", "false"].contains(&&token[..]) || token.parse::<f64>().is_ok();
+            },
+            None => return false,
+        };
+        *pos += 1;
+        skip_space(json, pos);
+        if json.get(*pos) == Some(&close) {
+            *pos += 1;
+            return true;
+        }
+        loop {
+            if close == b'}' {
+                if !string(json, pos) {
+                    return false;
+                }
+                skip_space(json, pos);
+                if json.get(*pos) != Some(&b':') {
+                    return false;
+                }
+                *pos += 1;
+            }
+            if !value(json, pos) {
+                return false;
+            }
+            skip_space(json, pos);
+            match json.get(*pos) {
+                Some(&b',') => *pos += 1,
+                Some(&c) if c == close => {
+                    *pos += 1;
+                    return true;
+                },
+                _ => return false,
+            }
+            skip_space(json, pos);
+        }
+    }
+
+    fn is_json(json: &str) -> bool {
+        let mut pos = 0;
+        value(json.as_bytes(), &mut pos) && json[pos..].trim().is_empty()
+    }
+
+    #[test]
+    fn json_is_one_valid_document_for_damaged_files() {
+        let song = test_util::mux_song(test_util::mp3(40, true, false), test_util::cdg(200), true);
+        let whole = probe(Cursor::new(&song[..]));
+        assert_eq!(whole.streams.len(), 2);
+        assert!(whole.error.is_none());
+        assert!(is_json(&to_json(Path::new("whole.ogk"), &whole)));
+
+        // Cut off mid-page, as by an interrupted download
+        let truncated = probe(Cursor::new(&song[..song.len() * 2 / 3]));
+        assert_eq!(truncated.diagnostics.recovered_errors, 1);
+        let json = to_json(Path::new("\"truncated\".ogk"), &truncated);
+        assert!(is_json(&json), "{}", json);
+        assert!(json.contains("\"last_recovered_error\": \"truncated page at end of input\""));
+
+        // Not an OGK file at all
+        let garbage = probe(Cursor::new(vec![0x4F; 1000]));
+        let json = to_json(Path::new("garbage.ogk"), &garbage);
+        assert!(is_json(&json), "{}", json);
+
+        assert!(!is_json("{\"a\": 1,}") && !is_json("Ignoring truncated page {}"));
+    }
+}
@@ -1,8 +1,96 @@
 extern crate ogk;
 extern crate clap;
+extern crate cdg;
+extern crate cdg_renderer;
+#[cfg(feature = "mpg123")]
+extern crate mpg123;
+extern crate num_cpus;
+extern crate zip;
 use clap::{Arg,App,SubCommand};
+use std::ffi::OsStr;
 use std::fs;
-use std::io::BufReader;
+use std::path::Path;
+use std::io::{self,BufRead,BufReader,Read,Write};
+
+mod demux;
+mod edit;
+mod import;
+mod info;
+mod verify;
+#[cfg(test)]
+mod test_util;
+
+/// Split a role label such as "guide=" off the front of an input
+/// file name. Anything that isn't a known role is part of the name.
+fn split_role(value: &OsStr) -> (Option<ogk::mp3::AudioRole>, &OsStr) {
+    if let Some(value) = value.to_str() {
+        if let Some(i) = value.find('=') {
+            if let Some(role) = ogk::mp3::AudioRole::from_name(&value[..i]) {
+                return (Some(role), OsStr::new(&value[i+1..]));
+            }
+        }
+    }
+    (None, value)
+}
+
+fn read_file(path: &OsStr) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    try!(try!(fs::File::open(path)).read_to_end(&mut contents));
+    Ok(contents)
+}
+
+/// A stable hash of a file's contents, for --reproducible
+fn hash_file(path: &OsStr) -> io::Result<u64> {
+    let mut file = BufReader::new(try!(fs::File::open(path)));
+    let mut hash = ogk::util::FNV_OFFSET;
+    loop {
+        let len = {
+            let buf = try!(file.fill_buf());
+            hash = ogk::util::fnv1a(hash, buf);
+            buf.len()
+        };
+        if len == 0 {
+            return Ok(hash);
+        }
+        file.consume(len);
+    }
+}
+
+/// A serial number, in decimal or 0x-prefixed hex
+fn parse_serial(value: &str) -> Option<u32> {
+    if value.starts_with("0x") {
+        u32::from_str_radix(&value[2..], 16).ok()
+    } else {
+        value.parse().ok()
+    }
+}
+
+/// The furthest --cdg-offset may move the graphics, in ms. Anything
+/// more is surely a mistake, and would need hours of padding.
+const MAX_CDG_OFFSET_MS: i64 = 60 * 60 * 1000;
+
+/// A time given as [[H:]M:]S[.FRAC], in µs
+fn parse_time(value: &str) -> Option<u64> {
+    let (whole, frac) = match value.find('.') {
+        Some(i) => (&value[..i], &value[i+1..]),
+        None => (value, ""),
+    };
+    if frac.len() > 6 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let mut seconds: u64 = 0;
+    for (i, part) in whole.split(':').enumerate() {
+        match part.parse::<u64>() {
+            Ok(n) if i < 3 => seconds = match seconds.checked_mul(60).and_then(|s| s.checked_add(n)) {
+                Some(seconds) => seconds,
+                None => return None,
+            },
+            _ => return None,
+        }
+    }
+    let micros: u64 = format!("{:0<6}", frac).parse().unwrap();
+    seconds.checked_mul(1000_000).and_then(|s| s.checked_add(micros))
+}
 
 fn main() {
     let matches = App::new("OGK tool")
@@ -21,38 +109,336 @@ fn main() {
                          .long("mp3")
                          .multiple(true)
                          .number_of_values(1)
-                         .value_name("FILE")))
+                         .value_name("[ROLE=]FILE")
+                         .help("An audio stream. ROLE is main (the default), instrumental, guide or backing"))
+                    .arg(Arg::with_name("cdg-offset")
+                         .long("cdg-offset")
+                         .value_name("MS")
+                         .allow_hyphen_values(true)
+                         .help("Show the graphics this many milliseconds later (or earlier, if negative) than in the CDG file"))
+                    .arg(Arg::with_name("cdg-compression")
+                         .long("cdg-compression")
+                         .value_name("METHOD")
+                         .possible_values(&["none", "lz4", "zstd"])
+                         .help("How to compress the graphics. Defaults to lz4, or zstd with --cdg-dictionary"))
+                    .arg(Arg::with_name("cdg-dictionary")
+                         .long("cdg-dictionary")
+                         .value_name("FILE")
+                         .help("A zstd dictionary for the graphics, as made by cdg-dict"))
+                    .arg(Arg::with_name("index")
+                         .long("index")
+                         .help("Add a seek index, so that players can seek without searching the file"))
+                    .arg(Arg::with_name("reproducible")
+                         .long("reproducible")
+                         .help("Derive serial numbers from the input files, so that the same inputs always give the same output"))
+                    .arg(Arg::with_name("serial")
+                         .long("serial")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("SERIAL")
+                         .help("Serial numbers for the streams, in the order given, MP3s first. Streams without one get a random serial, or a derived one with --reproducible")))
+        .subcommand(SubCommand::with_name("cdg-dict")
+                    .about("Train a zstd dictionary for compressing graphics on some CDG files")
+                    .arg(Arg::with_name("OUTPUT")
+                         .required(true))
+                    .arg(Arg::with_name("INPUT")
+                         .required(true)
+                         .multiple(true))
+                    .arg(Arg::with_name("size")
+                         .long("size")
+                         .value_name("BYTES")
+                         .help("The largest the dictionary may be. Defaults to 16384")))
+        .subcommand(SubCommand::with_name("chain")
+                    .about("Join OGK files into one chained file, to be played back to back")
+                    .arg(Arg::with_name("OUTPUT")
+                         .required(true)
+                         .help("The output file, or - for stdout"))
+                    .arg(Arg::with_name("INPUT")
+                         .required(true)
+                         .multiple(true)))
+        .subcommand(SubCommand::with_name("cut")
+                    .about("Copy part of an OGK file, such as a preview clip or a song without its intro")
+                    .arg(Arg::with_name("OUTPUT")
+                         .required(true))
+                    .arg(Arg::with_name("INPUT")
+                         .required(true))
+                    .arg(Arg::with_name("from")
+                         .long("from")
+                         .value_name("TIME")
+                         .help("Where the cut starts, as [[H:]M:]S[.FRAC]. Defaults to the start"))
+                    .arg(Arg::with_name("to")
+                         .long("to")
+                         .value_name("TIME")
+                         .help("Where the cut ends. Defaults to the end")))
+        .subcommand(SubCommand::with_name("concat")
+                    .about("Join OGK files into one song, such as a medley. Each must have the same streams")
+                    .arg(Arg::with_name("OUTPUT")
+                         .required(true))
+                    .arg(Arg::with_name("INPUT")
+                         .required(true)
+                         .multiple(true)))
+        .subcommand(SubCommand::with_name("demux")
+                    .about("Extract the original .cdg and .mp3 files from an OGK file")
+                    .arg(Arg::with_name("INPUT")
+                         .required(true))
+                    .arg(Arg::with_name("prefix")
+                         .long("prefix")
+                         .value_name("PREFIX")
+                         .help("Name outputs PREFIX.cdg, PREFIX.mp3, PREFIX.2.mp3 and so on. Defaults to INPUT without its extension"))
+                    .arg(Arg::with_name("force")
+                         .long("force")
+                         .short("f")
+                         .help("Overwrite existing files")))
+        .subcommand(SubCommand::with_name("info")
+                    .alias("probe")
+                    .about("Describe the streams in an OGK file")
+                    .arg(Arg::with_name("INPUT")
+                         .required(true))
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("Print the report as JSON")))
+        .subcommand(SubCommand::with_name("verify")
+                    .about("Check that an OGK file is intact and follows the specs")
+                    .arg(Arg::with_name("INPUT")
+                         .required(true)
+                         .multiple(true)))
+        .subcommand(SubCommand::with_name("import")
+                    .about("Mux every MP3+G pair found in zip archives and directories")
+                    .arg(Arg::with_name("INPUT")
+                         .required(true)
+                         .multiple(true))
+                    .arg(Arg::with_name("output-dir")
+                         .long("output-dir")
+                         .short("o")
+                         .value_name("DIR")
+                         .help("Where to write the OGK files. Defaults to the current directory"))
+                    .arg(Arg::with_name("jobs")
+                         .long("jobs")
+                         .short("j")
+                         .value_name("N")
+                         .help("How many pairs to mux at once. Defaults to the number of CPUs"))
+                    .arg(Arg::with_name("force")
+                         .long("force")
+                         .short("f")
+                         .help("Overwrite existing files")))
         .get_matches();
     match matches.subcommand() {
         ("mux", Some(matches)) => {
             let mut mux = ogk::ogg::OgkMux::new();
+            // In sectors, of which there are 75 per second
+            let cdg_offset = match matches.value_of("cdg-offset").map(str::parse::<i64>) {
+                None => 0,
+                Some(Ok(ms)) if -MAX_CDG_OFFSET_MS <= ms && ms <= MAX_CDG_OFFSET_MS => (ms * 75 + ms.signum() * 500) / 1000,
+                Some(Ok(_)) => {
+                    println!("--cdg-offset can't move the graphics by more than an hour");
+                    std::process::exit(1);
+                },
+                Some(Err(_)) => {
+                    println!("--cdg-offset must be a whole number of milliseconds");
+                    std::process::exit(1);
+                },
+            };
+            let dictionary = matches.value_of_os("cdg-dictionary").map(|file| match read_file(file) {
+                Ok(dictionary) => dictionary,
+                Err(e) => {
+                    println!("Failed to read dictionary {:?}: {}", file, e);
+                    std::process::exit(1);
+                },
+            });
+            let compression = match matches.value_of("cdg-compression") {
+                Some(name) => ogk::cdg::Compression::from_name(name).unwrap(),
+                None if dictionary.is_some() => ogk::cdg::Compression::Zstd,
+                None => ogk::cdg::Compression::LZ4,
+            };
+            let mut serials = Vec::new();
+            for value in matches.values_of("serial").into_iter().flat_map(|values| values) {
+                match parse_serial(value) {
+                    Some(serial) => serials.push(serial),
+                    None => {
+                        println!("--serial must be a 32-bit number, such as 1234 or 0x4d2");
+                        std::process::exit(1);
+                    },
+                }
+            }
+            if serials.iter().enumerate().any(|(i, serial)| serials[..i].contains(serial)) {
+                println!("Each --serial must be different");
+                std::process::exit(1);
+            }
+            let mut serials = serials.into_iter();
+            // The input file for each stream, in the order they were added
+            let mut inputs = Vec::new();
             if let Some(values) = matches.values_of_os("mp3") {
-                for file in values {
-                    use ogk::mp3::OggMP3Coder;
-                    match fs::File::open(file).map(BufReader::new).and_then(OggMP3Coder::new).map(Box::new) {
+                for value in values {
+                    use ogk::mp3::{AudioRole,OggMP3Coder};
+                    let (role, file) = split_role(value);
+                    match fs::File::open(file).map(BufReader::new).and_then(OggMP3Coder::new) {
                         Err(e) => {
                             println!("Failed to open MP3 file {:?}: {}", file, e);
                             std::process::exit(1);
                         },
-                        Ok(f) => mux.add_stream(f),
+                        Ok(mut f) => {
+                            f.set_role(role.unwrap_or(AudioRole::Main));
+                            match serials.next() {
+                                Some(serial) => mux.add_stream_with_serial(Box::new(f), serial),
+                                None => mux.add_stream(Box::new(f)),
+                            }
+                        },
                     }
+                    inputs.push(file);
                 }
             }
             if let Some(values) = matches.values_of_os("cdg") {
                 use ogk::cdg::OggCdgCoder;
                 for file in values {
-                    match fs::File::open(file).map(BufReader::new).map(OggCdgCoder::new).map(Box::new) {
+                    match fs::File::open(file).map(BufReader::new).map(|f| OggCdgCoder::with_offset(f, cdg_offset)) {
                         Err(e) => {
                             println!("Failed to open CDG file {:?}: {}", file, e);
                             std::process::exit(1);
                         },
-                        Ok(f) => mux.add_stream(f),
+                        Ok(mut f) => {
+                            if let Err(e) = f.set_compression(compression, dictionary.clone()) {
+                                println!("Can't compress graphics with {}: {}", compression.name(), e);
+                                std::process::exit(1);
+                            }
+                            match serials.next() {
+                                Some(serial) => mux.add_stream_with_serial(Box::new(f), serial),
+                                None => mux.add_stream(Box::new(f)),
+                            }
+                        },
                     }
+                    inputs.push(file);
                 }
             }
 
+            if serials.next().is_some() {
+                println!("More serial numbers were given than there are streams");
+                std::process::exit(1);
+            }
+            if matches.is_present("reproducible") {
+                let mut key = ogk::util::FNV_OFFSET;
+                for file in &inputs {
+                    match hash_file(file) {
+                        Ok(hash) => key = ogk::util::fnv1a(key, format!("{:016x}", hash).as_bytes()),
+                        Err(e) => {
+                            println!("Failed to read {:?}: {}", file, e);
+                            std::process::exit(1);
+                        },
+                    }
+                }
+                mux.set_serial_source(ogk::ogg::SerialSource::Hashed(key));
+            }
+            mux.set_write_index(matches.is_present("index"));
+
             let ofile = fs::File::create(matches.value_of_os("OUTPUT").unwrap()).expect("Failed to open output file");
-            mux.write_to(ofile).expect("Failed to write output file");
+            if let Err(e) = mux.write_to(ofile) {
+                println!("Failed to write output file: {}", e);
+                std::process::exit(1);
+            }
+            for &(stream, ref warning) in mux.warnings() {
+                println!("Warning: {:?}: {}", inputs[stream], warning);
+            }
+        },
+        ("cdg-dict", Some(matches)) => {
+            let size = match matches.value_of("size").map(str::parse) {
+                None => 16384,
+                Some(Ok(size)) => size,
+                Some(Err(_)) => {
+                    println!("--size must be a number of bytes");
+                    std::process::exit(1);
+                },
+            };
+            let mut cdgs = Vec::new();
+            for file in matches.values_of_os("INPUT").unwrap() {
+                match read_file(file) {
+                    Ok(cdg) => cdgs.push(cdg),
+                    Err(e) => {
+                        println!("Failed to read CDG file {:?}: {}", file, e);
+                        std::process::exit(1);
+                    },
+                }
+            }
+            match ogk::cdg::train_dictionary(&cdgs, size) {
+                Ok(dictionary) => {
+                    let mut ofile = fs::File::create(matches.value_of_os("OUTPUT").unwrap()).expect("Failed to open output file");
+                    ofile.write_all(&dictionary).expect("Failed to write output file");
+                },
+                Err(e) => {
+                    println!("Failed to train a dictionary: {}", e);
+                    std::process::exit(1);
+                },
+            }
+        },
+        ("chain", Some(matches)) => {
+            let output = matches.value_of_os("OUTPUT").unwrap();
+            let mut ofile : Box<io::Write> = if output == "-" {
+                Box::new(io::stdout())
+            } else {
+                Box::new(fs::File::create(output).expect("Failed to open output file"))
+            };
+            let inputs: Vec<&Path> = matches.values_of_os("INPUT").unwrap().map(Path::new).collect();
+            if !edit::chain(&inputs, &mut ofile) {
+                std::process::exit(1);
+            }
+        },
+        ("cut", Some(matches)) => {
+            let mut times = Vec::new();
+            for name in &["from", "to"] {
+                times.push(matches.value_of(name).map(|value| match parse_time(value) {
+                    Some(time) => time,
+                    None => {
+                        println!("--{} must be a time, such as 90, 1:30 or 1:30.25", name);
+                        std::process::exit(1);
+                    },
+                }));
+            }
+            let output = Path::new(matches.value_of_os("OUTPUT").unwrap());
+            let input = Path::new(matches.value_of_os("INPUT").unwrap());
+            if !edit::cut(input, output, times[0].unwrap_or(0), times[1]) {
+                std::process::exit(1);
+            }
+        },
+        ("concat", Some(matches)) => {
+            let inputs: Vec<_> = matches.values_of_os("INPUT").unwrap().map(Path::new).collect();
+            if !edit::concat(&inputs, Path::new(matches.value_of_os("OUTPUT").unwrap())) {
+                std::process::exit(1);
+            }
+        },
+        ("demux", Some(matches)) => {
+            let input = Path::new(matches.value_of_os("INPUT").unwrap());
+            let prefix = matches.value_of_os("prefix").map_or_else(|| input.with_extension(""), |prefix| Path::new(prefix).to_owned());
+            if !demux::demux(input, &prefix, matches.is_present("force")) {
+                std::process::exit(1);
+            }
+        },
+        ("info", Some(matches)) => {
+            let input = Path::new(matches.value_of_os("INPUT").unwrap());
+            if !info::info(input, matches.is_present("json")) {
+                std::process::exit(1);
+            }
+        },
+        ("verify", Some(matches)) => {
+            let mut ok = true;
+            for input in matches.values_of_os("INPUT").unwrap() {
+                ok &= verify::verify(Path::new(input));
+            }
+            if !ok {
+                std::process::exit(1);
+            }
+        },
+        ("import", Some(matches)) => {
+            let inputs: Vec<_> = matches.values_of_os("INPUT").unwrap().map(Path::new).collect();
+            let out_dir = Path::new(matches.value_of_os("output-dir").unwrap_or(".".as_ref()));
+            let jobs = match matches.value_of("jobs").map(str::parse) {
+                None => num_cpus::get(),
+                Some(Ok(jobs)) => jobs,
+                Some(Err(_)) => {
+                    println!("--jobs must be a number");
+                    std::process::exit(1);
+                },
+            };
+            if !import::import(&inputs, out_dir, jobs, matches.is_present("force")) {
+                std::process::exit(1);
+            }
         },
         (_, _) => println!("{}", matches.usage()),
     }
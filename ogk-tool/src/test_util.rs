@@ -0,0 +1,164 @@
+//! Synthetic songs for the tests, and ways to take them apart
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::io::{self,Cursor};
+use std::path::PathBuf;
+use std::process;
+
+use ogk::ogg::{self,BitstreamCoder,OggPageSource,OgkMux,Page};
+use ogk::cdg::OggCdgCoder;
+use ogk::mp3::OggMP3Coder;
+
+/// MPEG 1 Layer III, 128 kbit/s, 44.1 kHz, stereo, no CRC
+pub const FRAME_HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0x00];
+pub const FRAME_LEN: usize = 417;
+
+/// An audio frame whose body says which one it is
+pub fn frame(n: usize) -> Vec<u8> {
+    let mut frame = vec![(n % 0x80) as u8; FRAME_LEN];
+    frame[0..4].copy_from_slice(&FRAME_HEADER);
+    frame
+}
+
+/// An ID3v2 tag with 128 bytes of body
+pub fn id3_tag() -> Vec<u8> {
+    let mut tag = b"ID3\x04\x00\x00\x00\x00\x01\x00".to_vec();
+    tag.extend(vec![0x55; 128]);
+    tag
+}
+
+/// A LAME Info frame giving a delay of 576 + 529 samples and padding
+/// of 1260 - 529
+pub fn lame_info_frame() -> Vec<u8> {
+    let mut frame = vec![0; FRAME_LEN];
+    frame[0..4].copy_from_slice(&FRAME_HEADER);
+    frame[36..40].copy_from_slice(b"Info");
+    frame[43] = 0x0F;
+    let lame = 36 + 8 + 4 + 4 + 100 + 4;
+    frame[lame..lame+9].copy_from_slice(b"LAME3.100");
+    frame[lame+21..lame+24].copy_from_slice(&[0x24, 0x04, 0xEC]);
+    frame
+}
+
+/// An .mp3 of `frames` audio frames, optionally with a tag and an
+/// info frame in front
+pub fn mp3(frames: usize, tag: bool, info: bool) -> Vec<u8> {
+    let mut mp3 = Vec::new();
+    if tag {
+        mp3.extend(id3_tag());
+    }
+    if info {
+        mp3.extend(lame_info_frame());
+    }
+    for n in 0..frames {
+        mp3.extend(frame(n));
+    }
+    mp3
+}
+
+/// A subchannel packet
+pub fn command(instruction: u8, data: &[u8]) -> [u8; 24] {
+    let mut packet = [0; 24];
+    packet[0] = 9;
+    packet[1] = instruction;
+    packet[4..4 + data.len()].copy_from_slice(data);
+    packet
+}
+
+/// A .cdg that draws a different tile in every sector, with a palette
+/// load and a memory preset first
+pub fn cdg(sectors: usize) -> Vec<u8> {
+    let mut cdg = Vec::with_capacity(sectors * 96);
+    for n in 0..sectors {
+        let mut sector = [0; 96];
+        if n == 0 {
+            let palette: Vec<u8> = (0..16).map(|i| i * 3 % 0x40).collect();
+            sector[0..24].copy_from_slice(&command(30, &palette));
+            sector[24..48].copy_from_slice(&command(1, &[3, 0]));
+        }
+        let mut tile = [(n % 16) as u8, (n * 7 % 16) as u8, (n / 50 % 18) as u8, (n % 50) as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        for (row, byte) in tile[4..].iter_mut().enumerate() {
+            *byte = ((n + row) * 11 % 0x40) as u8;
+        }
+        sector[72..96].copy_from_slice(&command(6, &tile));
+        cdg.extend_from_slice(&sector);
+    }
+    cdg
+}
+
+/// Packets given up front, for streams the real coders won't make
+pub struct PacketCoder {
+    pub headers: Vec<Vec<u8>>,
+    pub packets: VecDeque<ogg::Packet>,
+}
+
+impl BitstreamCoder for PacketCoder {
+    fn headers(&self) -> Vec<Vec<u8>> {
+        self.headers.clone()
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<ogg::Packet>> {
+        Ok(self.packets.pop_front())
+    }
+
+    fn map_granule(&self, granule: u64) -> u64 {
+        granule
+    }
+}
+
+/// Mux whatever streams are given into one link
+pub fn mux(streams: Vec<Box<BitstreamCoder>>, index: bool) -> Vec<u8> {
+    let mut mux = OgkMux::new();
+    for stream in streams {
+        mux.add_stream(stream);
+    }
+    mux.set_write_index(index);
+    let mut out = Vec::new();
+    mux.write_to(&mut out).unwrap();
+    out
+}
+
+/// Mux an .mp3 and a .cdg, as `ogk mux` would
+pub fn mux_song(mp3: Vec<u8>, cdg: Vec<u8>, index: bool) -> Vec<u8> {
+    mux(vec![
+        Box::new(OggMP3Coder::new(Cursor::new(mp3)).unwrap()),
+        Box::new(OggCdgCoder::new(Cursor::new(cdg))),
+    ], index)
+}
+
+/// Split a file into its pages
+pub fn pages(data: &[u8]) -> Vec<Page> {
+    let mut source = OggPageSource::from_slice(data);
+    let mut pages = Vec::new();
+    while let Some(page) = source.next_page().unwrap() {
+        pages.push(Page{
+            flags: page.flags,
+            granule_position: page.granule_position,
+            stream_serial: page.stream_serial,
+            page_sequence: page.page_sequence,
+            segment_table: page.segment_table.to_vec(),
+            content: page.content.to_vec(),
+        });
+    }
+    pages
+}
+
+/// Put pages back together, with fresh CRCs
+pub fn join(pages: &[Page]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for page in pages {
+        page.write_to(&mut data).unwrap();
+    }
+    data
+}
+
+
+/// A fresh scratch directory, unique to this test
+pub fn scratch_dir(test: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("ogk-tool-{}-{}", process::id(), test));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,706 @@
+use std::cell::{Cell,RefCell};
+use std::collections::{HashMap,HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use cdg::SectorIter;
+use cdg_renderer::CdgInterpreter;
+#[cfg(feature = "mpg123")]
+use mpg123;
+use ogk::ogg::{BitstreamDecoder,DemuxEvent,OggDemux,OggPageSource,PAGE_BOS,PAGE_EOS};
+use ogk::cdg::{CdgGranule,CdgHeader,PacketType};
+use ogk::mp3::{FrameHeader,Mp3Granule,OggMp3Decoder};
+use ogk::index::{self,SeekIndex};
+use ogk::registry::{ContentType,Registry};
+
+/// Something wrong with the file, or a check that couldn't be done.
+/// A stream's decoder may see packets before the demuxer says which
+/// serial it has, so the serial is only looked up when printing.
+struct Problem {
+    stream: Option<Rc<Cell<u32>>>,
+    text: String,
+}
+
+impl Problem {
+    fn describe(&self) -> String {
+        match self.stream {
+            Some(ref serial) => format!("stream {:08x}: {}", serial.get(), self.text),
+            None => self.text.clone(),
+        }
+    }
+}
+
+/// The list of everything that's wrong with the file, shared with the
+/// stream decoders. Checks that couldn't be done are noted separately,
+/// as they don't fail the file.
+#[derive(Clone,Default)]
+struct Problems {
+    problems: Rc<RefCell<Vec<Problem>>>,
+    skipped: Rc<RefCell<Vec<Problem>>>,
+}
+
+impl Problems {
+    fn report<S: Into<String>>(&self, problem: S) {
+        self.problems.borrow_mut().push(Problem{stream: None, text: problem.into()});
+    }
+
+    fn report_in<S: Into<String>>(&self, serial: &Rc<Cell<u32>>, problem: S) {
+        self.problems.borrow_mut().push(Problem{stream: Some(serial.clone()), text: problem.into()});
+    }
+
+    fn skip_in<S: Into<String>>(&self, serial: &Rc<Cell<u32>>, check: S) {
+        self.skipped.borrow_mut().push(Problem{stream: Some(serial.clone()), text: check.into()});
+    }
+
+    fn count(&self) -> usize {
+        self.problems.borrow().len()
+    }
+
+    fn problems(&self) -> Vec<String> {
+        self.problems.borrow().iter().map(Problem::describe).collect()
+    }
+
+    fn skipped(&self) -> Vec<String> {
+        self.skipped.borrow().iter().map(Problem::describe).collect()
+    }
+}
+
+/// What the page check remembers about each stream
+struct PageStream {
+    last_sequence: u32,
+    last_granule: u64,
+    eos: bool,
+}
+
+/// What a link's OggIDX stream should agree with
+#[derive(Default)]
+struct LinkPages {
+    /// Where the link's first page is
+    start: u64,
+    /// The serial of the OggIDX stream and its index packet so far
+    index: Option<(u32, Vec<u8>)>,
+    /// The granule position of every page that has one, by serial
+    /// and offset from the start of the link
+    granules: HashMap<(u32, u64), u64>,
+}
+
+/// Check that every entry of a link's index points at a page of its
+/// stream with the granule position it gives
+fn check_index(link: u32, pages: &LinkPages, problems: &Problems) {
+    let (serial, packet) = match pages.index {
+        Some((serial, ref packet)) => (serial, packet),
+        None => return,
+    };
+    let index = match SeekIndex::parse(packet) {
+        Some(index) => index,
+        None => {
+            problems.report(format!("link {}: index stream {:08x} has no usable index", link, serial));
+            return;
+        },
+    };
+    for stream in index.serials() {
+        let wrong = index.entries(stream).iter().filter(|entry| pages.granules.get(&(stream, entry.offset)) != Some(&entry.granule)).count();
+        if wrong > 0 {
+            problems.report(format!("link {}: {} index entries for stream {:08x} don't match its pages", link, wrong, stream));
+        }
+    }
+}
+
+/// Check the page structure: CRCs, sequence numbers, granule
+/// positions, where the BOS and EOS pages fall, and the index.
+fn check_pages<R: io::Read>(reader: R, problems: &Problems) {
+    let mut source = OggPageSource::new(reader);
+    let mut streams: HashMap<u32, PageStream> = HashMap::new();
+    // Every stream of a chained file needs its own serial
+    let mut earlier_serials = HashSet::new();
+    let mut orphans = HashSet::new();
+    // Until the first non-BOS page, new streams may join the link
+    let mut in_headers = true;
+    let mut link = 0;
+    let mut link_pages = LinkPages::default();
+    let mut page_no = 0u64;
+    loop {
+        let (offset, page) = match source.next_page_at() {
+            Ok(Some(page)) => page,
+            Ok(None) => break,
+            Err(e) => {
+                problems.report(format!("page {}: {}", page_no, e));
+                break;
+            },
+        };
+        let serial = page.stream_serial;
+        let granule = page.granule_position;
+        let eos = page.flags.intersects(PAGE_EOS);
+        if page.flags.intersects(PAGE_BOS) {
+            if !in_headers {
+                if streams.values().all(|stream| stream.eos) {
+                    check_index(link, &link_pages, problems);
+                    link_pages = LinkPages{start: offset, ..Default::default()};
+                    link += 1;
+                    earlier_serials.extend(streams.drain().map(|(serial, _)| serial));
+                    in_headers = true;
+                } else {
+                    problems.report(format!("page {}: stream {:08x} begins in the middle of link {}", page_no, serial, link));
+                }
+            }
+            if streams.contains_key(&serial) {
+                problems.report(format!("page {}: stream {:08x} has a second BOS page", page_no, serial));
+            } else if earlier_serials.contains(&serial) {
+                problems.report(format!("page {}: stream {:08x} reuses the serial of a stream in an earlier link", page_no, serial));
+            }
+            if page.page_sequence != 0 {
+                problems.report(format!("page {}: BOS page of stream {:08x} has sequence number {}", page_no, serial, page.page_sequence));
+            }
+            if index::parse_header(page.content).is_ok() {
+                link_pages.index = Some((serial, Vec::new()));
+            }
+            streams.insert(serial, PageStream{
+                last_sequence: page.page_sequence,
+                last_granule: granule,
+                eos: eos,
+            });
+        } else {
+            in_headers = false;
+            match streams.get_mut(&serial) {
+                None => if orphans.insert(serial) {
+                    problems.report(format!("page {}: stream {:08x} has no BOS page", page_no, serial));
+                },
+                Some(stream) => {
+                    if stream.eos {
+                        problems.report(format!("page {}: stream {:08x} continues after its EOS page", page_no, serial));
+                    }
+                    if page.page_sequence != stream.last_sequence.wrapping_add(1) {
+                        problems.report(format!("page {}: stream {:08x} sequence number jumped from {} to {}",
+                                                page_no, serial, stream.last_sequence, page.page_sequence));
+                    }
+                    match link_pages.index {
+                        Some((index, ref mut packet)) if index == serial => packet.extend_from_slice(page.content),
+                        _ => (),
+                    }
+                    if granule != !0 {
+                        link_pages.granules.insert((serial, offset - link_pages.start), granule);
+                        if stream.last_granule != !0 && granule < stream.last_granule {
+                            problems.report(format!("page {}: stream {:08x} granule position went backwards from {} to {}",
+                                                    page_no, serial, stream.last_granule, granule));
+                        }
+                        stream.last_granule = granule;
+                    }
+                    stream.last_sequence = page.page_sequence;
+                    stream.eos |= eos;
+                },
+            }
+        }
+        page_no += 1;
+    }
+    check_index(link, &link_pages, problems);
+    let mut unfinished: Vec<_> = streams.iter().filter(|&(_, stream)| !stream.eos).map(|(serial, _)| *serial).collect();
+    unfinished.sort();
+    for serial in unfinished {
+        problems.report(format!("stream {:08x} has no EOS page", serial));
+    }
+    if source.crc_failures() > 0 {
+        problems.report(format!("{} pages failed their CRC check", source.crc_failures()));
+    }
+    if source.resync_bytes() > 0 {
+        problems.report(format!("{} bytes between pages aren't part of any page (runs of junk: {})", source.resync_bytes(), source.resyncs()));
+    }
+}
+
+/// Builds the keyframe, decompressed, that the spec says should
+/// match the interpreter's state
+fn expected_keyframe(interp: &CdgInterpreter) -> Vec<u8> {
+    let mut keyframe = Vec::with_capacity(32 + 32400 + 1);
+    for color in interp.palette() {
+        keyframe.extend_from_slice(&color.to_subchannel());
+    }
+    for y in 0..216 {
+        for x in 0..150 {
+            keyframe.push(interp.color_index(x * 2, y) << 4 | interp.color_index(x * 2 + 1, y));
+        }
+    }
+    keyframe.push(interp.transparent_color().unwrap_or(0xFF));
+    keyframe
+}
+
+/// Decodes an OggCDG stream and replays its commands
+struct CdgVerifier {
+    serial: Rc<Cell<u32>>,
+    header: CdgHeader,
+    interp: CdgInterpreter,
+    /// Sectors decoded so far
+    sectors: u64,
+    problems: Problems,
+}
+
+impl CdgVerifier {
+    fn report(&self, problem: &str) {
+        self.problems.report_in(&self.serial, format!("sector {}: {}", self.sectors, problem));
+    }
+}
+
+impl BitstreamDecoder for CdgVerifier {
+    fn map_granule(&self, granule: u64) -> u64 {
+        CdgGranule::from_u64(granule).micros()
+    }
+
+    fn num_headers(&self) -> usize { self.header.num_headers() }
+
+    fn process_header(&mut self, header: &[u8]) {
+        if !self.header.process_header(header) {
+            self.report("header packet of unknown type");
+        }
+    }
+
+    fn process_packet(&mut self, packet: &[u8], last_granule: u64) -> u64 {
+        if packet.len() < 2 {
+            self.report("truncated packet");
+            return last_granule;
+        }
+        match self.header.decode_packet(packet) {
+            Some((PacketType::Command, sectors)) => {
+                let declared = packet[1] as usize;
+                if sectors.len() != declared * 96 {
+                    self.report(&format!("packet declares {} sectors but holds {} bytes", declared, sectors.len()));
+                }
+                for sector in sectors.chunks(96).filter(|sector| sector.len() == 96) {
+                    for command in SectorIter::new(sector) {
+                        self.interp.handle_cmd(command);
+                    }
+                }
+                self.sectors += sectors.len() as u64 / 96;
+            },
+            Some((PacketType::Keyframe, keyframe)) => {
+                let mut keyframe = keyframe.into_owned();
+                // The P and Q bits of the palette don't matter
+                for byte in keyframe.iter_mut().take(32) {
+                    *byte &= 0x3F;
+                }
+                if keyframe != expected_keyframe(&self.interp) {
+                    self.report("keyframe doesn't match the state from replaying the commands");
+                }
+            },
+            Some((PacketType::Dictionary, _)) => self.report("dictionary packet after the headers"),
+            Some((PacketType::Other(n), _)) => self.report(&format!("packet of unknown type {}", n)),
+            None => self.report("packet failed to decompress"),
+        }
+        CdgGranule{sector: self.sectors, keyframe: CdgGranule::from_u64(last_granule).keyframe}.to_u64()
+    }
+
+    fn page_done(&mut self, granule: u64) {
+        let sector = CdgGranule::from_u64(granule).sector;
+        if granule != !0 && sector != self.sectors {
+            self.report(&format!("page granule position is for sector {}", sector));
+        }
+    }
+
+    fn notice_gap(&mut self) {}
+
+    fn finish(&mut self) {}
+}
+
+/// Checks that each frame of an OggMP3 stream is intact and, with the
+/// mpg123 feature, that it decodes
+struct Mp3Verifier {
+    serial: Rc<Cell<u32>>,
+    representative: FrameHeader,
+    clock: Mp3Granule,
+    aux_headers: usize,
+    frames: u64,
+    #[cfg(feature = "mpg123")]
+    decoder: Option<mpg123::Handle<i16>>,
+    #[cfg(feature = "mpg123")]
+    pcm: Vec<i16>,
+    problems: Problems,
+}
+
+impl Mp3Verifier {
+    fn report(&self, problem: &str) {
+        self.problems.report_in(&self.serial, format!("frame {}: {}", self.frames, problem));
+    }
+
+    #[cfg(feature = "mpg123")]
+    fn decode(&mut self, frame: &[u8]) {
+        let result = match self.decoder {
+            Some(ref mut decoder) => {
+                let pcm = &mut self.pcm;
+                decoder.feed(frame).and_then(|_| {
+                    // Drain everything the frame produced
+                    while try!(decoder.shit(pcm)).2 != 0 {}
+                    Ok(())
+                })
+            },
+            None => return,
+        };
+        if let Err(e) = result {
+            self.report(&format!("mpg123 failed to decode it: {:?}", e));
+        }
+    }
+
+    #[cfg(not(feature = "mpg123"))]
+    fn decode(&mut self, packet: &[u8]) {
+        if self.frames == 0 && !packet.is_empty() {
+            self.note_skipped();
+        }
+    }
+
+    /// Say that the frames weren't decoded, so that a clean result
+    /// isn't taken to mean they were
+    #[cfg(not(feature = "mpg123"))]
+    fn note_skipped(&self) {
+        self.problems.skip_in(&self.serial, "frames weren't decoded, as this build doesn't have the mpg123 feature");
+    }
+}
+
+impl BitstreamDecoder for Mp3Verifier {
+    fn map_granule(&self, granule: u64) -> u64 {
+        self.clock.micros(granule)
+    }
+
+    fn num_headers(&self) -> usize { self.aux_headers + 1 }
+
+    fn process_header(&mut self, _: &[u8]) {}
+
+    fn process_packet(&mut self, packet: &[u8], last_granule: u64) -> u64 {
+        match FrameHeader::parse(packet) {
+            None => self.report("frame has no valid header"),
+            Some(header) => {
+                if !header.is_compatible(&self.representative) {
+                    self.report("frame header doesn't match the stream header");
+                }
+                if header.frame_len() != packet.len() {
+                    self.report(&format!("frame is {} bytes, but its header says {}", packet.len(), header.frame_len()));
+                }
+            },
+        }
+        self.decode(packet);
+        self.frames += 1;
+        last_granule + self.representative.samples() as u64
+    }
+
+    fn notice_gap(&mut self) {}
+
+    fn finish(&mut self) {}
+}
+
+#[cfg(feature = "mpg123")]
+fn mp3_decoder(serial: &Rc<Cell<u32>>, problems: &Problems) -> Option<mpg123::Handle<i16>> {
+    let decoder = mpg123::Handle::new().and_then(|mut decoder| {
+        try!(decoder.open_feed());
+        Ok(decoder)
+    });
+    match decoder {
+        Ok(decoder) => Some(decoder),
+        Err(e) => {
+            problems.report_in(serial, format!("failed to start mpg123: {:?}", e));
+            None
+        },
+    }
+}
+
+fn start_cdg(header: &[u8], problems: &Problems) -> Option<(Box<BitstreamDecoder>, Rc<Cell<u32>>)> {
+    // The serial number is filled in once the demuxer has the stream
+    let serial = Rc::new(Cell::new(0));
+    CdgHeader::from_bytes(header).map(|cdg_header| (Box::new(CdgVerifier{
+        serial: serial.clone(),
+        header: cdg_header,
+        interp: CdgInterpreter::new(),
+        sectors: 0,
+        problems: problems.clone(),
+    }) as Box<BitstreamDecoder>, serial))
+}
+
+fn start_mp3(header: &[u8], problems: &Problems) -> Option<(Box<BitstreamDecoder>, Rc<Cell<u32>>)> {
+    let serial = Rc::new(Cell::new(0));
+    // Shortened headers would need to be rebuilt before the frames
+    // can be checked
+    match OggMp3Decoder::new(header) {
+        Some(frames) => Some((Box::new(Mp3Verifier{
+            serial: serial.clone(),
+            representative: frames.representative(),
+            clock: frames.clock(),
+            aux_headers: frames.header().aux_headers,
+            frames: 0,
+            #[cfg(feature = "mpg123")]
+            decoder: mp3_decoder(&serial, problems),
+            #[cfg(feature = "mpg123")]
+            pcm: vec![0; 8192],
+            problems: problems.clone(),
+        }) as Box<BitstreamDecoder>, serial)),
+        None => None,
+    }
+}
+
+/// The built-in codecs, with verifiers as their decoders
+fn registry(problems: &Problems) -> Registry<Rc<Cell<u32>>> {
+    let mut registry = Registry::builtin();
+    let cdg_problems = problems.clone();
+    registry.set_decoder("OggCDG", Box::new(move |header| start_cdg(header, &cdg_problems)));
+    let mp3_problems = problems.clone();
+    registry.set_decoder("OggMP3", Box::new(move |header| start_mp3(header, &mp3_problems)));
+    registry
+}
+
+fn note_serials<R>(demux: &mut OggDemux<R, Rc<Cell<u32>>>) {
+    for (serial, cell) in demux.streams() {
+        cell.set(serial);
+    }
+}
+
+/// Decode every stream, checking packet contents
+fn check_streams<R: io::Read>(reader: R, problems: &Problems) {
+    let stream_problems = problems.clone();
+    let registry = registry(problems);
+    let demux = OggDemux::new_tolerant(reader, move |header| {
+        let stream = registry.identify(header);
+        let is_index = registry.find(header).map(|codec| codec.content_type) == Some(ContentType::Index);
+        if stream.is_none() && !is_index {
+            let reason = match registry.find(header).map(|codec| (codec.parse_header)(header)) {
+                Some(Err(e)) => format!(": {}", e),
+                _ => String::new(),
+            };
+            stream_problems.report(format!("stream with header {:?} can't be checked{}", String::from_utf8_lossy(&header[..header.len().min(8)]), reason));
+        }
+        stream
+    });
+    let mut demux = match demux {
+        Ok(demux) => demux,
+        Err(e) => {
+            problems.report(format!("failed to read the stream headers: {}", e));
+            return;
+        },
+    };
+    note_serials(&mut demux);
+    while !demux.is_eof() {
+        if let Err(e) = demux.pump_page() {
+            problems.report(format!("failed to demux: {}", e));
+            return;
+        }
+        while let Some(event) = demux.next_event() {
+            match event {
+                DemuxEvent::NewLink{..} => note_serials(&mut demux),
+            }
+        }
+    }
+}
+
+/// Fully check an OGK file, listing any problems found. Returns true
+/// if there were none.
+pub fn verify(input: &Path) -> bool {
+    let open = || fs::File::open(input).map(io::BufReader::new);
+    let problems = Problems::default();
+    match open() {
+        Ok(file) => check_pages(file, &problems),
+        Err(e) => {
+            println!("Failed to open OGK file {:?}: {}", input, e);
+            return false;
+        },
+    }
+    match open() {
+        Ok(file) => check_streams(file, &problems),
+        Err(e) => problems.report(format!("failed to reopen: {}", e)),
+    }
+    for problem in problems.problems() {
+        println!("{}: {}", input.display(), problem);
+    }
+    for check in problems.skipped() {
+        println!("{}: not checked: {}", input.display(), check);
+    }
+    match problems.count() {
+        0 => {
+            println!("{}: OK", input.display());
+            true
+        },
+        1 => {
+            println!("{}: 1 problem found", input.display());
+            false
+        },
+        n => {
+            println!("{}: {} problems found", input.display(), n);
+            false
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::Cursor;
+    use cdg::SectorIter;
+    use cdg_renderer::CdgInterpreter;
+    use ogk::ogg::{self,Page,PAGE_BOS,PAGE_EOS};
+    use ogk::cdg::{CdgGranule,CdgHeader,Compression,PacketType};
+    use test_util::{self,PacketCoder};
+    use super::*;
+
+    /// Everything both checks find wrong with a file
+    fn problems_in(data: &[u8]) -> Vec<String> {
+        let problems = Problems::default();
+        check_pages(Cursor::new(data), &problems);
+        check_streams(Cursor::new(data), &problems);
+        problems.problems()
+    }
+
+    fn assert_found(problems: &[String], needle: &str) {
+        assert!(problems.iter().any(|problem| problem.contains(needle)), "no {:?} in {:?}", needle, problems);
+    }
+
+    fn song(index: bool) -> Vec<Page> {
+        test_util::pages(&test_util::mux_song(test_util::mp3(400, false, true), test_util::cdg(400), index))
+    }
+
+    fn is_mp3(page: &Page, pages: &[Page]) -> bool {
+        pages.iter().any(|bos| bos.stream_serial == page.stream_serial && bos.flags.intersects(PAGE_BOS) && bos.content.starts_with(b"OggMP3"))
+    }
+
+    /// Where the pages in the middle of the MP3 stream are
+    fn middle_mp3_pages(pages: &[Page]) -> Vec<usize> {
+        (0..pages.len()).filter(|&i| is_mp3(&pages[i], pages) && !pages[i].flags.intersects(PAGE_BOS | PAGE_EOS) && pages[i].granule_position != !0).collect()
+    }
+
+    #[test]
+    fn passes_an_intact_song() {
+        assert!(problems_in(&test_util::join(&song(true))).is_empty());
+    }
+
+    #[test]
+    fn finds_sequence_gaps() {
+        let mut pages = song(false);
+        let middle = middle_mp3_pages(&pages);
+        pages.remove(middle[middle.len() / 2]);
+        assert_found(&problems_in(&test_util::join(&pages)), "sequence number jumped");
+    }
+
+    #[test]
+    fn finds_misplaced_bos_pages() {
+        let mut pages = song(false);
+        let bos = Page{
+            flags: pages[0].flags,
+            granule_position: pages[0].granule_position,
+            stream_serial: pages[0].stream_serial ^ 0x5A5A,
+            page_sequence: 0,
+            segment_table: pages[0].segment_table.clone(),
+            content: pages[0].content.clone(),
+        };
+        let at = middle_mp3_pages(&pages)[1];
+        pages.insert(at, bos);
+        assert_found(&problems_in(&test_util::join(&pages)), "begins in the middle of link 0");
+    }
+
+    #[test]
+    fn finds_index_entries_that_miss_their_pages() {
+        let mut pages = song(true);
+        let intact = test_util::join(&pages);
+        // Moving every granule keeps them in order, but no longer
+        // matches what the index recorded
+        for i in middle_mp3_pages(&pages) {
+            pages[i].granule_position += 1;
+        }
+        assert!(problems_in(&intact).is_empty());
+        let problems = problems_in(&test_util::join(&pages));
+        assert_found(&problems, "index entries for stream");
+        assert!(!problems.iter().any(|problem| problem.contains("backwards")));
+    }
+
+    #[test]
+    fn finds_crc_failures() {
+        let mut data = test_util::join(&song(false));
+        let len = data.len();
+        data[len / 2] ^= 0xFF;
+        assert_found(&problems_in(&data), "1 pages failed their CRC check");
+    }
+
+    /// A single uncompressed OggCDG stream of the given packets
+    fn cdg_stream(packets: Vec<(PacketType, u8, Vec<u8>, CdgGranule)>) -> Vec<u8> {
+        let mut header = CdgHeader::new();
+        header.compression = Compression::None;
+        let packets: VecDeque<_> = packets.into_iter().map(|(typ, declared, body, granule)| {
+            let mut content = vec![typ.to_u8(), declared];
+            content.extend(body);
+            ogg::Packet{content: content, timestamp: granule.to_u64()}
+        }).collect();
+        test_util::mux(vec![Box::new(PacketCoder{
+            headers: vec![header.to_bytes()],
+            packets: packets,
+        })], false)
+    }
+
+    #[test]
+    fn checks_keyframes_against_the_replayed_commands() {
+        let cdg = test_util::cdg(20);
+        let mut interp = CdgInterpreter::new();
+        for sector in cdg.chunks(96) {
+            for command in SectorIter::new(sector) {
+                interp.handle_cmd(command);
+            }
+        }
+        let keyframe = expected_keyframe(&interp);
+        let mut wrong = keyframe.clone();
+        wrong[32 + 1000] ^= 0x11;
+        let at = CdgGranule{sector: 20, keyframe: 20};
+        let stream = |keyframe: Vec<u8>| cdg_stream(vec![
+            (PacketType::Command, 20, cdg.clone(), CdgGranule{sector: 20, keyframe: 0}),
+            (PacketType::Keyframe, 0, keyframe, at),
+        ]);
+        assert!(problems_in(&stream(keyframe)).is_empty());
+        let problems = problems_in(&stream(wrong));
+        assert_eq!(problems.len(), 1);
+        assert_found(&problems, "sector 20: keyframe doesn't match the state from replaying the commands");
+    }
+
+    #[test]
+    fn finds_packets_that_hold_fewer_sectors_than_declared() {
+        let cdg = test_util::cdg(3);
+        let problems = problems_in(&cdg_stream(vec![
+            (PacketType::Command, 5, cdg[..2 * 96].to_vec(), CdgGranule{sector: 2, keyframe: 0}),
+            (PacketType::Command, 1, cdg[2 * 96..].to_vec(), CdgGranule{sector: 3, keyframe: 0}),
+        ]));
+        assert_found(&problems, "packet declares 5 sectors but holds 192 bytes");
+    }
+
+    #[test]
+    fn finds_serials_reused_by_a_later_link() {
+        let song = test_util::join(&song(false));
+        let mut chained = song.clone();
+        chained.extend_from_slice(&song);
+        let problems = problems_in(&chained);
+        assert_eq!(problems.iter().filter(|problem| problem.contains("reuses the serial of a stream in an earlier link")).count(), 2);
+    }
+
+    #[test]
+    fn names_the_stream_of_problems_found_before_the_demuxer_does() {
+        // Each link's first packet is wrong, so the problems are found
+        // while the demuxer is still reading the link's headers
+        let cdg = test_util::cdg(3);
+        let link = || cdg_stream(vec![
+            (PacketType::Command, 5, cdg[..2 * 96].to_vec(), CdgGranule{sector: 2, keyframe: 0}),
+        ]);
+        let (first, second) = (link(), link());
+        let mut chained = first.clone();
+        chained.extend_from_slice(&second);
+        let problems = problems_in(&chained);
+        for link in &[first, second] {
+            let serial = test_util::pages(link)[0].stream_serial;
+            assert_found(&problems, &format!("stream {:08x}: sector 0: packet declares 5 sectors", serial));
+        }
+        assert!(!problems.iter().any(|problem| problem.contains("00000000")));
+    }
+
+    #[cfg(not(feature = "mpg123"))]
+    #[test]
+    fn says_which_streams_werent_decoded() {
+        let (first, second) = (test_util::join(&song(false)), test_util::join(&song(false)));
+        let mut chained = first.clone();
+        chained.extend_from_slice(&second);
+        let problems = Problems::default();
+        check_streams(Cursor::new(&chained), &problems);
+        let skipped = problems.skipped();
+        assert_eq!(skipped.len(), 2);
+        for link in &[first, second] {
+            let pages = test_util::pages(link);
+            let mp3 = pages.iter().find(|page| page.content.starts_with(b"OggMP3")).unwrap();
+            assert_found(&skipped, &format!("stream {:08x}: frames weren't decoded", mp3.stream_serial));
+        }
+    }
+}
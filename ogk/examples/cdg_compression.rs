@@ -0,0 +1,91 @@
+//! Compares the OggCDG compression methods on a set of CDG files.
+//!
+//! Usage: cdg_compression [--dictionary FILE] CDG...
+//!
+//! For the dictionary to be a fair test, train it on different files
+//! from the ones measured here.
+
+extern crate ogk;
+
+use std::env;
+use std::fs;
+use std::io::{self,Cursor,Read};
+use std::time::Instant;
+
+use ogk::cdg::{CdgHeader,Compression,OggCdgCoder};
+use ogk::ogg::BitstreamCoder;
+
+fn read_file(path: &str) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    try!(try!(fs::File::open(path)).read_to_end(&mut contents));
+    Ok(contents)
+}
+
+/// The header and data packets of one stream
+fn encode(cdg: &[u8], compression: Compression, dictionary: Option<&Vec<u8>>) -> io::Result<(Vec<Vec<u8>>, Vec<Vec<u8>>)> {
+    let mut coder = OggCdgCoder::new(Cursor::new(cdg));
+    try!(coder.set_compression(compression, dictionary.cloned()));
+    let mut packets = Vec::new();
+    while let Some(packet) = try!(coder.next_frame()) {
+        packets.push(packet.content);
+    }
+    Ok((coder.headers(), packets))
+}
+
+fn main() {
+    let mut args : Vec<String> = env::args().skip(1).collect();
+    let dictionary = match args.iter().position(|arg| arg == "--dictionary") {
+        Some(i) if i + 1 < args.len() => {
+            let file = args.remove(i + 1);
+            args.remove(i);
+            Some(read_file(&file).expect("Failed to read dictionary"))
+        },
+        Some(_) => panic!("--dictionary needs a file"),
+        None => None,
+    };
+    if args.is_empty() {
+        println!("Usage: cdg_compression [--dictionary FILE] CDG...");
+        return;
+    }
+    let cdgs : Vec<Vec<u8>> = args.iter().map(|file| read_file(file).expect("Failed to read CDG file")).collect();
+    let raw : usize = cdgs.iter().map(Vec::len).sum();
+
+    let mut methods = vec![
+        ("none", Compression::None, None),
+        ("lz4", Compression::LZ4, None),
+        ("zstd", Compression::Zstd, None),
+    ];
+    if dictionary.is_some() {
+        methods.push(("zstd+dict", Compression::Zstd, dictionary.as_ref()));
+    }
+
+    println!("{} files, {} bytes of CDG", cdgs.len(), raw);
+    println!("{:<10} {:>12} {:>7} {:>10} {:>10}", "method", "bytes", "ratio", "encode ms", "decode ms");
+    for (name, compression, dictionary) in methods {
+        let mut size = 0;
+        let mut streams = Vec::new();
+        let start = Instant::now();
+        for cdg in &cdgs {
+            let (headers, packets) = encode(cdg, compression, dictionary).expect("Failed to encode");
+            size += headers.iter().chain(&packets).map(Vec::len).sum::<usize>();
+            streams.push((headers, packets));
+        }
+        let encode_time = start.elapsed();
+
+        let start = Instant::now();
+        for &(ref headers, ref packets) in &streams {
+            let mut header = CdgHeader::from_bytes(&headers[0]).unwrap();
+            for aux in &headers[1..] {
+                header.process_header(aux);
+            }
+            for packet in packets {
+                header.decode_packet(packet).expect("Failed to decode");
+            }
+        }
+        let decode_time = start.elapsed();
+
+        let millis = |d: ::std::time::Duration| d.as_secs() as f64 * 1000. + d.subsec_nanos() as f64 / 1e6;
+        println!("{:<10} {:>12} {:>6.1}% {:>10.1} {:>10.1}", name, size, size as f64 * 100. / raw as f64,
+                 millis(encode_time), millis(decode_time));
+    }
+}
@@ -38,7 +38,7 @@ fn main() {
     use std::cell::Cell;
     let stream_no = Cell::new(0);
     
-    match ogk::ogg::OggDemux::new(io::stdin(), move |header| {
+    match ogk::ogg::OggDemux::new_tolerant(io::stdin(), move |header| {
         let s = stream_no.get();
         stream_no.set(s + 1);
         println!("{}@initial: {}", s,  hexdump(header));
@@ -48,6 +48,7 @@ fn main() {
             while !oggfile.is_eof() {
                 oggfile.pump_page().unwrap();
             }
+            println!("{:?}", oggfile.diagnostics());
         },
         Err(e) => {
             println!("{:?}", e);
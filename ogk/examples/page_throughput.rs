@@ -0,0 +1,138 @@
+//! Measures how fast OggPageSource and Mp3Stream get through their
+//! input.
+//!
+//! Usage: page_throughput [OGK [MP3]]
+//!
+//! Without files, a synthetic stream of about 64MB is used for each.
+//!
+//! The "from a reader" cases only use APIs that predate slice input,
+//! so they can be copied into an older checkout to compare against.
+
+extern crate ogk;
+
+use std::env;
+use std::fs;
+use std::io::{Cursor,Read};
+use std::time::{Duration,Instant};
+
+use ogk::mp3::Mp3Stream;
+use ogk::ogg::{OggPageSource,Page,PAGE_BOS};
+
+const ROUNDS: usize = 5;
+
+fn read_file(path: &str) -> Vec<u8> {
+    let mut contents = Vec::new();
+    fs::File::open(path).and_then(|mut f| f.read_to_end(&mut contents)).expect("Failed to read input");
+    contents
+}
+
+/// Pages of assorted sizes from a single stream
+fn synthetic_ogg() -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut sequence = 0;
+    while data.len() < 64 << 20 {
+        let mut page = Page::new(1, sequence);
+        if sequence == 0 {
+            page.flags = PAGE_BOS;
+        }
+        let len = 1000 + sequence as usize * 7919 % 60000;
+        page.segment_table = vec![255; len / 255];
+        page.segment_table.push((len % 255) as u8);
+        page.content = vec![sequence as u8; len];
+        page.write_to(&mut data).unwrap();
+        sequence += 1;
+    }
+    data
+}
+
+/// The same pages with damage: a flipped bit every 100KB, and a run
+/// of junk between pages every so often
+fn corrupt(ogg: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(ogg.len() * 2);
+    for (i, chunk) in ogg.chunks(100_000).enumerate() {
+        data.extend_from_slice(chunk);
+        if i % 4 == 0 {
+            data.extend((0..100_000u32).map(|j| (j * 2654435761 >> 24) as u8));
+        }
+        let last = data.len() - 1;
+        data[last - 1000] ^= 0x10;
+    }
+    data
+}
+
+/// MPEG-1 layer III frames at 128kbit/s and 44.1kHz
+fn synthetic_mp3() -> Vec<u8> {
+    let mut frame = vec![0; 417];
+    frame[0..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+    let mut data = Vec::new();
+    while data.len() < 64 << 20 {
+        data.extend_from_slice(&frame);
+    }
+    data
+}
+
+fn seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+/// Runs `f` a few times and reports the best rate
+fn measure<F: FnMut() -> usize>(name: &str, bytes: usize, mut f: F) {
+    let mut best = None;
+    let mut count = 0;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        count = f();
+        let elapsed = seconds(start.elapsed());
+        best = Some(best.map_or(elapsed, |best: f64| best.min(elapsed)));
+    }
+    let best = best.unwrap();
+    println!("{:<24} {:>8} items {:>9.1} MB/s", name, count, bytes as f64 / best / 1e6);
+}
+
+fn main() {
+    let args : Vec<String> = env::args().skip(1).collect();
+    let ogg = args.get(0).map_or_else(synthetic_ogg, |path| read_file(path));
+    let mp3 = args.get(1).map_or_else(synthetic_mp3, |path| read_file(path));
+    let damaged = corrupt(&ogg);
+
+    measure("ogg pages from a reader", ogg.len(), || {
+        let mut source = OggPageSource::new(Cursor::new(&ogg[..]));
+        let mut pages = 0;
+        while let Some(_) = source.next_page().unwrap() {
+            pages += 1;
+        }
+        pages
+    });
+    measure("ogg pages from memory", ogg.len(), || {
+        let mut source = OggPageSource::from_slice(&ogg);
+        let mut pages = 0;
+        while let Some(_) = source.next_page().unwrap() {
+            pages += 1;
+        }
+        pages
+    });
+    measure("damaged ogg from a reader", damaged.len(), || {
+        let mut source = OggPageSource::new(Cursor::new(&damaged[..]));
+        let mut pages = 0;
+        while let Some(_) = source.next_page().unwrap() {
+            pages += 1;
+        }
+        pages
+    });
+    measure("mp3 frames from a reader", mp3.len(), || {
+        let mut stream = Mp3Stream::new(Cursor::new(&mp3[..]));
+        let mut frames = 0;
+        while let Some(_) = stream.next_frame().unwrap() {
+            frames += 1;
+        }
+        frames
+    });
+    measure("mp3 frames from memory", mp3.len(), || {
+        let mut stream = Mp3Stream::from_slice(&mp3);
+        let mut frames = 0;
+        while let Some(_) = stream.next_frame().unwrap() {
+            frames += 1;
+        }
+        frames
+    });
+}
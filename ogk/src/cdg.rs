@@ -4,7 +4,10 @@ use std::borrow::Cow;
 use std::cmp::min;
 
 use lz4;
+use zstd;
 use ogg;
+use util::{self,DecodeQueue,HeaderError};
+use cdg_parser::{Command,SectorIter};
 
 pub struct OggCdgCoder<R> {
     reader: R,
@@ -12,17 +15,53 @@ pub struct OggCdgCoder<R> {
     // TODO: add keyframe support
     cur_frame: u64,
     last_keyframe: u64,
+    /// Sectors still to be added (if positive) or dropped (if
+    /// negative) at the start of the stream
+    offset: i64,
+    compression: Compression,
+    dictionary: Option<Vec<u8>>,
+    /// Set up with the dictionary, for zstd
+    compressor: Option<zstd::bulk::Compressor<'static>>,
 }
 
+/// The zstd level used for packets; they're small, so even the
+/// slowest levels keep up easily
+const ZSTD_LEVEL: i32 = 19;
+
 impl <R: Read> OggCdgCoder<R> {
     pub fn new(reader: R) -> Self {
+        Self::with_offset(reader, 0)
+    }
+
+    /// Shift the graphics `offset` sectors later relative to the
+    /// audio, by adding empty sectors at the start, or earlier, by
+    /// dropping them.
+    pub fn with_offset(reader: R, offset: i64) -> Self {
         OggCdgCoder{
             reader: reader,
             packetsize: 75,
             cur_frame: 0,
             last_keyframe: 0,
+            offset: offset,
+            compression: Compression::LZ4,
+            dictionary: None,
+            compressor: None,
         }
     }
+
+    /// Compress packets with `compression` instead of LZ4. A
+    /// dictionary is only allowed with zstd, and is written to the
+    /// stream as an extra header packet.
+    pub fn set_compression(&mut self, compression: Compression, dictionary: Option<Vec<u8>>) -> io::Result<()> {
+        self.compressor = match compression {
+            Compression::Zstd => Some(try!(zstd::bulk::Compressor::with_dictionary(ZSTD_LEVEL, dictionary.as_ref().map_or(&[][..], |d| &d[..])))),
+            _ if dictionary.is_some() => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Only zstd can use a dictionary")),
+            _ => None,
+        };
+        self.compression = compression;
+        self.dictionary = dictionary;
+        Ok(())
+    }
 }
 
 impl <R: Read> ogg::BitstreamCoder for OggCdgCoder<R> {
@@ -30,25 +69,46 @@ impl <R: Read> ogg::BitstreamCoder for OggCdgCoder<R> {
     //type Error = io::Error;
     
     fn headers(&self) -> Vec<Vec<u8>> {
-        let mut header = Vec::with_capacity(14);
-
-        header.extend_from_slice(b"OggCDG\0\0");
-        header.push(0); // Major
-        header.push(0); // Minor
-        header.push(Compression::LZ4 as u8); // LZ4
-        header.push(self.packetsize-1);
-        vec![header]
+        let header = CdgHeader{
+            version: (CDG_MAJOR_VERSION, 0),
+            compression: self.compression,
+            sectors_per_packet: self.packetsize as usize,
+            aux_headers: self.dictionary.is_some() as usize,
+            dictionary: None,
+        };
+        let mut headers = vec![header.to_bytes()];
+        if let Some(ref dictionary) = self.dictionary {
+            let mut packet = vec![PacketType::Dictionary.to_u8(), 0];
+            packet.extend_from_slice(dictionary);
+            headers.push(packet);
+        }
+        headers
     }
 
     fn next_frame(&mut self) -> io::Result<Option<ogg::Packet>> {
         let mut input = Vec::with_capacity(self.packetsize as usize * 96);
         let mut output = Vec::new();
-        let size = try!(self.reader.by_ref().take(self.packetsize as u64 * 96).read_to_end(&mut input));
+        if self.offset < 0 {
+            // Offsets too large to skip just drop the whole file
+            let skip = self.offset.checked_neg().map_or(!0, |sectors| sectors as u64).saturating_mul(96);
+            self.offset = 0;
+            if try!(io::copy(&mut self.reader.by_ref().take(skip), &mut io::sink())) % 96 != 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Incomplete sector read"));
+            }
+        }
+        if self.offset > 0 {
+            let padding = min(self.offset, self.packetsize as i64);
+            self.offset -= padding;
+            input.resize(padding as usize * 96, 0);
+        }
+        let wanted = self.packetsize as u64 * 96 - input.len() as u64;
+        let read = try!(self.reader.by_ref().take(wanted).read_to_end(&mut input));
         //let size = try!(self.reader.read(&mut input));
-        if size % 96 != 0 {
+        if read % 96 != 0 {
             return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Incomplete sector read"));
         }
 
+        let size = input.len();
         if size == 0 {
             return Ok(None);
         }
@@ -56,37 +116,106 @@ impl <R: Read> ogg::BitstreamCoder for OggCdgCoder<R> {
         output.push(0);
         output.push((size / 96) as u8);
 
-        let mut encoder = try!(lz4::EncoderBuilder::new()
-                               .level(9)
-                               .checksum(lz4::ContentChecksum::NoChecksum)
-                               .build(output));
-        
-        try!(encoder.write_all(&input));
-        let (output, result) = encoder.finish();
-        try!(result);
-        
+        let output = match self.compression {
+            Compression::None => {
+                output.extend_from_slice(&input);
+                output
+            },
+            Compression::LZ4 => {
+                let mut encoder = try!(lz4::EncoderBuilder::new()
+                                       .level(9)
+                                       .checksum(lz4::ContentChecksum::NoChecksum)
+                                       .build(output));
+                try!(encoder.write_all(&input));
+                let (output, result) = encoder.finish();
+                try!(result);
+                output
+            },
+            Compression::Zstd => {
+                let compressor = self.compressor.as_mut().expect("zstd compressor missing");
+                output.extend(try!(compressor.compress(&input)));
+                output
+            },
+        };
+
         self.cur_frame += size as u64 / 96;
 
         Ok(Some(ogg::Packet{
             content: output,
-            timestamp: self.cur_frame << 32 | self.last_keyframe,
+            timestamp: CdgGranule{sector: self.cur_frame, keyframe: self.last_keyframe}.to_u64(),
         }))
     }
 
     fn map_granule(&self, granule: u64) -> u64 {
-        (granule >> 32) * 1000_000 / 75
+        CdgGranule::from_u64(granule).micros()
+    }
+}
+
+/// An OggCDG granule position. See "Granule format" in the spec.
+#[derive(Copy,Clone,PartialEq,Eq,Debug,Default)]
+pub struct CdgGranule {
+    /// Sectors from the start of the stream to the end of the packet
+    pub sector: u64,
+    /// The sector of the last keyframe. Only the low 20 bits survive
+    /// encoding.
+    pub keyframe: u64,
+}
+
+impl CdgGranule {
+    pub fn from_u64(granule: u64) -> Self {
+        CdgGranule{
+            sector: granule >> 20,
+            keyframe: granule & 0xFFFFF,
+        }
+    }
+
+    pub fn to_u64(self) -> u64 {
+        self.sector << 20 | self.keyframe & 0xFFFFF
+    }
+
+    /// The time at the end of the packet, in µs
+    pub fn micros(self) -> u64 {
+        self.sector.saturating_mul(1000_000) / 75
     }
 }
 #[derive(Copy,Clone,PartialEq,Debug)]
 pub enum Compression {
     None,
     LZ4,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Compression::None),
+            1 => Some(Compression::LZ4),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// The name used on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::LZ4 => "lz4",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Compression::None, Compression::LZ4, Compression::Zstd].iter().cloned()
+            .find(|compression| compression.name() == name)
+    }
 }
 
 #[derive(Copy,Clone,PartialEq,Debug)]
 pub enum PacketType {
     Command,
     Keyframe,
+    /// A zstd dictionary; only found among the headers
+    Dictionary,
     Other(u8),
 }
 
@@ -95,6 +224,7 @@ impl PacketType {
         match v {
             0 => PacketType::Command,
             1 => PacketType::Keyframe,
+            2 => PacketType::Dictionary,
             _ => PacketType::Other(v),
         }
     }
@@ -103,68 +233,333 @@ impl PacketType {
         match self {
             PacketType::Command => 0,
             PacketType::Keyframe => 1,
+            PacketType::Dictionary => 2,
             PacketType::Other(v) => v,
         }
     }
 }
 
 
+/// The OggCDG major version this library reads and writes
+pub const CDG_MAJOR_VERSION: u8 = 0;
+/// The newest minor version this library knows the fields of
+pub const CDG_MINOR_VERSION: u8 = 1;
+
 pub struct CdgHeader {
+    /// The version the header was read with. `to_bytes` ignores this
+    /// and writes the oldest version that has the fields in use.
+    pub version: (u8, u8),
     pub compression: Compression,
     pub sectors_per_packet: usize,
+    /// Header packets after the first
+    pub aux_headers: usize,
+    /// From the dictionary header packet, if there is one
+    pub dictionary: Option<Vec<u8>>,
 }
 
 
 impl CdgHeader {
     pub fn new() -> Self {
         CdgHeader{
+            version: (CDG_MAJOR_VERSION, 0),
             compression: Compression::LZ4,
             sectors_per_packet: 75, // 1s at a time
+            aux_headers: 0,
+            dictionary: None,
         }
     }
 
+    /// The first header packet. Minor version 0 is written unless
+    /// there are more header packets to count.
     pub fn to_bytes(&self) -> Vec<u8> {
         let spp = min(self.sectors_per_packet - 1, 255) as u8;
-        vec![
+        let mut header = vec![
             b'O', b'g', b'g', b'C', b'D', b'G', 0, 0,
-            0, 0, self.compression as u8, spp,
-        ]
+            CDG_MAJOR_VERSION, 0, self.compression as u8, spp,
+        ];
+        if self.aux_headers > 0 {
+            header[9] = 1;
+            header.push(self.aux_headers as u8);
+        }
+        header
     }
 
-    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
-        if buf[0..9] != *b"OggCDG\0\0\0" {
-            return None;
+    /// Parse the first header packet. Headers with a higher minor
+    /// version are accepted, and any fields we don't know are ignored.
+    pub fn parse(buf: &[u8]) -> Result<Self, HeaderError> {
+        let version = try!(util::check_header_version(buf, b"OggCDG\0\0", CDG_MAJOR_VERSION));
+        if buf.len() < 12 {
+            return Err(HeaderError::Malformed("header is too short"));
         }
-        
-        let compression = match buf[10] {
-            0 => Compression::None,
-            1 => Compression::LZ4,
-            _ => return None,
+        let compression = match Compression::from_u8(buf[10]) {
+            Some(compression) => compression,
+            None => return Err(HeaderError::Malformed("unknown compression method")),
         };
         let spp = buf[11] as usize + 1;
-        Some(CdgHeader{
+        // The header count arrived in minor version 1
+        let aux_headers = match (version.1, buf.get(12)) {
+            (0, _) => 0,
+            (_, Some(&count)) => count as usize,
+            (_, None) => return Err(HeaderError::Malformed("header is too short")),
+        };
+        Ok(CdgHeader{
+            version: version,
             compression: compression,
             sectors_per_packet: spp,
+            aux_headers: aux_headers,
+            dictionary: None,
         })
     }
 
+    /// As `parse`, for when the reason doesn't matter
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        Self::parse(buf).ok()
+    }
+
+    /// The number of header packets, including the first
+    pub fn num_headers(&self) -> usize {
+        1 + self.aux_headers
+    }
+
+    /// Take in one of the header packets after the first. Returns
+    /// false if it isn't understood.
+    pub fn process_header(&mut self, buf: &[u8]) -> bool {
+        if buf.len() < 2 || PacketType::from_u8(buf[0]) != PacketType::Dictionary {
+            return false;
+        }
+        self.dictionary = Some(buf[2..].to_owned());
+        true
+    }
+
     fn decompress_packet<'a>(&self, buf: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
         match self.compression {
             Compression::None => Ok(Cow::Borrowed(buf)),
             Compression::LZ4 => {
-                use std::io::{Cursor, copy};
-                let mut res = Vec::new();
-                try!(copy(
-                    &mut try!(lz4::Decoder::new(Cursor::new(buf))),
-                    &mut res));
-                Ok(Cow::Owned(res))
-            }
-        }        
+                use std::io::Cursor;
+                read_limited(try!(lz4::Decoder::new(Cursor::new(buf)))).map(Cow::Owned)
+            },
+            Compression::Zstd => {
+                let dictionary = self.dictionary.as_ref().map_or(&[][..], |d| &d[..]);
+                read_limited(try!(zstd::stream::Decoder::with_dictionary(buf, dictionary))).map(Cow::Owned)
+            },
+        }
     }
 
     /// Decode a packet from the middle of the stream
     pub fn decode_packet<'a>(&self, buf: &'a [u8]) -> Option<(PacketType, Cow<'a, [u8]>)> {
+        if buf.len() < 2 {
+            return None;
+        }
         let typ = PacketType::from_u8(buf[0]);
         self.decompress_packet(&buf[2..]).ok().map(|pkt| (typ, pkt))
     }
 }
+
+/// Decompressed packets larger than this are rejected. A full packet
+/// of 256 sectors is only 24KiB, so this leaves plenty of room.
+const MAX_PACKET_SIZE: u64 = 1 << 20;
+
+/// Read a decompressed packet, failing if it's implausibly large
+fn read_limited<R: Read>(reader: R) -> io::Result<Vec<u8>> {
+    let mut res = Vec::new();
+    try!(reader.take(MAX_PACKET_SIZE + 1).read_to_end(&mut res));
+    if res.len() as u64 > MAX_PACKET_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Decompressed packet is too large"));
+    }
+    Ok(res)
+}
+
+/// Train a zstd dictionary for OggCDG packets on some CDG files. Each
+/// is cut into packet-sized samples, as the coder would.
+pub fn train_dictionary(cdgs: &[Vec<u8>], max_size: usize) -> io::Result<Vec<u8>> {
+    let samples : Vec<&[u8]> = cdgs.iter()
+        .flat_map(|cdg| cdg.chunks(CdgHeader::new().sectors_per_packet * 96))
+        .collect();
+    zstd::dict::from_samples(&samples, max_size)
+}
+
+/// A drawing command and the sector it's in, counting from 0
+#[derive(Debug)]
+pub struct TimedCommand {
+    pub sector: u64,
+    pub command: Command,
+}
+
+/// Decodes an OggCDG stream into drawing commands. Keyframes are
+/// only used to keep track of the granule position.
+pub struct OggCdgDecoder {
+    header: CdgHeader,
+    /// How far the stream has been decoded
+    granule: CdgGranule,
+    output: DecodeQueue<TimedCommand>,
+}
+
+impl OggCdgDecoder {
+    /// Returns None if `header` isn't a usable OggCDG header
+    pub fn new(header: &[u8]) -> Option<Self> {
+        CdgHeader::from_bytes(header).map(|header| OggCdgDecoder{
+            header: header,
+            granule: CdgGranule::default(),
+            output: DecodeQueue::new(),
+        })
+    }
+
+    /// Where the decoded commands go
+    pub fn output(&self) -> DecodeQueue<TimedCommand> {
+        self.output.clone()
+    }
+}
+
+impl ogg::BitstreamDecoder for OggCdgDecoder {
+    fn map_granule(&self, granule: u64) -> u64 {
+        CdgGranule::from_u64(granule).micros()
+    }
+
+    fn num_headers(&self) -> usize { self.header.num_headers() }
+
+    fn process_header(&mut self, header: &[u8]) {
+        self.header.process_header(header);
+    }
+
+    fn process_packet(&mut self, packet: &[u8], _: u64) -> u64 {
+        if packet.len() < 2 {
+            return self.granule.to_u64();
+        }
+        match self.header.decode_packet(packet) {
+            Some((PacketType::Command, sectors)) => {
+                let start = self.granule.sector;
+                for (i, sector) in sectors.chunks(96).filter(|sector| sector.len() == 96).enumerate() {
+                    for command in SectorIter::new(sector) {
+                        self.output.push(TimedCommand{
+                            sector: start + i as u64,
+                            command: command,
+                        });
+                    }
+                }
+                // Trust the declared length over the data, so that a
+                // damaged packet doesn't throw off the timing
+                self.granule.sector = start + packet[1] as u64;
+            },
+            Some((PacketType::Keyframe, _)) => self.granule.keyframe = self.granule.sector,
+            _ => (),
+        }
+        self.granule.to_u64()
+    }
+
+    fn page_done(&mut self, granule: u64) {
+        // Skip ahead over lost pages
+        if granule != !0 && CdgGranule::from_u64(granule).sector > self.granule.sector {
+            self.granule = CdgGranule::from_u64(granule);
+        }
+    }
+
+    fn notice_gap(&mut self) {}
+
+    fn finish(&mut self) {
+        self.output.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use ogg::BitstreamCoder;
+    use super::*;
+
+    /// The sector counts and granules of each packet
+    fn packets(coder: &mut OggCdgCoder<Cursor<Vec<u8>>>) -> Vec<(u8, u64)> {
+        let mut packets = Vec::new();
+        while let Some(packet) = coder.next_frame().unwrap() {
+            packets.push((packet.content[1], packet.timestamp >> 20));
+        }
+        packets
+    }
+
+    #[test]
+    fn offset_pads_or_trims_leading_sectors() {
+        let input = vec![0; 100 * 96];
+        let mut later = OggCdgCoder::with_offset(Cursor::new(input.clone()), 80);
+        assert_eq!(packets(&mut later), vec![(75, 75), (75, 150), (30, 180)]);
+        let mut earlier = OggCdgCoder::with_offset(Cursor::new(input), -30);
+        assert_eq!(packets(&mut earlier), vec![(70, 70)]);
+        let mut everything = OggCdgCoder::with_offset(Cursor::new(vec![0; 100 * 96]), i64::min_value());
+        assert_eq!(packets(&mut everything), vec![]);
+    }
+
+    #[test]
+    fn decoder_times_commands_like_the_coder() {
+        use ogg::{BitstreamDecoder,OggDemux,OgkMux};
+        // A memory preset in sector 80, in the second packet
+        let mut input = vec![0; 100 * 96];
+        input[80 * 96] = 9;
+        input[80 * 96 + 1] = 1;
+        let mut mux = OgkMux::new();
+        mux.add_stream(Box::new(OggCdgCoder::new(Cursor::new(input))));
+        let mut file = Vec::new();
+        mux.write_to(&mut file).unwrap();
+
+        let decoder = OggCdgDecoder::new(&CdgHeader::new().to_bytes()).unwrap();
+        let output = decoder.output();
+        let decoder = ::std::cell::RefCell::new(Some(Box::new(decoder) as Box<BitstreamDecoder>));
+        let mut demux = OggDemux::new(Cursor::new(file), move |_| decoder.borrow_mut().take().map(|decoder| (decoder, ()))).unwrap();
+        while !demux.is_eof() {
+            demux.pump_page().unwrap();
+        }
+        let command = output.pop().unwrap();
+        assert_eq!(command.sector, 80);
+        match command.command {
+            Command::MemoryPreset{..} => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(output.pop().is_none());
+        assert!(output.is_finished());
+        assert_eq!(demux.high_water_mark(), CdgGranule{sector: 100, keyframe: 0}.micros());
+    }
+
+    #[test]
+    fn zstd_dictionary_travels_in_the_headers() {
+        let mut input = vec![0; 100 * 96];
+        input[80 * 96] = 9;
+        input[80 * 96 + 1] = 1;
+        let dictionary = train_dictionary(&vec![input.clone(); 20], 1024).unwrap();
+        let mut coder = OggCdgCoder::new(Cursor::new(input.clone()));
+        assert!(coder.set_compression(Compression::LZ4, Some(dictionary.clone())).is_err());
+        coder.set_compression(Compression::Zstd, Some(dictionary.clone())).unwrap();
+
+        let headers = coder.headers();
+        assert_eq!(headers.len(), 2);
+        let mut header = CdgHeader::from_bytes(&headers[0]).unwrap();
+        assert_eq!(header.compression, Compression::Zstd);
+        assert_eq!(header.num_headers(), 2);
+        assert!(header.process_header(&headers[1]));
+        assert_eq!(header.dictionary, Some(dictionary));
+
+        let mut output = Vec::new();
+        while let Some(packet) = coder.next_frame().unwrap() {
+            let (typ, sectors) = header.decode_packet(&packet.content).unwrap();
+            assert_eq!(typ, PacketType::Command);
+            output.extend_from_slice(&sectors);
+        }
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn reads_future_minor_versions_only() {
+        let mut header = CdgHeader{aux_headers: 1, ..CdgHeader::new()}.to_bytes();
+        assert_eq!(CdgHeader::parse(&header).unwrap().version, (0, 1));
+        // A later minor version with a field we don't know about
+        header[9] = CDG_MINOR_VERSION + 1;
+        header.push(0xAA);
+        let parsed = CdgHeader::parse(&header).unwrap();
+        assert_eq!(parsed.version, (0, 2));
+        assert_eq!(parsed.num_headers(), 2);
+        // Minor version 1 needs the header count
+        header[9] = 1;
+        assert_eq!(CdgHeader::parse(&header[..12]).err(), Some(HeaderError::Malformed("header is too short")));
+
+        header[8] = 1;
+        assert_eq!(CdgHeader::parse(&header).err(), Some(HeaderError::UnsupportedVersion{found: (1, 1), supported: 0}));
+        assert!(OggCdgDecoder::new(&header).is_none());
+        assert_eq!(CdgHeader::parse(b"OggMP3\0\0\0\0").err(), Some(HeaderError::WrongMagic));
+    }
+}
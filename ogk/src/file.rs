@@ -0,0 +1,426 @@
+//! A simple way to read an OGK file, for tools and tests that don't
+//! need to drive the demuxer themselves.
+
+use std::cell::RefCell;
+use std::cmp::max;
+use std::fs;
+use std::io::{self,Read,Seek,SeekFrom};
+use std::path::Path;
+use std::rc::Rc;
+use std::vec;
+
+use ogg::{BitstreamDecoder,DemuxEvent,OggDemux,OggPageSource,StreamError};
+use cdg::{CdgGranule,OggCdgDecoder,TimedCommand};
+use mp3::{self,AudioRole,FrameHeader,Mp3Granule,OggMp3Decoder,TimedFrame};
+use registry::Registry;
+use util::DecodeQueue;
+
+/// How much of the end of the file to look at for the last pages
+const TAIL_SIZE: u64 = 256 * 1024;
+
+/// A stream's codec, and what its headers say about it
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub enum StreamKind {
+    Cdg,
+    Mp3 {
+        /// A header that every frame in the stream is compatible with
+        representative: FrameHeader,
+        clock: Mp3Granule,
+        /// Samples of padding at the end of the stream
+        padding: u32,
+        role: AudioRole,
+    },
+}
+
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub struct StreamInfo {
+    pub serial: u32,
+    /// The link of a chained file the stream is in, counting from 0
+    pub link: u32,
+    /// When the stream's link starts, in µs
+    pub start: u64,
+    pub kind: StreamKind,
+}
+
+impl StreamInfo {
+    /// The time of one of the stream's granule positions, in µs from
+    /// the start of the file
+    pub fn granule_time(&self, granule: u64) -> u64 {
+        self.start.saturating_add(match self.kind {
+            StreamKind::Cdg => CdgGranule::from_u64(granule).micros(),
+            StreamKind::Mp3{clock, ..} => clock.micros(granule),
+        })
+    }
+}
+
+#[derive(Clone)]
+enum Output {
+    Cdg(DecodeQueue<TimedCommand>),
+    Mp3 {
+        frames: DecodeQueue<TimedFrame>,
+        /// The auxiliary header packets read so far
+        headers: Rc<RefCell<Vec<Vec<u8>>>>,
+        aux_headers: usize,
+        has_tag: bool,
+    },
+}
+
+/// What the demuxer keeps for each stream
+struct Stream {
+    kind: StreamKind,
+    output: Output,
+}
+
+/// Passes everything on to `inner`, keeping a copy of the header
+/// packets
+struct HeaderTap {
+    inner: Box<BitstreamDecoder>,
+    headers: Rc<RefCell<Vec<Vec<u8>>>>,
+}
+
+impl BitstreamDecoder for HeaderTap {
+    fn map_granule(&self, granule: u64) -> u64 { self.inner.map_granule(granule) }
+    fn num_headers(&self) -> usize { self.inner.num_headers() }
+
+    fn process_header(&mut self, header: &[u8]) {
+        self.headers.borrow_mut().push(header.to_owned());
+        self.inner.process_header(header);
+    }
+
+    fn process_packet(&mut self, packet: &[u8], last_granule: u64) -> u64 {
+        self.inner.process_packet(packet, last_granule)
+    }
+
+    fn page_done(&mut self, granule: u64) { self.inner.page_done(granule) }
+    fn notice_gap(&mut self) { self.inner.notice_gap() }
+    fn finish(&mut self) { self.inner.finish() }
+}
+
+fn start_cdg(header: &[u8]) -> Option<(Box<BitstreamDecoder>, Stream)> {
+    OggCdgDecoder::new(header).map(|decoder| {
+        let stream = Stream{
+            kind: StreamKind::Cdg,
+            output: Output::Cdg(decoder.output()),
+        };
+        (Box::new(decoder) as Box<BitstreamDecoder>, stream)
+    })
+}
+
+fn start_mp3(header: &[u8]) -> Option<(Box<BitstreamDecoder>, Stream)> {
+    OggMp3Decoder::new(header).map(|decoder| {
+        let headers = Rc::new(RefCell::new(Vec::new()));
+        let stream = Stream{
+            kind: StreamKind::Mp3{
+                representative: decoder.representative(),
+                clock: decoder.clock(),
+                padding: decoder.padding(),
+                role: decoder.role(),
+            },
+            output: Output::Mp3{
+                frames: decoder.output(),
+                headers: headers.clone(),
+                aux_headers: decoder.num_headers() - 1,
+                has_tag: header[10] & 1 != 0,
+            },
+        };
+        (Box::new(HeaderTap{inner: Box::new(decoder), headers: headers}) as Box<BitstreamDecoder>, stream)
+    })
+}
+
+fn registry() -> Registry<Stream> {
+    let mut registry = Registry::builtin();
+    registry.set_decoder("OggCDG", Box::new(start_cdg));
+    registry.set_decoder("OggMP3", Box::new(start_mp3));
+    registry
+}
+
+/// An OGK file being read. Streams of codecs the library can't decode
+/// are left out.
+///
+/// Reading one stream decodes the others as well; their output is
+/// kept until it is read, so read every stream you care about.
+pub struct OgkFile<R> {
+    demux: OggDemux<R, Stream>,
+    streams: Vec<(StreamInfo, Output)>,
+    /// Set once reading has failed, so that iterators stop there
+    failed: bool,
+}
+
+impl OgkFile<io::BufReader<fs::File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StreamError> {
+        let file = try!(fs::File::open(path));
+        Self::from_reader(io::BufReader::new(file))
+    }
+}
+
+impl <R: Read> OgkFile<R> {
+    /// Damaged pages are skipped, as by a tolerant `OggDemux`.
+    pub fn from_reader(reader: R) -> Result<Self, StreamError> {
+        let demux = try!(OggDemux::with_registry_tolerant(reader, registry()));
+        let mut file = OgkFile{
+            demux: demux,
+            streams: Vec::new(),
+            failed: false,
+        };
+        file.collect_streams(0);
+        Ok(file)
+    }
+
+    /// Note the streams of the link that just started
+    fn collect_streams(&mut self, start: u64) {
+        let link = self.demux.link();
+        let mut streams : Vec<_> = self.demux.streams().map(|(serial, stream)| (StreamInfo{
+            serial: serial,
+            link: link,
+            start: start,
+            kind: stream.kind,
+        }, stream.output.clone())).collect();
+        streams.sort_by_key(|&(ref info, _)| info.serial);
+        self.streams.extend(streams);
+    }
+
+    fn pump(&mut self) -> Result<(), StreamError> {
+        if let Err(e) = self.demux.pump_page() {
+            self.failed = true;
+            return Err(e);
+        }
+        while let Some(event) = self.demux.next_event() {
+            match event {
+                DemuxEvent::NewLink{start_time, ..} => self.collect_streams(start_time),
+            }
+        }
+        Ok(())
+    }
+
+    fn at_end(&self) -> bool {
+        self.failed || self.demux.is_eof()
+    }
+
+    /// The streams found so far, indexed as for `commands` and
+    /// `frames`. Those of later links in a chained file are added as
+    /// reading reaches them.
+    pub fn streams(&self) -> Vec<StreamInfo> {
+        self.streams.iter().map(|&(info, _)| info).collect()
+    }
+
+    /// The drawing commands of a CDG stream. None if there's no such
+    /// stream.
+    pub fn commands(&mut self, stream: usize) -> Option<Commands<R>> {
+        let queue = match self.streams.get(stream) {
+            Some(&(_, Output::Cdg(ref queue))) => queue.clone(),
+            _ => return None,
+        };
+        Some(StreamIter{file: self, queue: queue})
+    }
+
+    /// The frames of an MP3 stream. None if there's no such stream.
+    pub fn frames(&mut self, stream: usize) -> Option<Frames<R>> {
+        let queue = match self.streams.get(stream) {
+            Some(&(_, Output::Mp3{frames: ref queue, ..})) => queue.clone(),
+            _ => return None,
+        };
+        Some(StreamIter{file: self, queue: queue})
+    }
+
+    /// What's known about a stream, as (name, value) pairs: its
+    /// "role" and, if the source file was tagged, "title", "artist"
+    /// and "album".
+    pub fn metadata(&mut self, stream: usize) -> vec::IntoIter<(&'static str, String)> {
+        let mut metadata = Vec::new();
+        let (info, output) = match self.streams.get(stream) {
+            Some(&(info, ref output)) => (info, output.clone()),
+            None => return metadata.into_iter(),
+        };
+        if let StreamKind::Mp3{role, ..} = info.kind {
+            metadata.push(("role", role.name().to_owned()));
+        }
+        if let Output::Mp3{headers, aux_headers, has_tag: true, ..} = output {
+            // The tag may be on a later page than the stream header
+            while headers.borrow().len() < aux_headers && !self.at_end() {
+                let _ = self.pump();
+            }
+            if let Some(tag) = headers.borrow().first() {
+                for &(name, frame_id) in &[("title", b"TIT2"), ("artist", b"TPE1"), ("album", b"TALB")] {
+                    if let Some(text) = mp3::id3v2_text(tag, frame_id) {
+                        metadata.push((name, text));
+                    }
+                }
+            }
+        }
+        metadata.into_iter()
+    }
+}
+
+impl <R: Read + Seek> OgkFile<R> {
+    /// Start again from `time`, in µs from the start of the file: each
+    /// stream carries on with its first item at or after that time.
+    /// Drawing commands before `time` are skipped too, so to show the
+    /// screen as it is at `time`, read them from the start instead.
+    /// Seeking back to an earlier link of a chained file reads it again
+    /// from the beginning; within a link, the demuxer seeks directly.
+    pub fn seek(&mut self, time: u64) -> Result<(), StreamError> {
+        self.failed = false;
+        let link = self.demux.link();
+        let link_start = self.streams.iter().rev().find(|&&(ref info, _)| info.link == link).map_or(0, |&(ref info, _)| info.start);
+        if time < link_start {
+            try!(self.demux.get_mut().seek(SeekFrom::Start(0)));
+            self.streams.clear();
+            try!(self.demux.restart());
+            self.collect_streams(0);
+        }
+        try!(self.demux.seek(time));
+        // The demuxer starts each stream's decoder again
+        let link = self.demux.link();
+        let restarted : Vec<_> = self.demux.streams().map(|(serial, stream)| (serial, stream.output.clone())).collect();
+        for (serial, output) in restarted {
+            if let Some(&mut (_, ref mut old)) = self.streams.iter_mut().find(|&&mut (ref info, _)| info.link == link && info.serial == serial) {
+                *old = output;
+            }
+        }
+        loop {
+            self.skip_until(time);
+            let reached = self.streams.iter().all(|&(_, ref output)| match *output {
+                Output::Cdg(ref queue) => !queue.is_empty() || queue.is_finished(),
+                Output::Mp3{frames: ref queue, ..} => !queue.is_empty() || queue.is_finished(),
+            });
+            if reached || self.at_end() {
+                return Ok(());
+            }
+            try!(self.pump());
+        }
+    }
+
+    /// Drop the output from before `time`
+    fn skip_until(&mut self, time: u64) {
+        for &(ref info, ref output) in &self.streams {
+            match *output {
+                Output::Cdg(ref queue) => {
+                    while let Some(command) = queue.pop() {
+                        if info.start.saturating_add((CdgGranule{sector: command.sector, keyframe: 0}).micros()) >= time {
+                            queue.unpop(command);
+                            break;
+                        }
+                    }
+                },
+                Output::Mp3{frames: ref queue, ..} => {
+                    while let Some(frame) = queue.pop() {
+                        if info.granule_time(frame.sample) >= time {
+                            queue.unpop(frame);
+                            break;
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    /// How long the file plays for, in µs, going by its last pages.
+    /// None if they belong to streams that haven't been read yet, as
+    /// at the start of a chained file.
+    pub fn duration(&mut self) -> Result<Option<u64>, StreamError> {
+        let tail = {
+            // Put the reader back where the demuxer left it
+            let reader = self.demux.get_mut();
+            let position = try!(reader.seek(SeekFrom::Current(0)));
+            let end = try!(reader.seek(SeekFrom::End(0)));
+            try!(reader.seek(SeekFrom::Start(end.saturating_sub(TAIL_SIZE))));
+            let mut tail = Vec::new();
+            let read = reader.by_ref().take(TAIL_SIZE).read_to_end(&mut tail);
+            try!(reader.seek(SeekFrom::Start(position)));
+            try!(read);
+            tail
+        };
+        let mut pages = OggPageSource::from_slice(&tail);
+        let mut duration = None;
+        while let Ok(Some(page)) = pages.next_page() {
+            if page.granule_position == !0 {
+                continue;
+            }
+            // Serial numbers may be reused by a later link
+            if let Some(&(ref info, _)) = self.streams.iter().rev().find(|&&(ref info, _)| info.serial == page.stream_serial) {
+                duration = max(duration, Some(info.granule_time(page.granule_position)));
+            }
+        }
+        Ok(duration)
+    }
+}
+
+/// The output of one stream, read as it's needed
+pub struct StreamIter<'a, R: 'a, T> {
+    file: &'a mut OgkFile<R>,
+    queue: DecodeQueue<T>,
+}
+
+pub type Commands<'a, R> = StreamIter<'a, R, TimedCommand>;
+pub type Frames<'a, R> = StreamIter<'a, R, TimedFrame>;
+
+impl <'a, R: Read, T> Iterator for StreamIter<'a, R, T> {
+    type Item = Result<T, StreamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.queue.pop() {
+                return Some(Ok(item));
+            }
+            if self.queue.is_finished() || self.file.at_end() {
+                return None;
+            }
+            if let Err(e) = self.file.pump() {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use ogg::OgkMux;
+    use cdg::OggCdgCoder;
+    use mp3::OggMP3Coder;
+    use super::*;
+
+    /// Three seconds of graphics with a command in sector 100, and 40
+    /// frames of 22050Hz MP3
+    fn song() -> Vec<u8> {
+        let mut cdg = vec![0; 225 * 96];
+        cdg[100 * 96] = 9;
+        cdg[100 * 96 + 1] = 1;
+        let mut mp3 = Vec::new();
+        for _ in 0..40 {
+            let mut frame = vec![0; 208];
+            frame[0..4].copy_from_slice(&[0xFF, 0xF3, 0x80, 0x00]);
+            mp3.extend(frame);
+        }
+        let mut mux = OgkMux::new();
+        mux.add_stream(Box::new(OggMP3Coder::new(Cursor::new(mp3)).unwrap()));
+        mux.add_stream(Box::new(OggCdgCoder::new(Cursor::new(cdg))));
+        let mut file = Vec::new();
+        mux.write_to(&mut file).unwrap();
+        file
+    }
+
+    #[test]
+    fn reads_streams_and_seeks() {
+        let mut file = OgkFile::from_reader(Cursor::new(song())).unwrap();
+        let streams = file.streams();
+        assert_eq!(streams.len(), 2);
+        let cdg = streams.iter().position(|info| info.kind == StreamKind::Cdg).unwrap();
+        let mp3 = 1 - cdg;
+        assert_eq!(file.metadata(mp3).collect::<Vec<_>>(), vec![("role", "main".to_owned())]);
+        assert_eq!(file.duration().unwrap(), Some(3000_000));
+
+        let sectors : Vec<u64> = file.commands(cdg).unwrap().map(|command| command.unwrap().sector).collect();
+        assert_eq!(sectors, vec![100]);
+        assert_eq!(file.frames(mp3).unwrap().count(), 40);
+        assert!(file.frames(cdg).is_none());
+
+        // 1s is between frames 38 and 39
+        file.seek(1000_000).unwrap();
+        let samples : Vec<u64> = file.frames(mp3).unwrap().map(|frame| frame.unwrap().sample).collect();
+        assert_eq!(samples, vec![39 * 576]);
+        assert_eq!(file.commands(cdg).unwrap().count(), 1);
+        file.seek(2000_000).unwrap();
+        assert_eq!(file.commands(cdg).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,117 @@
+//! OggIDX: a logical stream that records where each stream's pages
+//! are in its link, so a demuxer can seek without searching for them.
+
+use byteorder::{ByteOrder,LittleEndian};
+
+use util::{self,HeaderError};
+
+pub const INDEX_MAGIC: &[u8] = b"OggIDX\0\0";
+pub const INDEX_MAJOR_VERSION: u8 = 0;
+pub const INDEX_MINOR_VERSION: u8 = 0;
+
+/// The only header packet of an OggIDX stream
+pub fn header() -> Vec<u8> {
+    let mut header = INDEX_MAGIC.to_vec();
+    header.push(INDEX_MAJOR_VERSION);
+    header.push(INDEX_MINOR_VERSION);
+    header
+}
+
+/// Check an OggIDX header, returning its version
+pub fn parse_header(header: &[u8]) -> Result<(u8, u8), HeaderError> {
+    util::check_header_version(header, INDEX_MAGIC, INDEX_MAJOR_VERSION)
+}
+
+/// A page on which at least one packet ends
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub struct IndexEntry {
+    /// Where the page starts, in bytes from the start of the link
+    pub offset: u64,
+    pub granule: u64,
+}
+
+/// The pages of each stream in a link, in the order they were written
+#[derive(Clone,PartialEq,Eq,Debug,Default)]
+pub struct SeekIndex {
+    streams: Vec<(u32, Vec<IndexEntry>)>,
+}
+
+impl SeekIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the next page of a stream
+    pub fn add(&mut self, serial: u32, entry: IndexEntry) {
+        if let Some(&mut (_, ref mut entries)) = self.streams.iter_mut().find(|&&mut (s, _)| s == serial) {
+            entries.push(entry);
+            return;
+        }
+        self.streams.push((serial, vec![entry]));
+    }
+
+    /// The indexed streams, in the order they were first added
+    pub fn serials(&self) -> Vec<u32> {
+        self.streams.iter().map(|&(serial, _)| serial).collect()
+    }
+
+    /// The pages of a stream. Empty if it isn't indexed.
+    pub fn entries(&self, serial: u32) -> &[IndexEntry] {
+        self.streams.iter().find(|&&(s, _)| s == serial).map_or(&[], |&(_, ref entries)| &entries[..])
+    }
+
+    /// The last page of a stream whose granule position is at or
+    /// before `time`, in µs, going by `map_granule`. Every packet
+    /// that ends after `time` is on a later page.
+    pub fn entry_before<F: Fn(u64) -> u64>(&self, serial: u32, time: u64, map_granule: F) -> Option<IndexEntry> {
+        self.entries(serial).iter().take_while(|entry| map_granule(entry.granule) <= time).last().cloned()
+    }
+
+    /// The index packet
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0; 4];
+        LittleEndian::write_u32(&mut buf, self.streams.len() as u32);
+        for &(serial, ref entries) in &self.streams {
+            let mut stream = [0; 8];
+            LittleEndian::write_u32(&mut stream[0..4], serial);
+            LittleEndian::write_u32(&mut stream[4..8], entries.len() as u32);
+            buf.extend_from_slice(&stream);
+            for entry in entries {
+                let mut raw = [0; 16];
+                LittleEndian::write_u64(&mut raw[0..8], entry.offset);
+                LittleEndian::write_u64(&mut raw[8..16], entry.granule);
+                buf.extend_from_slice(&raw);
+            }
+        }
+        buf
+    }
+
+    /// Read an index packet. None if it's cut short.
+    pub fn parse(mut buf: &[u8]) -> Option<Self> {
+        if buf.len() < 4 {
+            return None;
+        }
+        let count = LittleEndian::read_u32(buf);
+        buf = &buf[4..];
+        let mut index = SeekIndex::new();
+        for _ in 0..count {
+            if buf.len() < 8 {
+                return None;
+            }
+            let serial = LittleEndian::read_u32(&buf[0..4]);
+            let len = LittleEndian::read_u32(&buf[4..8]) as usize;
+            buf = &buf[8..];
+            // Check before allocating, in case the count is damaged
+            if buf.len() / 16 < len {
+                return None;
+            }
+            let entries = buf[..len * 16].chunks(16).map(|raw| IndexEntry{
+                offset: LittleEndian::read_u64(&raw[0..8]),
+                granule: LittleEndian::read_u64(&raw[8..16]),
+            }).collect();
+            buf = &buf[len * 16..];
+            index.streams.push((serial, entries));
+        }
+        Some(index)
+    }
+}
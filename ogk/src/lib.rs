@@ -3,13 +3,18 @@
 #[macro_use] extern crate lazy_static;
 extern crate byteorder;
 extern crate lz4;
+extern crate memchr;
 extern crate cdg as cdg_parser;
 extern crate rand;
+extern crate zstd;
 
 pub mod mp3;
 pub mod util;
 pub mod ogg;
 pub mod cdg;
+pub mod registry;
+pub mod index;
+pub mod file;
 
 
 #[cfg(test)]
@@ -4,64 +4,268 @@ use std::io;
 //use std::collections::VecDeque;
 
 use ogg;
-use util;
+use util::{self,DecodeQueue,HeaderError,Input,ReadInput,SliceInput};
 
-pub struct Mp3Stream<R> {
-    reader: R,
-    buffer: util::ShiftBuffer,
+/// The largest possible mp3 frame is 2881 bytes.
+const MAX_FRAME_SIZE : usize = 2881;
+
+/// Statistics about the condition of an MP3 stream, for reporting
+/// damaged input
+#[derive(Copy,Clone,PartialEq,Eq,Debug,Default)]
+pub struct Mp3StreamStats {
+    /// Number of frames produced
+    pub frames: u64,
+    /// Number of bytes of unrecognized data that were skipped. This
+    /// does not include ID3 tags.
+    pub skipped_bytes: u64,
+    /// Number of times sync was lost and then regained
+    pub resyncs: u64,
+    /// Number of frames that failed their CRC check. These frames
+    /// are still produced, so that the stream keeps its timing.
+    pub crc_errors: u64,
+}
+
+pub struct Mp3Stream<I> {
+    input: I,
+    /// The number of following frame headers that must agree with a
+    /// candidate frame before we believe it's real
+    confirmations: usize,
+    /// The header of the last frame, if the next frame is expected to
+    /// immediately follow it
+    synced: Option<FrameHeader>,
+    /// Set when data has been skipped since the first frame
+    lost_sync: bool,
+    at_start: bool,
+    /// The ID3v2 tag from the start of the stream, if any
+    id3v2: Option<Vec<u8>>,
+    stats: Mp3StreamStats,
 }
 
-impl <R: Read> Mp3Stream<R> {
+impl <R: Read> Mp3Stream<ReadInput<R>> {
     pub fn new(reader: R) -> Self {
+        Self::with_confirmations(reader, 1)
+    }
+
+    /// Create a stream that, whenever it is looking for sync,
+    /// requires `confirmations` consecutive valid frame headers
+    /// following a candidate frame before accepting it. More
+    /// confirmations make false syncs inside album art or junk less
+    /// likely, but may drop a few good frames after damage.
+    pub fn with_confirmations(reader: R, confirmations: usize) -> Self {
         // This will fail if the buffer is not large enough to contain
-        // the largest complete frame
+        // the largest complete frame plus the look-ahead
+        let window = MAX_FRAME_SIZE * (confirmations + 1) + 4;
+        Self::with_input(ReadInput::new(reader, window), confirmations)
+    }
+}
+
+impl <'a> Mp3Stream<SliceInput<'a>> {
+    /// Parse frames straight out of memory, as with `new`
+    pub fn from_slice(data: &'a [u8]) -> Self {
+        Self::with_input(SliceInput::new(data), 1)
+    }
+}
+
+impl <I: Input> Mp3Stream<I> {
+    pub fn with_input(input: I, confirmations: usize) -> Self {
         Mp3Stream{
-            reader: reader,
-            // The largest possible mp3 frame is 2881 bytes.
-            buffer: util::ShiftBuffer::new(2881),
+            input: input,
+            confirmations: confirmations,
+            synced: None,
+            lost_sync: false,
+            at_start: true,
+            id3v2: None,
+            stats: Mp3StreamStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> Mp3StreamStats {
+        self.stats
+    }
+
+    /// The ID3v2 tag that preceded the first frame, exactly as it
+    /// appeared in the input. Only known once the first frame has
+    /// been read.
+    pub fn id3v2_tag(&self) -> Option<&[u8]> {
+        self.id3v2.as_ref().map(|tag| &tag[..])
+    }
+
+    fn skip(&mut self, count: usize) {
+        self.input.consume(count);
+        self.stats.skipped_bytes += count as u64;
+        if self.stats.frames != 0 {
+            self.lost_sync = true;
         }
+        self.synced = None;
+    }
+
+    /// Read past an ID3v2 tag at the start of the stream, if any,
+    /// keeping a copy of it.
+    fn read_id3v2(&mut self) -> io::Result<()> {
+        use std::cmp::min;
+        if self.input.data().len() < 10 || &self.input.data()[0..3] != b"ID3" {
+            return Ok(());
+        }
+        // The size is a 28-bit syncsafe integer, and excludes the
+        // header and footer
+        let size = self.input.data()[6..10].iter().fold(0, |acc, b| acc << 7 | (*b as usize & 0x7F));
+        let footer = if self.input.data()[5] & 0x10 != 0 { 10 } else { 0 };
+        let mut remaining = size + 10 + footer;
+        let mut tag = Vec::with_capacity(remaining);
+        while remaining > 0 {
+            let count = min(remaining, self.input.data().len());
+            if count == 0 {
+                break;
+            }
+            tag.extend_from_slice(self.input.consume(count));
+            remaining -= count;
+            try!(self.input.fill());
+        }
+        self.id3v2 = Some(tag);
+        Ok(())
+    }
+
+    /// Check whether the frame at the start of the buffer, which is
+    /// `len` bytes long, is followed by the requested number of
+    /// matching frames (or the end of the file).
+    fn is_confirmed(&self, header: &FrameHeader, at_eof: bool) -> bool {
+        let mut offset = header.frame_len();
+        for _ in 0..self.confirmations {
+            if offset + 4 > self.input.data().len() {
+                // Running into the end of the file is as good as a
+                // confirmation.
+                return at_eof;
+            }
+            match FrameHeader::parse(&self.input.data()[offset..offset+4]) {
+                Some(ref next) if next.is_compatible(header) => offset += next.frame_len(),
+                _ => return false,
+            }
+        }
+        true
     }
 
     pub fn next_frame(&mut self) -> io::Result<Option<&[u8]>> {
         loop {
-            try!(self.buffer.fill_max(&mut self.reader));
-            // find the beginning of a frame
-            if self.buffer.is_empty() {
-                // Must have been an EOF
-                return Ok(None);
+            try!(self.input.fill());
+            if self.at_start {
+                self.at_start = false;
+                try!(self.read_id3v2());
+                continue;
             }
-            let mut frame_found = false;
-            for i in 0..self.buffer.len()-1 {
-                // This is just layer III. To match layers I and II as well, it should be
-                // && self.buffer[i+1] & 0xE0 == 0xE0
-                // TODO: support encoding audio layers I and II
-                if self.buffer[i] == 0xFF && self.buffer[i+1] & 0xE6 == 0xE2 {
-                    frame_found = true;
-                    if i != 0 {
-                        self.buffer.consume(i);
-                    }
-                    if self.buffer.len() < 4 {
-                        try!(self.buffer.fill_max(&mut self.reader));
-                    }
-                    break;
-                }
+            let at_eof = self.input.is_complete();
+            if self.input.data().len() < 4 {
+                // There isn't even room for a frame header left.
+                let len = self.input.data().len();
+                self.skip(len);
+                return Ok(None);
             }
-            if !frame_found {
-                let len = self.buffer.len();
-                self.buffer.consume(len);
+            if at_eof && self.input.data().len() == 128 && &self.input.data()[0..3] == b"TAG" {
+                // An ID3v1 tag; not damage.
+                self.input.consume(128);
                 continue;
             }
+            // find the beginning of a frame. This matches the sync
+            // word of any of MPEG 1, 2 or 2.5, layers I through III;
+            // FrameHeader::parse rejects the reserved combinations.
+            let frame_start = self.input.data().windows(2)
+                .position(|pair| pair[0] == 0xFF && pair[1] & 0xE0 == 0xE0);
+            match frame_start {
+                Some(0) => (),
+                Some(i) => {
+                    self.skip(i);
+                    continue;
+                },
+                None => {
+                    // Keep the last byte around; it may be the first
+                    // half of a sync word.
+                    let len = self.input.data().len();
+                    self.skip(len - 1);
+                    continue;
+                },
+            }
+
             // Validate the frame.
-            match mpg_get_frame_size(&self.buffer[0..4]) {
-                Some(len) => return Ok(Some(self.buffer.consume(len))),
+            let header = match FrameHeader::parse(&self.input.data()[0..4]) {
+                Some(header) => header,
                 None => {
                     // false match
-                    self.buffer.consume(1);
+                    self.skip(1);
                     continue;
                 },
+            };
+            let len = header.frame_len();
+            if len > self.input.data().len() {
+                if at_eof {
+                    // The buffer always holds a complete frame unless
+                    // we've hit EOF, so this is a truncated final
+                    // frame. Drop it.
+                    let len = self.input.data().len();
+                    self.skip(len);
+                    return Ok(None);
+                }
+                // Otherwise, it's a false match
+                self.skip(1);
+                continue;
+            }
+            let crc_ok = check_crc(&header, &self.input.data()[0..len]);
+            let in_sync = self.synced.as_ref().map_or(false, |prev| prev.is_compatible(&header));
+            if !in_sync {
+                // We're looking for sync, so be picky.
+                if crc_ok == Some(false) || !self.is_confirmed(&header, at_eof) {
+                    self.skip(1);
+                    continue;
+                }
+                if self.lost_sync {
+                    self.stats.resyncs += 1;
+                    self.lost_sync = false;
+                }
             }
+            if crc_ok == Some(false) {
+                self.stats.crc_errors += 1;
+            }
+            self.stats.frames += 1;
+            self.synced = Some(header);
+            return Ok(Some(self.input.consume(len)));
+        }
+    }
+}
+
+/// CRC-16 as used by MPEG audio (polynomial 0x8005, MSB first)
+fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { crc << 1 ^ 0x8005 } else { crc << 1 };
         }
     }
+    crc
+}
+
+/// Verify the CRC of a complete frame. Returns None if the frame has
+/// no CRC, or if it's one we can't check (Layer II, where the
+/// protected region depends on the bit allocation tables).
+fn check_crc(header: &FrameHeader, frame: &[u8]) -> Option<bool> {
+    use byteorder::{BigEndian,ByteOrder};
+    if !header.protected {
+        return None;
+    }
+    let protected_len = match header.layer {
+        // Layer III: the side information
+        Layer::III => header.side_info_len(),
+        // Layer I: 4 bits of allocation per subband and channel, with
+        // joint stereo sharing the subbands above the bound
+        Layer::I => match header.channel_mode {
+            ChannelMode::Mono => 16,
+            ChannelMode::JointStereo => (32 + 4 * (header.mode_extension as usize + 1)) / 2,
+            _ => 32,
+        },
+        Layer::II => return None,
+    };
+    if frame.len() < 6 + protected_len {
+        return Some(false);
+    }
+    let crc = crc16(crc16(0xFFFF, &frame[2..4]), &frame[6..6+protected_len]);
+    Some(crc == BigEndian::read_u16(&frame[4..6]))
 }
 
 
@@ -123,116 +327,603 @@ const MPEG_FRAME_SAMPLES : [[u16;4];4] = [
 const MPEG_SLOT_SIZE : [u16;4] = [ 0, 1, 1, 4 ]; // Rsvd, 3, 2, 1
 
 
-fn mpg_get_frame_size (hdr: &[u8]) -> Option<usize> {
-    
-    // Quick validity check
-    if     ( (hdr[0] & 0xFF) != 0xFF)
-        || ( (hdr[1] & 0xE0) != 0xE0)   // 3 sync bits
-        || ( (hdr[1] & 0x18) == 0x08)   // Version rsvd
-        || ( (hdr[1] & 0x06) == 0x00)   // Layer rsvd
-        || ( (hdr[2] & 0xF0) == 0xF0)   // Bitrate rsvd
-    {
-        return None;
+/// MPEG audio version
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub enum MpegVersion {
+    Mpeg1,
+    Mpeg2,
+    Mpeg25,
+}
+
+/// MPEG audio layer
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub enum Layer {
+    I,
+    II,
+    III,
+}
+
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub enum ChannelMode {
+    Stereo,
+    JointStereo,
+    DualChannel,
+    Mono,
+}
+
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub enum Emphasis {
+    None,
+    /// 50/15 µs
+    FiftyFifteen,
+    Reserved,
+    /// CCITT J.17
+    CcittJ17,
+}
+
+/// A parsed MPEG audio frame header
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub struct FrameHeader {
+    pub version: MpegVersion,
+    pub layer: Layer,
+    /// Whether a CRC-16 follows the header
+    pub protected: bool,
+    /// In kbit/s
+    pub bitrate: u32,
+    /// In Hz
+    pub sample_rate: u32,
+    pub padding: bool,
+    pub private: bool,
+    pub channel_mode: ChannelMode,
+    /// Joint stereo parameters; the meaning depends on the layer
+    pub mode_extension: u8,
+    pub copyright: bool,
+    pub original: bool,
+    pub emphasis: Emphasis,
+}
+
+impl FrameHeader {
+    /// Parse the 4-byte header at the start of `hdr`. Returns None
+    /// if it isn't a valid header, including for free-format
+    /// streams, which we don't support.
+    pub fn parse(hdr: &[u8]) -> Option<Self> {
+        if hdr.len() < 4 || hdr[0] != 0xFF {
+            return None;
+        }
+        Self::from_pseudoheader(hdr)
     }
-    
-    // Data to be extracted from the header
-    let ver = ((hdr[1] & 0x18) >> 3) as usize;   // Version index
-    let lyr = ((hdr[1] & 0x06) >> 1) as usize;   // Layer index
-    let pad = ((hdr[2] & 0x02) >> 1) as usize;   // Padding? 0/1
-    let brx = ((hdr[2] & 0xf0) >> 4) as usize;   // Bitrate index
-    let srx = ((hdr[2] & 0x0c) >> 2) as usize;   // SampRate index
-    
-    // Lookup real values of these fields
-    let bitrate   = MPEG_BITRATES[ver][lyr][brx] as usize * 1000;
-    let samprate  = MPEG_SRATES[ver][srx] as usize;
-    let samples   = MPEG_FRAME_SAMPLES[ver][lyr] as usize;
-    let slot_size = MPEG_SLOT_SIZE[lyr] as usize;
-    
-    if samprate == 0 {
-        return None;
+
+    /// Parse a header whose first byte has been replaced, such as the
+    /// representative frame header in an OggMP3 stream header. The
+    /// first byte is ignored.
+    pub fn from_pseudoheader(hdr: &[u8]) -> Option<Self> {
+        // Quick validity check
+        if     hdr.len() < 4
+            || ( (hdr[1] & 0xE0) != 0xE0)   // 3 sync bits
+            || ( (hdr[1] & 0x18) == 0x08)   // Version rsvd
+            || ( (hdr[1] & 0x06) == 0x00)   // Layer rsvd
+            || ( (hdr[2] & 0xF0) == 0xF0)   // Bitrate rsvd
+            || ( (hdr[2] & 0xF0) == 0x00)   // Free format
+            || ( (hdr[2] & 0x0C) == 0x0C)   // SampRate rsvd
+        {
+            return None;
+        }
+
+        // Data to be extracted from the header
+        let ver = ((hdr[1] & 0x18) >> 3) as usize;   // Version index
+        let lyr = ((hdr[1] & 0x06) >> 1) as usize;   // Layer index
+        let brx = ((hdr[2] & 0xf0) >> 4) as usize;   // Bitrate index
+        let srx = ((hdr[2] & 0x0c) >> 2) as usize;   // SampRate index
+
+        Some(FrameHeader{
+            version: match ver {
+                0 => MpegVersion::Mpeg25,
+                2 => MpegVersion::Mpeg2,
+                _ => MpegVersion::Mpeg1,
+            },
+            layer: match lyr {
+                1 => Layer::III,
+                2 => Layer::II,
+                _ => Layer::I,
+            },
+            protected: hdr[1] & 0x01 == 0,
+            bitrate: MPEG_BITRATES[ver][lyr][brx] as u32,
+            sample_rate: MPEG_SRATES[ver][srx] as u32,
+            padding: hdr[2] & 0x02 != 0,
+            private: hdr[2] & 0x01 != 0,
+            channel_mode: match hdr[3] >> 6 {
+                0 => ChannelMode::Stereo,
+                1 => ChannelMode::JointStereo,
+                2 => ChannelMode::DualChannel,
+                _ => ChannelMode::Mono,
+            },
+            mode_extension: (hdr[3] >> 4) & 0x3,
+            copyright: hdr[3] & 0x08 != 0,
+            original: hdr[3] & 0x04 != 0,
+            emphasis: match hdr[3] & 0x03 {
+                0 => Emphasis::None,
+                1 => Emphasis::FiftyFifteen,
+                2 => Emphasis::Reserved,
+                _ => Emphasis::CcittJ17,
+            },
+        })
     }
-    let base_framesize = (samples * bitrate / samprate) >> 3;
 
-    // Frame sizes are truncated integers
-    if pad == 1 {
-	Some(base_framesize + slot_size)
-    } else {
-	Some(base_framesize)
+    fn version_index(&self) -> usize {
+        match self.version {
+            MpegVersion::Mpeg25 => 0,
+            MpegVersion::Mpeg2 => 2,
+            MpegVersion::Mpeg1 => 3,
+        }
+    }
+
+    fn layer_index(&self) -> usize {
+        match self.layer {
+            Layer::III => 1,
+            Layer::II => 2,
+            Layer::I => 3,
+        }
+    }
+
+    /// The number of samples (per channel) in the frame
+    pub fn samples(&self) -> u32 {
+        MPEG_FRAME_SAMPLES[self.version_index()][self.layer_index()] as u32
+    }
+
+    /// The length of the frame in bytes, including the header
+    pub fn frame_len(&self) -> usize {
+        let samples = self.samples() as usize;
+        let slot_size = MPEG_SLOT_SIZE[self.layer_index()] as usize;
+        let pad = if self.padding { 1 } else { 0 };
+
+        // Frame sizes are a truncated number of slots; Layer I uses
+        // 4-byte slots, so the truncation has to happen before scaling
+        // up to bytes.
+        let slots = samples / 8 / slot_size * self.bitrate as usize * 1000 / self.sample_rate as usize;
+        (slots + pad) * slot_size
+    }
+
+    pub fn channels(&self) -> u32 {
+        if self.channel_mode == ChannelMode::Mono { 1 } else { 2 }
+    }
+
+    /// The length of the Layer III side information that follows
+    /// the header (and CRC, if any)
+    pub fn side_info_len(&self) -> usize {
+        match (self.version == MpegVersion::Mpeg1, self.channel_mode == ChannelMode::Mono) {
+            (true, false) => 32,
+            (true, true) | (false, false) => 17,
+            (false, true) => 9,
+        }
+    }
+
+    /// Whether a frame with header `other` could belong to the same
+    /// stream as this one. The version, layer and sample rate never
+    /// change within a stream.
+    pub fn is_compatible(&self, other: &FrameHeader) -> bool {
+        self.version == other.version
+            && self.layer == other.layer
+            && self.sample_rate == other.sample_rate
     }
 }
 
 pub fn max_fsize() -> usize {
     use std::cmp::max;
-    let mut frame = [0xFF;3];
+    let mut frame = [0xFF;4];
     let mut maxsize = 0;
     for x in 0..255 {
         frame[1] = x;
         for y in 0..255 {
             frame[2] = y;
-            maxsize = max(maxsize, mpg_get_frame_size(&frame).unwrap_or(0));
+            maxsize = max(maxsize, FrameHeader::parse(&frame).map_or(0, |h| h.frame_len()));
         }
     }
     maxsize
 }
 
+/// The number of samples of delay introduced by a Layer III decoder,
+/// which LAME leaves out of the delay it records.
+const DECODER_DELAY : u32 = 529;
+
+/// Encoder delay and padding, as recorded in a Xing/Info or VBRI
+/// frame. Both are counted in samples (per channel) of decoder
+/// output, and include the decoder's own delay.
+#[derive(Copy,Clone,PartialEq,Eq,Debug,Default)]
+pub struct GaplessInfo {
+    /// Samples at the start of the decoded stream that are not part
+    /// of the original audio
+    pub delay: u32,
+    /// Samples at the end of the decoded stream that are not part of
+    /// the original audio
+    pub padding: u32,
+}
+
+/// Check whether `frame` is a Xing, Info or VBRI frame rather than
+/// audio. If so, returns the gapless playback information it
+/// contains, which will be all zeroes if the encoder didn't record
+/// any.
+pub fn parse_info_frame(frame: &[u8]) -> Option<GaplessInfo> {
+    use byteorder::{BigEndian,ByteOrder};
+
+    let header = match FrameHeader::parse(frame) {
+        // These only appear in Layer III streams
+        Some(ref header) if header.layer == Layer::III => *header,
+        _ => return None,
+    };
+    let crc_len = if header.protected { 2 } else { 0 };
+
+    let xing_off = 4 + crc_len + header.side_info_len();
+    if frame.len() >= xing_off + 8 && (&frame[xing_off..xing_off+4] == b"Xing" || &frame[xing_off..xing_off+4] == b"Info") {
+        let flags = BigEndian::read_u32(&frame[xing_off+4..xing_off+8]);
+        // Skip the frame count, byte count, TOC and quality fields,
+        // if present.
+        let lame_off = xing_off + 8
+            + if flags & 0x1 != 0 { 4 } else { 0 }
+            + if flags & 0x2 != 0 { 4 } else { 0 }
+            + if flags & 0x4 != 0 { 100 } else { 0 }
+            + if flags & 0x8 != 0 { 4 } else { 0 };
+        if frame.len() < lame_off + 24 || !frame[lame_off..lame_off+4].iter().all(|c| c.is_ascii_alphanumeric()) {
+            // No LAME extension; we know it's not audio, but nothing
+            // about the delay.
+            return Some(GaplessInfo::default());
+        }
+        let raw = &frame[lame_off+21..lame_off+24];
+        let delay = (raw[0] as u32) << 4 | (raw[1] as u32) >> 4;
+        let padding = (raw[1] as u32 & 0xF) << 8 | raw[2] as u32;
+        return Some(GaplessInfo{
+            delay: delay + DECODER_DELAY,
+            padding: padding.saturating_sub(DECODER_DELAY),
+        });
+    }
+
+    // VBRI headers are always at a fixed offset
+    let vbri_off = 4 + 32;
+    if frame.len() >= vbri_off + 8 && &frame[vbri_off..vbri_off+4] == b"VBRI" {
+        let delay = BigEndian::read_u16(&frame[vbri_off+6..vbri_off+8]) as u32;
+        return Some(GaplessInfo{
+            delay: delay + DECODER_DELAY,
+            padding: 0,
+        });
+    }
+    None
+}
+
+/// Find a text frame, such as `TIT2` (title) or `TPE1` (artist), in
+/// an ID3v2 tag. ID3v2.2 tags are searched for the equivalent
+/// three-letter frame. Returns the first string in the frame.
+pub fn id3v2_text(tag: &[u8], frame_id: &[u8; 4]) -> Option<String> {
+    use std::cmp::min;
+    let syncsafe = |b: &[u8]| b.iter().fold(0, |acc, b| acc << 7 | (*b as usize & 0x7F));
+    let plain = |b: &[u8]| b.iter().fold(0, |acc, b| acc << 8 | *b as usize);
+    if tag.len() < 10 || &tag[0..3] != b"ID3" {
+        return None;
+    }
+    let version = tag[3];
+    let flags = tag[5];
+    // Before 2.4, unsynchronisation applies to the tag as a whole;
+    // it's rare enough not to bother undoing
+    if version < 4 && flags & 0x80 != 0 {
+        return None;
+    }
+    let end = min(tag.len(), 10 + syncsafe(&tag[6..10]));
+    let mut pos = 10;
+    if version >= 3 && flags & 0x40 != 0 {
+        // Skip the extended header
+        if end < 14 {
+            return None;
+        }
+        pos += if version == 3 { 4 + plain(&tag[10..14]) } else { syncsafe(&tag[10..14]) };
+    }
+    let (wanted, header_len): (&[u8], usize) = if version == 2 {
+        match frame_id {
+            b"TIT2" => (b"TT2", 6),
+            b"TPE1" => (b"TP1", 6),
+            b"TALB" => (b"TAL", 6),
+            _ => return None,
+        }
+    } else {
+        (frame_id, 10)
+    };
+    while pos + header_len <= end {
+        if tag[pos] == 0 {
+            // Padding
+            break;
+        }
+        let size = match version {
+            2 => plain(&tag[pos+3..pos+6]),
+            3 => plain(&tag[pos+4..pos+8]),
+            _ => syncsafe(&tag[pos+4..pos+8]),
+        };
+        let body = pos + header_len;
+        if body + size > end {
+            return None;
+        }
+        if &tag[pos..pos+wanted.len()] == wanted {
+            return decode_id3v2_text(&tag[body..body+size]);
+        }
+        pos = body + size;
+    }
+    None
+}
+
+fn decode_id3v2_text(body: &[u8]) -> Option<String> {
+    let (encoding, mut text) = match body.split_first() {
+        Some((encoding, text)) => (*encoding, text),
+        None => return None,
+    };
+    let text = match encoding {
+        0 => text.iter().map(|b| *b as char).collect(),
+        1 | 2 => {
+            // UTF-16; encoding 1 has a BOM, encoding 2 is big endian
+            let mut big_endian = encoding == 2;
+            if text.starts_with(&[0xFF, 0xFE]) {
+                big_endian = false;
+                text = &text[2..];
+            } else if text.starts_with(&[0xFE, 0xFF]) {
+                big_endian = true;
+                text = &text[2..];
+            }
+            let units: Vec<u16> = text.chunks(2).filter(|unit| unit.len() == 2).map(|unit| {
+                if big_endian {
+                    (unit[0] as u16) << 8 | unit[1] as u16
+                } else {
+                    (unit[1] as u16) << 8 | unit[0] as u16
+                }
+            }).collect();
+            String::from_utf16_lossy(&units)
+        },
+        3 => String::from_utf8_lossy(text).into_owned(),
+        _ => return None,
+    };
+    // 2.4 allows several NUL-separated strings
+    let text = text.split('\0').next().unwrap_or("").trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_owned())
+    }
+}
+
+/// What part of the song an audio stream holds. See "Roles" in the
+/// OggMP3 spec.
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub enum AudioRole {
+    Main,
+    Instrumental,
+    GuideVocal,
+    BackingVocals,
+    Other(u8),
+}
+
+impl AudioRole {
+    pub fn from_u8(v: u8) -> Self {
+        match v {
+            0 => AudioRole::Main,
+            1 => AudioRole::Instrumental,
+            2 => AudioRole::GuideVocal,
+            3 => AudioRole::BackingVocals,
+            n => AudioRole::Other(n),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            AudioRole::Main => 0,
+            AudioRole::Instrumental => 1,
+            AudioRole::GuideVocal => 2,
+            AudioRole::BackingVocals => 3,
+            AudioRole::Other(n) => n,
+        }
+    }
+
+    /// The role of a stream, given its OggMP3 header
+    pub fn from_header(header: &[u8]) -> Self {
+        Mp3Header::from_bytes(header).map_or(AudioRole::Main, |header| header.role)
+    }
+
+    /// The short name used on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            AudioRole::Main => "main",
+            AudioRole::Instrumental => "instrumental",
+            AudioRole::GuideVocal => "guide",
+            AudioRole::BackingVocals => "backing",
+            AudioRole::Other(_) => "other",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "main" => Some(AudioRole::Main),
+            "instrumental" => Some(AudioRole::Instrumental),
+            "guide" => Some(AudioRole::GuideVocal),
+            "backing" => Some(AudioRole::BackingVocals),
+            _ => None,
+        }
+    }
+}
+
+/// The OggMP3 major version this library reads and writes
+pub const MP3_MAJOR_VERSION: u8 = 0;
+/// The newest minor version this library knows the fields of
+pub const MP3_MINOR_VERSION: u8 = 3;
+
+/// The first header packet of an OggMP3 stream
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub struct Mp3Header {
+    pub version: (u8, u8),
+    /// See "Flags" in the spec
+    pub flags: u8,
+    pub aux_headers: usize,
+    pub representative: FrameHeader,
+    pub sample_frequency: u32,
+    pub samples_per_frame: u32,
+    /// All zeroes before 0.1
+    pub gapless: GaplessInfo,
+    /// Main before 0.3
+    pub role: AudioRole,
+}
+
+impl Mp3Header {
+    /// Parse the first header packet. Headers with a higher minor
+    /// version are accepted, and any fields we don't know are ignored.
+    pub fn parse(buf: &[u8]) -> Result<Self, HeaderError> {
+        use byteorder::{ByteOrder,LittleEndian};
+        let version = try!(util::check_header_version(buf, b"OggMP3\0\0", MP3_MAJOR_VERSION));
+        // Each minor version has a fixed length
+        let len = match version.1 {
+            0 => 24,
+            1 | 2 => 32,
+            _ => 33,
+        };
+        if buf.len() < len {
+            return Err(HeaderError::Malformed("header is too short"));
+        }
+        let representative = match FrameHeader::from_pseudoheader(&buf[12..16]) {
+            Some(representative) => representative,
+            None => return Err(HeaderError::Malformed("invalid representative frame header")),
+        };
+        Ok(Mp3Header{
+            version: version,
+            flags: buf[10],
+            aux_headers: buf[11] as usize,
+            representative: representative,
+            sample_frequency: LittleEndian::read_u32(&buf[16..20]),
+            samples_per_frame: LittleEndian::read_u32(&buf[20..24]),
+            gapless: if version.1 >= 1 {
+                GaplessInfo{
+                    delay: LittleEndian::read_u32(&buf[24..28]),
+                    padding: LittleEndian::read_u32(&buf[28..32]),
+                }
+            } else {
+                GaplessInfo::default()
+            },
+            role: if version.1 >= 3 { AudioRole::from_u8(buf[32]) } else { AudioRole::Main },
+        })
+    }
+
+    /// As `parse`, for when the reason doesn't matter
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        Self::parse(buf).ok()
+    }
+}
+
 // OggMP3 encoder
 pub struct OggMP3Coder<R> {
     /// A reader that produces MP3 frames
-    stream: Mp3Stream<R>,
+    stream: Mp3Stream<ReadInput<R>>,
     // Only Some until the first data frame has been produced
     first_frame: Option<ogg::Packet>,
     pseudoheader: [u8;4],
+    /// The header of the first frame
+    header: FrameHeader,
     samples_per_frame: u32,
     sample_frequency: u32,
+    gapless: GaplessInfo,
+    /// The ID3v2 tag from the start of the input
+    tag: Option<Vec<u8>>,
+    /// The Xing/Info/VBRI frame from the start of the input
+    info_frame: Option<Vec<u8>>,
+    role: AudioRole,
     last_sample_no: u64,
 }
 
 impl <R: Read> OggMP3Coder<R> {
     pub fn new(reader: R) -> io::Result<Self> {
         let mut stream = Mp3Stream::new(reader);
-        let first_frame = try!(stream.next_frame()).map(|frame| ogg::Packet{
+        let mut first_frame = try!(stream.next_frame()).map(|frame| ogg::Packet{
             content: frame.to_owned(),
             timestamp: 0,
         });
+        // A Xing/Info/VBRI frame decodes as silence; we record what
+        // it tells us in the stream header rather than muxing it.
+        let gapless = first_frame.as_ref().and_then(|frame| parse_info_frame(&frame.content));
+        let mut info_frame = None;
+        if gapless.is_some() {
+            info_frame = first_frame.take().map(|frame| frame.content);
+            first_frame = try!(stream.next_frame()).map(|frame| ogg::Packet{
+                content: frame.to_owned(),
+                timestamp: 0,
+            });
+        }
+        let tag = stream.id3v2_tag().map(|tag| tag.to_owned());
         match first_frame {
             None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "MP3 file contained no valid frames")),
             Some(frame) => {
                 // The pseudoheader is the frame header with the first byte set to 0.
                 let pseudoheader = [0, frame.content[1], frame.content[2], frame.content[3]];
-                let mp_ver = pseudoheader[1] as usize & 0x18 >> 3;
-                let mp_lyr = pseudoheader[1] as usize & 0x06 >> 1;
-                let mp_srx = pseudoheader[2] as usize & 0x0c >> 2;
-                let sample_frequency = MPEG_SRATES[mp_ver][mp_srx] as u32;
-                let samples_per_frame = MPEG_FRAME_SAMPLES[mp_ver][mp_lyr] as u32;
+                // Mp3Stream only produces frames with valid headers
+                let header = FrameHeader::parse(&frame.content).expect("Mp3Stream produced an invalid frame");
 
                 Ok(OggMP3Coder{
                     stream: stream,
                     first_frame: Some(frame),
                     pseudoheader: pseudoheader,
-                    sample_frequency: sample_frequency,
-                    samples_per_frame: samples_per_frame,
+                    sample_frequency: header.sample_rate,
+                    samples_per_frame: header.samples(),
+                    header: header,
+                    gapless: gapless.unwrap_or_default(),
+                    tag: tag,
+                    info_frame: info_frame,
+                    role: AudioRole::Main,
                     last_sample_no: 0,
                 })
             }
         }
     }
+
+    /// Say what part of the song this stream holds
+    pub fn set_role(&mut self, role: AudioRole) {
+        self.role = role;
+    }
+
+    /// The ID3v2 tag from the start of the input, if any
+    pub fn id3v2_tag(&self) -> Option<&[u8]> {
+        self.tag.as_ref().map(|tag| &tag[..])
+    }
+
+    /// The encoder delay and padding found in the input, if any
+    pub fn gapless_info(&self) -> GaplessInfo {
+        self.gapless
+    }
+
+    /// Statistics about damage found in the input so far
+    pub fn stats(&self) -> Mp3StreamStats {
+        self.stream.stats()
+    }
 }
 
 impl <R: Read> ogg::BitstreamCoder for OggMP3Coder<R> {
     fn headers(&self) -> Vec<Vec<u8>> {
         use byteorder::{LittleEndian,WriteBytesExt};
-        let mut header = Vec::with_capacity(24);
+        let mut header = Vec::with_capacity(33);
         header.extend_from_slice(b"OggMP3\0\0");
-        header.push(0); // major version
-        header.push(0); // minor version
-        header.push(if self.pseudoheader[3] & 0xC0 == 0xC0 { 0 } else { 2 }); // tag; we only care about the stereo bit
-        header.push(0);
+        let mut flags = 0;
+        if self.tag.is_some() {
+            flags |= 1;
+        }
+        if self.header.channels() != 1 {
+            flags |= 2;
+        }
+        if self.info_frame.is_some() {
+            flags |= 8;
+        }
+        header.push(MP3_MAJOR_VERSION);
+        header.push(MP3_MINOR_VERSION);
+        header.push(flags);
+        header.push(self.tag.iter().chain(self.info_frame.iter()).count() as u8);
         header.extend_from_slice(&self.pseudoheader);
 
         header.write_u32::<LittleEndian>(self.sample_frequency).unwrap();
         header.write_u32::<LittleEndian>(self.samples_per_frame).unwrap();
+        header.write_u32::<LittleEndian>(self.gapless.delay).unwrap();
+        header.write_u32::<LittleEndian>(self.gapless.padding).unwrap();
+        header.push(self.role.to_u8());
 
-        vec![header]
+        let mut headers = vec![header];
+        headers.extend(self.tag.iter().cloned());
+        headers.extend(self.info_frame.iter().cloned());
+        headers
     }
 
     fn next_frame(&mut self) -> io::Result<Option<ogg::Packet>> {
@@ -251,7 +942,541 @@ impl <R: Read> ogg::BitstreamCoder for OggMP3Coder<R> {
         }
     }
 
+    fn warnings(&self) -> Vec<String> {
+        let stats = self.stream.stats();
+        let mut warnings = Vec::new();
+        if stats.skipped_bytes != 0 {
+            warnings.push(format!("skipped {} bytes of unrecognized data; lost sync {} times", stats.skipped_bytes, stats.resyncs));
+        }
+        if stats.crc_errors != 0 {
+            warnings.push(format!("{} of {} frames failed their CRC check", stats.crc_errors, stats.frames));
+        }
+        warnings
+    }
+
     fn map_granule(&self, timestamp: u64) -> u64 {
-        timestamp * 1000_000 / self.sample_frequency as u64
+        Mp3Granule{sample_rate: self.sample_frequency, delay: self.gapless.delay}.micros(timestamp)
+    }
+}
+
+/// How an OggMP3 stream's granule positions map to time. Granules
+/// count samples from the start of the stream, encoder delay
+/// included; time 0 is the first sample after the delay.
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub struct Mp3Granule {
+    pub sample_rate: u32,
+    pub delay: u32,
+}
+
+impl Mp3Granule {
+    /// The time of `granule`, in µs
+    pub fn micros(&self, granule: u64) -> u64 {
+        granule.saturating_sub(self.delay as u64).saturating_mul(1000_000) / self.sample_rate as u64
+    }
+}
+
+/// An MP3 frame and the granule position of its first sample
+#[derive(Debug)]
+pub struct TimedFrame {
+    pub sample: u64,
+    pub frame: Vec<u8>,
+}
+
+/// Splits an OggMP3 stream back into MP3 frames. Streams with
+/// shortened frame headers aren't supported.
+pub struct OggMp3Decoder {
+    header: Mp3Header,
+    clock: Mp3Granule,
+    /// Granule position at the end of the last frame
+    position: u64,
+    output: DecodeQueue<TimedFrame>,
+}
+
+impl OggMp3Decoder {
+    /// Returns None if `header` isn't a usable OggMP3 header
+    pub fn new(header: &[u8]) -> Option<Self> {
+        match Mp3Header::from_bytes(header) {
+            Some(header) if header.flags & 4 == 0 => Some(OggMp3Decoder{
+                header: header,
+                clock: Mp3Granule{sample_rate: header.representative.sample_rate, delay: header.gapless.delay},
+                position: 0,
+                output: DecodeQueue::new(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The parsed stream header
+    pub fn header(&self) -> &Mp3Header {
+        &self.header
+    }
+
+    /// The header that every frame in the stream is compatible with
+    pub fn representative(&self) -> FrameHeader {
+        self.header.representative
+    }
+
+    pub fn clock(&self) -> Mp3Granule {
+        self.clock
+    }
+
+    /// Samples of padding at the end of the stream
+    pub fn padding(&self) -> u32 {
+        self.header.gapless.padding
+    }
+
+    pub fn role(&self) -> AudioRole {
+        self.header.role
+    }
+
+    /// Where the frames go
+    pub fn output(&self) -> DecodeQueue<TimedFrame> {
+        self.output.clone()
+    }
+}
+
+impl ogg::BitstreamDecoder for OggMp3Decoder {
+    fn map_granule(&self, granule: u64) -> u64 {
+        self.clock.micros(granule)
+    }
+
+    fn num_headers(&self) -> usize { self.header.aux_headers + 1 }
+
+    fn process_header(&mut self, _: &[u8]) {}
+
+    fn process_packet(&mut self, packet: &[u8], _: u64) -> u64 {
+        let samples = FrameHeader::parse(packet).map_or(self.header.representative.samples(), |header| header.samples());
+        self.output.push(TimedFrame{
+            sample: self.position,
+            frame: packet.to_owned(),
+        });
+        self.position += samples as u64;
+        self.position
+    }
+
+    fn page_done(&mut self, granule: u64) {
+        // Skip ahead over lost pages
+        if granule != !0 && granule > self.position {
+            self.position = granule;
+        }
+    }
+
+    fn notice_gap(&mut self) {}
+
+    fn finish(&mut self) {
+        self.output.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use ogg::BitstreamCoder;
+    use super::*;
+
+    // Version and layer are given as their raw header field values
+    // (version: 0 = 2.5, 2 = 2, 3 = 1; layer: 1 = III, 2 = II, 3 = I)
+    fn header(ver: u8, lyr: u8, brx: u8, srx: u8, pad: bool) -> [u8;4] {
+        [0xFF,
+         0xE0 | ver << 3 | lyr << 1 | 1,
+         brx << 4 | srx << 2 | if pad { 2 } else { 0 },
+         0x00]
+    }
+
+    fn frame_size(hdr: &[u8]) -> Option<usize> {
+        FrameHeader::parse(hdr).map(|h| h.frame_len())
+    }
+
+    // Bitrates in kbit/s for bitrate indices 1 to 14, by layer
+    const V1_BITRATES : [[u32;14];3] = [
+        [32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448],
+        [32, 48, 56,  64,  80,  96, 112, 128, 160, 192, 224, 256, 320, 384],
+        [32, 40, 48,  56,  64,  80,  96, 112, 128, 160, 192, 224, 256, 320],
+    ];
+    const V2_BITRATES : [[u32;14];3] = [
+        [32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256],
+        [ 8, 16, 24, 32, 40, 48,  56,  64,  80,  96, 112, 128, 144, 160],
+        [ 8, 16, 24, 32, 40, 48,  56,  64,  80,  96, 112, 128, 144, 160],
+    ];
+
+    #[test]
+    fn header_bytes_1_and_2_exhaustive() {
+        for b1 in 0..256usize {
+            for b2 in 0..256usize {
+                let hdr = [0xFF, b1 as u8, b2 as u8, 0];
+                let (ver, lyr, brx, srx) = ((b1 >> 3) & 3, (b1 >> 1) & 3, b2 >> 4, (b2 >> 2) & 3);
+                let valid = b1 & 0xE0 == 0xE0 && ver != 1 && lyr != 0 && brx != 0 && brx != 15 && srx != 3;
+                let h = match FrameHeader::parse(&hdr) {
+                    None => {
+                        assert!(!valid, "Rejected valid header {:02x} {:02x}", b1, b2);
+                        continue;
+                    },
+                    Some(h) => h,
+                };
+                assert!(valid, "Accepted invalid header {:02x} {:02x}", b1, b2);
+
+                let (version, rates, table) = match ver {
+                    3 => (MpegVersion::Mpeg1, [44100, 48000, 32000], V1_BITRATES),
+                    2 => (MpegVersion::Mpeg2, [22050, 24000, 16000], V2_BITRATES),
+                    _ => (MpegVersion::Mpeg25, [11025, 12000, 8000], V2_BITRATES),
+                };
+                let (layer, samples) = match lyr {
+                    3 => (Layer::I, 384),
+                    2 => (Layer::II, 1152),
+                    _ => (Layer::III, if ver == 3 { 1152 } else { 576 }),
+                };
+                let bitrate = table[3 - lyr][brx - 1];
+                let sample_rate = rates[srx];
+                let pad = (b2 >> 1) & 1;
+                let frame_len = if layer == Layer::I {
+                    (12 * bitrate as usize * 1000 / sample_rate + pad) * 4
+                } else {
+                    samples / 8 * bitrate as usize * 1000 / sample_rate + pad
+                };
+
+                assert_eq!(h.version, version);
+                assert_eq!(h.layer, layer);
+                assert_eq!(h.protected, b1 & 1 == 0);
+                assert_eq!(h.bitrate, bitrate);
+                assert_eq!(h.sample_rate, sample_rate as u32);
+                assert_eq!(h.padding, pad == 1);
+                assert_eq!(h.private, b2 & 1 == 1);
+                assert_eq!(h.samples(), samples as u32);
+                assert_eq!(h.frame_len(), frame_len, "Wrong length for {:02x} {:02x}", b1, b2);
+            }
+        }
+    }
+
+    #[test]
+    fn header_byte_3_exhaustive() {
+        let modes = [ChannelMode::Stereo, ChannelMode::JointStereo, ChannelMode::DualChannel, ChannelMode::Mono];
+        let emphases = [Emphasis::None, Emphasis::FiftyFifteen, Emphasis::Reserved, Emphasis::CcittJ17];
+        for b3 in 0..256usize {
+            let h = FrameHeader::parse(&[0xFF, 0xFB, 0x90, b3 as u8]).unwrap();
+            assert_eq!(h.channel_mode, modes[b3 >> 6]);
+            assert_eq!(h.channels(), if b3 >> 6 == 3 { 1 } else { 2 });
+            assert_eq!(h.mode_extension as usize, (b3 >> 4) & 3);
+            assert_eq!(h.copyright, b3 & 0x08 != 0);
+            assert_eq!(h.original, b3 & 0x04 != 0);
+            assert_eq!(h.emphasis, emphases[b3 & 3]);
+        }
+    }
+
+    #[test]
+    fn pseudoheader() {
+        let h = FrameHeader::from_pseudoheader(&[0, 0xFB, 0x90, 0xC0]).unwrap();
+        assert_eq!(h.frame_len(), 417);
+        assert_eq!(h.channel_mode, ChannelMode::Mono);
+        assert!(FrameHeader::parse(&[0, 0xFB, 0x90, 0xC0]).is_none());
+        assert!(FrameHeader::parse(&[0xFF, 0xFB, 0x90]).is_none());
+    }
+
+    #[test]
+    fn largest_frame() {
+        assert_eq!(max_fsize(), MAX_FRAME_SIZE);
+    }
+
+    fn frame(hdr: [u8;4], len: usize) -> Vec<u8> {
+        let mut frame = vec![0; len];
+        frame[0..4].copy_from_slice(&hdr);
+        frame
+    }
+
+    // (version, layer, bitrate index, srate index, frame length,
+    // sample rate, samples per frame)
+    const CASES : [(u8, u8, u8, u8, usize, u32, u32); 9] = [
+        (3, 3, 12, 0,  416, 44100,  384), // MPEG 1   Layer I   384k
+        (3, 2, 10, 1,  576, 48000, 1152), // MPEG 1   Layer II  192k
+        (3, 1,  9, 0,  417, 44100, 1152), // MPEG 1   Layer III 128k
+        (2, 3,  4, 2,  192, 16000,  384), // MPEG 2   Layer I    64k
+        (2, 2,  8, 1,  384, 24000, 1152), // MPEG 2   Layer II   64k
+        (2, 1,  8, 0,  208, 22050,  576), // MPEG 2   Layer III  64k
+        (0, 3,  1, 1,  128, 12000,  384), // MPEG 2.5 Layer I    32k
+        (0, 2,  2, 2,  288,  8000, 1152), // MPEG 2.5 Layer II   16k
+        (0, 1,  4, 0,  208, 11025,  576), // MPEG 2.5 Layer III  32k
+    ];
+
+    #[test]
+    fn frame_sizes() {
+        for &(ver, lyr, brx, srx, len, _, _) in &CASES {
+            assert_eq!(frame_size(&header(ver, lyr, brx, srx, false)), Some(len));
+        }
+    }
+
+    #[test]
+    fn padded_frame_sizes() {
+        // Layer I pads by a whole 4-byte slot
+        assert_eq!(frame_size(&header(3, 3, 12, 0, true)), Some(420));
+        assert_eq!(frame_size(&header(3, 2, 10, 1, true)), Some(577));
+        assert_eq!(frame_size(&header(3, 1,  9, 0, true)), Some(418));
+    }
+
+    #[test]
+    fn rejects_reserved_fields() {
+        assert_eq!(frame_size(&header(1, 1, 9, 0, false)), None); // version
+        assert_eq!(frame_size(&header(3, 0, 9, 0, false)), None); // layer
+        assert_eq!(frame_size(&header(3, 1, 15, 0, false)), None); // bitrate
+        assert_eq!(frame_size(&header(3, 1, 9, 3, false)), None); // sample rate
+        assert_eq!(frame_size(&header(3, 1, 0, 0, false)), None); // free format
+    }
+
+    #[test]
+    fn stream_syncs_on_all_layers() {
+        for &(ver, lyr, brx, srx, len, _, _) in &CASES {
+            let mut input = vec![0x12, 0xFF, 0x00];
+            for _ in 0..3 {
+                input.extend(frame(header(ver, lyr, brx, srx, false), len));
+            }
+            let mut stream = Mp3Stream::new(Cursor::new(input));
+            for _ in 0..3 {
+                let frame = stream.next_frame().unwrap().expect("Missing frame");
+                assert_eq!(frame.len(), len);
+                assert_eq!(frame[0], 0xFF);
+            }
+            assert!(stream.next_frame().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn coder_sample_counts() {
+        for &(ver, lyr, brx, srx, len, rate, spf) in &CASES {
+            let mut input = Vec::new();
+            for _ in 0..2 {
+                input.extend(frame(header(ver, lyr, brx, srx, false), len));
+            }
+            let mut coder = OggMP3Coder::new(Cursor::new(input)).unwrap();
+            assert_eq!(coder.sample_frequency, rate);
+            assert_eq!(coder.samples_per_frame, spf);
+            assert_eq!(coder.next_frame().unwrap().unwrap().timestamp, spf as u64);
+            assert_eq!(coder.next_frame().unwrap().unwrap().timestamp, 2 * spf as u64);
+            assert!(coder.next_frame().unwrap().is_none());
+        }
+    }
+
+    fn frames(hdr: [u8;4], len: usize, count: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for _ in 0..count {
+            data.extend(frame(hdr, len));
+        }
+        data
+    }
+
+    fn read_all<I: Input>(stream: &mut Mp3Stream<I>) -> usize {
+        let mut count = 0;
+        while let Some(_) = stream.next_frame().unwrap() {
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn decoder_positions_match_the_coder() {
+        use ogg::{BitstreamDecoder,OggDemux,OgkMux};
+        let hdr = header(2, 1, 8, 0, false);
+        let mut mux = OgkMux::new();
+        mux.add_stream(Box::new(OggMP3Coder::new(Cursor::new(frames(hdr, 208, 5))).unwrap()));
+        let mut file = Vec::new();
+        mux.write_to(&mut file).unwrap();
+
+        let queue = ::std::rc::Rc::new(::std::cell::RefCell::new(None));
+        let stream_queue = queue.clone();
+        let mut demux = OggDemux::new(Cursor::new(file), move |header| OggMp3Decoder::new(header).map(|decoder| {
+            *stream_queue.borrow_mut() = Some(decoder.output());
+            (Box::new(decoder) as Box<BitstreamDecoder>, ())
+        })).unwrap();
+        while !demux.is_eof() {
+            demux.pump_page().unwrap();
+        }
+        let output = queue.borrow_mut().take().unwrap();
+        for i in 0..5 {
+            let timed = output.pop().unwrap();
+            assert_eq!(timed.sample, i * 576);
+            assert_eq!(timed.frame, frame(hdr, 208));
+        }
+        assert!(output.pop().is_none());
+        assert!(output.is_finished());
+        assert_eq!(demux.high_water_mark(), 5 * 576 * 1000_000 / 22050);
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(0xFFFF, b"123456789"), 0xAEE7);
+    }
+
+    #[test]
+    fn rejects_false_sync_in_junk() {
+        let hdr = header(3, 1, 9, 0, false);
+        let mut input = hdr.to_vec();
+        input.extend(vec![0x55; 96]);
+        input.extend(frames(hdr, 417, 3));
+        let mut stream = Mp3Stream::new(Cursor::new(input));
+        assert_eq!(read_all(&mut stream), 3);
+        assert_eq!(stream.stats(), Mp3StreamStats{
+            frames: 3,
+            skipped_bytes: 100,
+            resyncs: 0,
+            crc_errors: 0,
+        });
+    }
+
+    #[test]
+    fn counts_resyncs() {
+        let hdr = header(3, 1, 9, 0, false);
+        let mut input = frames(hdr, 417, 2);
+        input.extend(vec![0xFF; 50]);
+        input.extend(frames(hdr, 417, 2));
+        let mut stream = Mp3Stream::new(Cursor::new(input));
+        assert_eq!(read_all(&mut stream), 4);
+        assert_eq!(stream.stats().skipped_bytes, 50);
+        assert_eq!(stream.stats().resyncs, 1);
+    }
+
+    #[test]
+    fn ignores_id3_tags() {
+        let mut input = b"ID3\x04\x00\x00\x00\x00\x01\x00".to_vec();
+        input.extend(vec![0xFF; 128]);
+        input.extend(frames(header(3, 1, 9, 0, false), 417, 2));
+        input.extend(b"TAG");
+        input.extend(vec![0xFF; 125]);
+        let mut stream = Mp3Stream::new(Cursor::new(input));
+        assert_eq!(read_all(&mut stream), 2);
+        assert_eq!(stream.stats().skipped_bytes, 0);
+        assert_eq!(stream.id3v2_tag().map(|tag| tag.len()), Some(138));
+    }
+
+    #[test]
+    fn reads_id3_text_frames() {
+        // ID3v2.3 with a Latin-1 artist and a UTF-16 title
+        let mut tag = b"ID3\x03\x00\x00\x00\x00\x00\x28".to_vec();
+        tag.extend_from_slice(b"TPE1\x00\x00\x00\x06\x00\x00\x00Abba\x00");
+        tag.extend_from_slice(b"TIT2\x00\x00\x00\x08\x00\x00\x01\xFF\xFEO\x00K\x00");
+        tag.extend_from_slice(&[0; 6]);
+        assert_eq!(id3v2_text(&tag, b"TPE1"), Some("Abba".to_owned()));
+        assert_eq!(id3v2_text(&tag, b"TIT2"), Some("OK".to_owned()));
+        assert_eq!(id3v2_text(&tag, b"TALB"), None);
+    }
+
+    #[test]
+    fn checks_crc() {
+        // MPEG 1 Layer III, protected, stereo: 32 bytes of side info
+        let mut hdr = header(3, 1, 9, 0, false);
+        hdr[1] &= !1;
+        let mut good = frame(hdr, 417);
+        for (i, b) in good[6..38].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let crc = crc16(crc16(0xFFFF, &good[2..4]), &good[6..38]);
+        good[4] = (crc >> 8) as u8;
+        good[5] = crc as u8;
+        assert_eq!(check_crc(&FrameHeader::parse(&good).unwrap(), &good), Some(true));
+        let mut bad = good.clone();
+        bad[10] ^= 0x10;
+        assert_eq!(check_crc(&FrameHeader::parse(&bad).unwrap(), &bad), Some(false));
+
+        // A bad frame in the middle of the stream is kept, but
+        // counted
+        let mut input = good.clone();
+        input.extend(bad.clone());
+        input.extend(good.clone());
+        let mut stream = Mp3Stream::new(Cursor::new(input));
+        assert_eq!(read_all(&mut stream), 3);
+        assert_eq!(stream.stats().crc_errors, 1);
+
+        // ... but one can't be used to find sync
+        let mut input = bad.clone();
+        input.extend(good.clone());
+        let mut stream = Mp3Stream::new(Cursor::new(input));
+        assert_eq!(read_all(&mut stream), 1);
+        assert_eq!(stream.stats().skipped_bytes, 417);
+    }
+
+    // An MPEG 1 Layer III Info frame with a LAME tag recording 576
+    // samples of delay and 1260 of padding
+    fn lame_info_frame() -> Vec<u8> {
+        let mut frame = frame(header(3, 1, 9, 0, false), 417);
+        frame[36..40].copy_from_slice(b"Info");
+        frame[43] = 0x0F; // frames, bytes, TOC and quality
+        let lame = 36 + 8 + 4 + 4 + 100 + 4;
+        frame[lame..lame+9].copy_from_slice(b"LAME3.100");
+        frame[lame+21..lame+24].copy_from_slice(&[0x24, 0x04, 0xEC]);
+        frame
+    }
+
+    #[test]
+    fn parses_lame_tag() {
+        assert_eq!(parse_info_frame(&lame_info_frame()), Some(GaplessInfo{
+            delay: 576 + 529,
+            padding: 1260 - 529,
+        }));
+    }
+
+    #[test]
+    fn parses_bare_xing_and_vbri() {
+        let mut xing = frame(header(3, 1, 9, 0, false), 417);
+        xing[36..40].copy_from_slice(b"Xing");
+        assert_eq!(parse_info_frame(&xing), Some(GaplessInfo::default()));
+
+        let mut vbri = frame(header(3, 1, 9, 0, false), 417);
+        vbri[36..40].copy_from_slice(b"VBRI");
+        vbri[42..44].copy_from_slice(&[0x02, 0x40]);
+        assert_eq!(parse_info_frame(&vbri), Some(GaplessInfo{delay: 576 + 529, padding: 0}));
+
+        assert_eq!(parse_info_frame(&frame(header(3, 1, 9, 0, false), 417)), None);
+    }
+
+    #[test]
+    fn coder_moves_info_frame_to_header() {
+        let mut input = lame_info_frame();
+        for _ in 0..2 {
+            input.extend(frame(header(3, 1, 9, 0, false), 417));
+        }
+        let mut coder = OggMP3Coder::new(Cursor::new(input)).unwrap();
+        coder.set_role(AudioRole::GuideVocal);
+        let mut headers = coder.headers();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[1], lame_info_frame());
+        let header = headers.remove(0);
+        assert_eq!(header.len(), 33);
+        // Stereo, with an info frame header
+        assert_eq!(&header[10..12], &[0x0A, 1]);
+        assert_eq!(&header[24..32], &[0x51, 0x04, 0, 0, 0xDB, 0x02, 0, 0]);
+        assert_eq!(AudioRole::from_header(&header), AudioRole::GuideVocal);
+        assert_eq!(coder.next_frame().unwrap().unwrap().timestamp, 1152);
+        assert_eq!(coder.next_frame().unwrap().unwrap().timestamp, 2304);
+        assert!(coder.next_frame().unwrap().is_none());
+        // The first sample after the delay is at time 0
+        assert_eq!(coder.map_granule(1105), 0);
+        assert_eq!(coder.map_granule(1105 + 44100), 1000_000);
+    }
+
+    #[test]
+    fn reads_header_fields_by_version() {
+        let mut input = lame_info_frame();
+        input.extend(frame(header(3, 1, 9, 0, false), 417));
+        let mut coder = OggMP3Coder::new(Cursor::new(input)).unwrap();
+        coder.set_role(AudioRole::GuideVocal);
+        let mut header = coder.headers().remove(0);
+        let parsed = Mp3Header::parse(&header).unwrap();
+        assert_eq!(parsed.version, (0, 3));
+        assert_eq!(parsed.aux_headers, 1);
+        assert_eq!(parsed.gapless, coder.gapless_info());
+
+        // A later minor version may add fields; they're ignored
+        header[9] = MP3_MINOR_VERSION + 1;
+        header.extend_from_slice(&[1, 2, 3]);
+        let parsed = Mp3Header::parse(&header).unwrap();
+        assert_eq!(parsed.version, (0, 4));
+        assert_eq!(parsed.role, AudioRole::GuideVocal);
+
+        // Fields from later versions than the header's are not read
+        header[9] = 0;
+        let parsed = Mp3Header::parse(&header).unwrap();
+        assert_eq!((parsed.gapless, parsed.role), (GaplessInfo::default(), AudioRole::Main));
+        header[9] = 1;
+        assert_eq!(Mp3Header::parse(&header[..24]).err(), Some(HeaderError::Malformed("header is too short")));
+
+        header[8] = 1;
+        assert_eq!(Mp3Header::parse(&header).err(), Some(HeaderError::UnsupportedVersion{found: (1, 1), supported: 0}));
+        assert!(OggMp3Decoder::new(&header).is_none());
     }
 }
@@ -1,10 +1,13 @@
 use std::error::Error;
 use std::fmt::{self, Display};
-use std::io::{self,Write,Read};
+use std::io::{self,Write,Read,Seek,SeekFrom};
 use std::collections;
 use rand;
 
-use util;
+use memchr::memmem;
+use util::{self,Input,ReadInput,SliceInput};
+use registry::Registry;
+use index::{self,IndexEntry,SeekIndex};
 
 #[derive(Debug)]
 pub enum StreamError {
@@ -139,6 +142,7 @@ bitflags!{
 enum ParseResult<V> {
     No, // This is not a valid value
     InsufficientNoms(usize), // Needs at least N total bytes
+    Yuck, // Looks like a page, but it's damaged
     Yay(usize, V), // Successful parse. Consumed N bytes
 }
 
@@ -189,17 +193,25 @@ impl<'a> RefPage<'a> {
         let target_crc = LittleEndian::read_u32(&buf[22..26]);
         if crc != target_crc {
             // CRC failed
-            return ParseResult::No;
+            return ParseResult::Yuck;
         }
 
-        ParseResult::Yay(target_size, RefPage{
+        ParseResult::Yay(target_size, Self::parse_unchecked(&buf[..target_size]))
+    }
+
+    /// Read a page that `parse` has already accepted, exactly as long
+    /// as it said, without checking it again
+    fn parse_unchecked(buf: &'a [u8]) -> Self {
+        use byteorder::{LittleEndian,ByteOrder};
+        let lacing_count = buf[26] as usize;
+        RefPage{
             flags: PageFlags::from_bits_truncate(buf[5]),
             granule_position: LittleEndian::read_u64(&buf[6..14]),
             stream_serial: LittleEndian::read_u32(&buf[14..18]),
             page_sequence: LittleEndian::read_u32(&buf[18..22]),
-            segment_table: lacing,
-            content: &buf[27+lacing_count..target_size],
-        })
+            segment_table: &buf[27..27+lacing_count],
+            content: &buf[27+lacing_count..],
+        }
     }
 
     fn packets(&self) -> Packets {
@@ -335,6 +347,11 @@ impl Page {
     pub fn content_size(&self) -> usize {
         self.segment_table.iter().map(|x| *x as usize).sum()
     }
+
+    /// The size of the page once written
+    pub fn size(&self) -> usize {
+        27 + self.segment_table.len() + self.content.len()
+    }
 }
 
 // Page packer
@@ -346,6 +363,9 @@ pub struct PagePacker {
 
     stream_serial: u32,
     page_sequence: u32,
+
+    /// Granule position of the last packet added
+    last_granule: u64,
 }
 
 impl PagePacker {
@@ -357,6 +377,7 @@ impl PagePacker {
             active_page: Some(start_page),
             stream_serial: serial,
             page_sequence: 0,
+            last_granule: 0,
         }
     }
 
@@ -388,6 +409,7 @@ impl PagePacker {
                 self.emit()
             }
         }
+        self.last_granule = packet.timestamp;
         // TODO: Limit page size?
         //if self.get_active().content_size() > 8192 {
         //self.emit()
@@ -395,7 +417,13 @@ impl PagePacker {
     }
 
     pub fn close(&mut self) {
-        self.get_active().flags |= PAGE_EOS;
+        let last_granule = self.last_granule;
+        let page = self.get_active();
+        if page.segment_table.is_empty() {
+            // An empty EOS page still marks the end of the last packet
+            page.granule_position = last_granule;
+        }
+        page.flags |= PAGE_EOS;
         self.emit();
         assert!(self.active_page.is_none());
     }
@@ -419,103 +447,485 @@ pub trait BitstreamCoder {
 
     /// Map a granule position to an absolute timestamp in µs
     fn map_granule(&self, u64) -> u64;
+
+    /// Describe any problems found in the input so far, such as
+    /// damaged data that had to be skipped. These are meant for the
+    /// user; muxing continues regardless.
+    fn warnings(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Identifies a stream within an `IncrementalMux`. Streams are
+/// numbered from 0 in the order that they were added.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub struct StreamId(usize);
+
+impl StreamId {
+    pub fn index(&self) -> usize {
+        self.0
+    }
 }
 
 struct MuxStream {
     bitstream: Box<BitstreamCoder>,
     packer: PagePacker,
+    /// Finished pages waiting to be written, with their timestamps in µs
+    ready: collections::VecDeque<(u64, Page)>,
+    /// Timestamp of the first packet on the active page
+    page_start: Option<u64>,
+    /// Timestamp of the most recent packet
+    last_time: u64,
+    closed: bool,
 }
 
 impl MuxStream {
-    fn pump(&mut self) -> io::Result<()> {
-        while self.packer.peek_next().is_none() && !self.packer.is_closed() {
-            if let Some(frame) = try!(self.bitstream.next_frame()) {
-                self.packer.add_packet(&frame);
-                // TODO: Emit early for lower bufferring needs
+    /// Move finished pages out of the packer. Pages on which no packet
+    /// ends have no granule position of their own, so they are given
+    /// the timestamp of the packet that spans them.
+    fn collect_pages(&mut self, spanning_time: u64) {
+        while let Some(page) = self.packer.take_next() {
+            let time = if page.granule_position == !0 {
+                spanning_time
             } else {
-                self.packer.close();
-            }
+                self.bitstream.map_granule(page.granule_position)
+            };
+            self.ready.push_back((time, page));
         }
-        Ok(())
     }
+}
 
-    fn is_live(&self) -> bool {
-        self.packer.peek_next().is_some() || !self.packer.is_closed()
-    }
+/// Where a muxer gets the serial numbers of new streams
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum SerialSource {
+    /// Random, as the Ogg spec recommends
+    Random,
+    /// A hash of each stream's headers and this key, such as a hash
+    /// of the input files. Muxing the same streams with the same key
+    /// always gives the same file.
+    Hashed(u64),
 }
 
-// Muxer
-pub struct OgkMux {
-    streams: Vec<MuxStream>,
+impl SerialSource {
+    /// The serial for a stream, trying again until it isn't one of
+    /// `used`
+    pub fn pick(self, headers: &[Vec<u8>], used: &collections::HashSet<u32>) -> u32 {
+        use byteorder::{ByteOrder,LittleEndian};
+        let mut hash = match self {
+            SerialSource::Random => {
+                let mut serial = rand::random();
+                while used.contains(&serial) {
+                    serial = rand::random();
+                }
+                return serial;
+            },
+            SerialSource::Hashed(key) => {
+                let mut buf = [0; 8];
+                LittleEndian::write_u64(&mut buf, key);
+                util::fnv1a(util::FNV_OFFSET, &buf)
+            },
+        };
+        for header in headers {
+            let mut len = [0; 8];
+            LittleEndian::write_u64(&mut len, header.len() as u64);
+            hash = util::fnv1a(util::fnv1a(hash, &len), header);
+        }
+        loop {
+            let serial = (hash ^ hash >> 32) as u32;
+            if !used.contains(&serial) {
+                return serial;
+            }
+            hash = util::fnv1a(hash, &[0]);
+        }
+    }
 }
 
-impl Default for OgkMux {
-    fn default() -> Self { Self::new() }
+/// A muxer that is fed packets as they become available, writing
+/// pages out as soon as the interleaving allows.
+///
+/// A page is written once every open stream has caught up to it, or
+/// once it is more than `interleave_window` µs behind the newest
+/// packet, so a stalled stream can't hold up the others forever.
+pub struct IncrementalMux<W> {
+    writer: W,
+    streams: Vec<MuxStream>,
+    /// Serial numbers that must not be assigned to new streams
+    excluded_serials: collections::HashSet<u32>,
+    serial_source: SerialSource,
+    headers_written: bool,
+    max_page_duration: u64,
+    interleave_window: u64,
+    /// Bytes written so far
+    written: u64,
+    /// The OggIDX stream, if one is being written
+    index: Option<PagePacker>,
+    seek_index: SeekIndex,
 }
 
-impl OgkMux {
-    pub fn new() -> Self {
-        OgkMux{
+impl <W: Write> IncrementalMux<W> {
+    pub fn new(writer: W) -> Self {
+        IncrementalMux{
+            writer: writer,
             streams: Vec::new(),
+            excluded_serials: collections::HashSet::new(),
+            serial_source: SerialSource::Random,
+            headers_written: false,
+            max_page_duration: 500_000,
+            interleave_window: 1000_000,
+            written: 0,
+            index: None,
+            seek_index: SeekIndex::new(),
         }
     }
 
-    pub fn add_stream(&mut self, stream: Box<BitstreamCoder>) {
-        let serial = rand::random();
+    /// Close a page once the packets on it span this many µs. 0 puts
+    /// every packet on its own page. Defaults to 500ms.
+    pub fn set_max_page_duration(&mut self, duration: u64) {
+        self.max_page_duration = duration;
+    }
+
+    /// How far, in µs, a stream may fall behind the newest packet
+    /// before its pages stop holding back the others. Defaults to 1s.
+    pub fn set_interleave_window(&mut self, window: u64) {
+        self.interleave_window = window;
+    }
+
+    /// Never assign these serial numbers. Every logical stream in a
+    /// chained file needs its own serial, so pass the serials used
+    /// by earlier links here.
+    pub fn exclude_serials<I: IntoIterator<Item=u32>>(&mut self, serials: I) {
+        self.excluded_serials.extend(serials);
+    }
+
+    /// Where the serial numbers of streams added from now on come
+    /// from. Defaults to `SerialSource::Random`.
+    pub fn set_serial_source(&mut self, source: SerialSource) {
+        self.serial_source = source;
+    }
+
+    /// Add an OggIDX stream, listing where every page of the other
+    /// streams is, so that demuxers can seek straight to them. The
+    /// index itself is written by `finish`. Call this once the
+    /// streams have been added, before any data is written.
+    pub fn write_index(&mut self) {
+        assert!(!self.headers_written, "The index must be added before any data is written");
+        if self.index.is_none() {
+            let serial = self.serial_source.pick(&[index::header()], &self.excluded_serials);
+            self.excluded_serials.insert(serial);
+            self.index = Some(PagePacker::new(serial));
+        }
+    }
+
+    /// The serial numbers of all streams, in the order they were added
+    pub fn serials(&self) -> Vec<u32> {
+        self.streams.iter().map(|stream| stream.packer.stream_serial).collect()
+    }
+
+    /// The serial number of the OggIDX stream, if there is one
+    pub fn index_serial(&self) -> Option<u32> {
+        self.index.as_ref().map(|index| index.stream_serial)
+    }
+
+    /// Add a stream. All streams must be added before any packets are
+    /// pushed.
+    pub fn add_stream(&mut self, stream: Box<BitstreamCoder>) -> StreamId {
+        let serial = self.serial_source.pick(&stream.headers(), &self.excluded_serials);
+        self.push_stream(stream, serial)
+    }
+
+    /// Add a stream with a serial number of the caller's choosing.
+    /// Fails if the serial is already used or excluded.
+    pub fn add_stream_with_serial(&mut self, stream: Box<BitstreamCoder>, serial: u32) -> io::Result<StreamId> {
+        if self.excluded_serials.contains(&serial) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Serial {:08x} is already in use", serial)));
+        }
+        Ok(self.push_stream(stream, serial))
+    }
+
+    fn push_stream(&mut self, stream: Box<BitstreamCoder>, serial: u32) -> StreamId {
+        assert!(!self.headers_written, "Streams must be added before any data is written");
+        self.excluded_serials.insert(serial);
         self.streams.push(MuxStream{
             bitstream: stream,
             packer: PagePacker::new(serial),
-        })
+            ready: collections::VecDeque::new(),
+            page_start: None,
+            last_time: 0,
+            closed: false,
+        });
+        StreamId(self.streams.len() - 1)
+    }
+
+    pub fn coder(&self, id: StreamId) -> &BitstreamCoder {
+        &*self.streams[id.0].bitstream
+    }
+
+    pub fn coder_mut(&mut self, id: StreamId) -> &mut BitstreamCoder {
+        &mut *self.streams[id.0].bitstream
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
     }
 
-    pub fn write_to<W: io::Write>(&mut self, mut w: W) -> io::Result<()> {
-        // Write headers...
+    /// The timestamp of the last packet pushed to a stream, in µs
+    pub fn stream_time(&self, id: StreamId) -> u64 {
+        self.streams[id.0].last_time
+    }
+
+    /// Write the header pages of every stream. This happens
+    /// automatically when the first packet is pushed.
+    pub fn write_headers(&mut self) -> io::Result<()> {
+        if self.headers_written {
+            return Ok(());
+        }
+        self.headers_written = true;
+
+        // The first header of each stream goes on a BOS page of its own...
         for stream in &mut self.streams {
-            let headers = stream.bitstream.headers();
-            let first_packet = try!(stream.bitstream.next_frame());
-            let header_count = headers.len();
-            let mut it = headers.into_iter();
-            stream.packer.add_packet(&Packet{content: it.next().expect("Streams must contain at least one header"), timestamp: 0});
-            // Produce additional headers, if any
-            if header_count > 1 {
-                stream.packer.emit();
-                for header in it {
-                    stream.packer.add_packet(&Packet{content: header, timestamp: 0});
-                }
+            let mut headers = stream.bitstream.headers().into_iter();
+            stream.packer.add_packet(&Packet{content: headers.next().expect("Streams must contain at least one header"), timestamp: 0});
+            stream.packer.emit();
+            let page = stream.packer.take_next().unwrap();
+            try!(page.write_to(&mut self.writer));
+            self.written += page.size() as u64;
+            // ...and the rest must finish before the first data page
+            let mut secondary = false;
+            for header in headers {
+                stream.packer.add_packet(&Packet{content: header, timestamp: 0});
+                secondary = true;
             }
-            // Close the stream
-            if let Some(packet) = first_packet {
+            if secondary {
                 stream.packer.emit();
-                stream.packer.add_packet(&packet);
-            } else {
-                stream.packer.close();
             }
-            try!(stream.packer.take_next().unwrap().write_to(&mut w));
         }
 
-        // Write the remaining header packets
+        if let Some(ref mut index) = self.index {
+            index.add_packet(&Packet{content: index::header(), timestamp: 0});
+            index.emit();
+            let page = index.take_next().unwrap();
+            try!(page.write_to(&mut self.writer));
+            self.written += page.size() as u64;
+        }
+
         for stream in &mut self.streams {
-            while let Some(frame) = stream.packer.take_next() {
-                try!(frame.write_to(&mut w));
+            while let Some(page) = stream.packer.take_next() {
+                try!(page.write_to(&mut self.writer));
+                self.written += page.size() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a packet to a stream. Packets within a stream must be
+    /// pushed in order.
+    pub fn push_packet(&mut self, id: StreamId, packet: Packet) -> io::Result<()> {
+        try!(self.write_headers());
+        {
+            let max_page_duration = self.max_page_duration;
+            let stream = &mut self.streams[id.0];
+            assert!(!stream.closed, "Attempted to push a packet to a closed stream");
+            let time = stream.bitstream.map_granule(packet.timestamp);
+            if stream.page_start.is_none() {
+                stream.page_start = Some(time);
+            }
+            stream.packer.add_packet(&packet);
+            if stream.packer.peek_next().is_some() {
+                // The packet spilled onto a new page
+                stream.page_start = Some(time);
+            }
+            if time.saturating_sub(stream.page_start.unwrap()) >= max_page_duration {
+                stream.packer.emit();
+                stream.page_start = None;
             }
+            stream.last_time = ::std::cmp::max(stream.last_time, time);
+            stream.collect_pages(time);
         }
+        self.write_ready(None)
+    }
 
+    /// Write out every page that ends at or before `time` (in µs),
+    /// closing partially filled pages if necessary, and flush the
+    /// writer.
+    pub fn flush_until(&mut self, time: u64) -> io::Result<()> {
+        try!(self.write_headers());
         for stream in &mut self.streams {
-            try!(stream.pump());
+            if stream.closed || stream.page_start.map_or(true, |start| start > time) {
+                continue;
+            }
+            stream.packer.emit();
+            stream.page_start = None;
+            let last_time = stream.last_time;
+            stream.collect_pages(last_time);
         }
+        try!(self.write_ready(Some(time)));
+        self.writer.flush()
+    }
+
+    /// Mark the end of a stream.
+    pub fn close_stream(&mut self, id: StreamId) -> io::Result<()> {
+        try!(self.write_headers());
+        {
+            let stream = &mut self.streams[id.0];
+            if stream.closed {
+                return Ok(());
+            }
+            stream.packer.close();
+            stream.closed = true;
+            let last_time = stream.last_time;
+            stream.collect_pages(last_time);
+        }
+        self.write_ready(None)
+    }
+
+    /// Close every stream, write all remaining pages and the index,
+    /// if any, and return the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        for i in 0..self.streams.len() {
+            try!(self.close_stream(StreamId(i)));
+        }
+        try!(self.write_ready(Some(!0)));
+        if let Some(mut index) = self.index.take() {
+            index.add_packet(&Packet{content: self.seek_index.to_bytes(), timestamp: 0});
+            index.close();
+            while let Some(page) = index.take_next() {
+                try!(page.write_to(&mut self.writer));
+            }
+        }
+        try!(self.writer.flush());
+        Ok(self.writer)
+    }
 
+    /// Write pages in timestamp order for as long as the page with
+    /// the lowest timestamp may be written.
+    fn write_ready(&mut self, force_until: Option<u64>) -> io::Result<()> {
         loop {
-            self.streams.retain(MuxStream::is_live);
-            if self.streams.is_empty() {
-                break;
+            let low_water = self.streams.iter().filter(|s| !s.closed).map(|s| s.last_time).min();
+            let newest = self.streams.iter().map(|s| s.last_time).max().unwrap_or(0);
+            let next = self.streams.iter().enumerate()
+                .filter_map(|(i, s)| s.ready.front().map(|&(time, _)| (time, i)))
+                .min();
+            let (time, i) = match next {
+                Some(next) => next,
+                None => return Ok(()),
+            };
+            let writable = low_water.map_or(true, |low| time <= low)
+                || newest - ::std::cmp::min(time, newest) >= self.interleave_window
+                || force_until.map_or(false, |until| time <= until);
+            if !writable {
+                return Ok(());
+            }
+            let (_, page) = self.streams[i].ready.pop_front().unwrap();
+            if self.index.is_some() && page.granule_position != !0 {
+                self.seek_index.add(page.stream_serial, IndexEntry{offset: self.written, granule: page.granule_position});
             }
-            if let Some(stream) = self.streams.iter_mut().min_by_key(|stream| stream.bitstream.map_granule(stream.packer.peek_next().unwrap().granule_position)) {
-                try!(stream.packer.take_next().unwrap().write_to(&mut w));
-                try!(stream.pump());
+            try!(page.write_to(&mut self.writer));
+            self.written += page.size() as u64;
+        }
+    }
+}
+
+// Muxer
+pub struct OgkMux {
+    /// With the serial each was given, if any
+    streams: Vec<(Box<BitstreamCoder>, Option<u32>)>,
+    serial_source: SerialSource,
+    /// Streams written by earlier calls to write_to
+    stream_count: usize,
+    /// Serials used by earlier links
+    used_serials: Vec<u32>,
+    write_index: bool,
+    warnings: Vec<(usize, String)>,
+}
+
+impl Default for OgkMux {
+    fn default() -> Self { Self::new() }
+}
+
+impl OgkMux {
+    pub fn new() -> Self {
+        OgkMux{
+            streams: Vec::new(),
+            serial_source: SerialSource::Random,
+            stream_count: 0,
+            used_serials: Vec::new(),
+            write_index: false,
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn add_stream(&mut self, stream: Box<BitstreamCoder>) {
+        self.streams.push((stream, None));
+    }
+
+    /// Add a stream with a serial number of the caller's choosing.
+    /// `write_to` fails if it's used by another stream.
+    pub fn add_stream_with_serial(&mut self, stream: Box<BitstreamCoder>, serial: u32) {
+        self.streams.push((stream, Some(serial)));
+    }
+
+    /// Where the serial numbers of streams without one come from. With
+    /// `SerialSource::Hashed`, the output depends only on the input
+    /// streams. Defaults to `SerialSource::Random`.
+    pub fn set_serial_source(&mut self, source: SerialSource) {
+        self.serial_source = source;
+    }
+
+    /// Give each link an OggIDX stream, so that demuxers can seek
+    /// without searching the file. Off by default.
+    pub fn set_write_index(&mut self, write_index: bool) {
+        self.write_index = write_index;
+    }
+
+    /// Warnings reported by streams that have finished muxing. Each
+    /// is tagged with the index of its stream, counting from 0 in the
+    /// order that the streams were added, across all links.
+    pub fn warnings(&self) -> &[(usize, String)] {
+        &self.warnings
+    }
+
+    /// Write all streams added since the last call as one link.
+    /// Calling this again with the same writer produces a chained
+    /// stream, with one link for each call.
+    pub fn write_to<W: io::Write>(&mut self, w: W) -> io::Result<()> {
+        // Everything is available up front, so fill pages completely
+        // and never give up waiting on a stream.
+        let mut mux = IncrementalMux::new(w);
+        mux.set_max_page_duration(!0);
+        mux.set_interleave_window(!0);
+        mux.exclude_serials(self.used_serials.iter().cloned());
+        mux.set_serial_source(self.serial_source);
+        let base = self.stream_count;
+        // Chosen serials go first, so that generated ones avoid them
+        let mut streams : Vec<_> = self.streams.drain(..).enumerate().collect();
+        streams.sort_by_key(|&(i, (_, serial))| (serial.is_none(), i));
+        let mut open = Vec::new();
+        for (i, (stream, serial)) in streams {
+            let id = match serial {
+                Some(serial) => try!(mux.add_stream_with_serial(stream, serial)),
+                None => mux.add_stream(stream),
+            };
+            open.push((i, id));
+        }
+        open.sort_by_key(|&(i, _)| i);
+        if self.write_index {
+            mux.write_index();
+        }
+
+        // Pull from whichever stream is furthest behind
+        while let Some((i, id)) = open.iter().cloned().min_by_key(|&(_, id)| mux.stream_time(id)) {
+            if let Some(packet) = try!(mux.coder_mut(id).next_frame()) {
+                try!(mux.push_packet(id, packet));
             } else {
-                panic!("This should be unreachable");
+                self.warnings.extend(mux.coder(id).warnings().into_iter().map(|w| (base + i, w)));
+                try!(mux.close_stream(id));
+                open.retain(|&(_, open_id)| open_id != id);
             }
         }
+        self.stream_count += mux.serials().len();
+        self.used_serials.extend(mux.serials());
+        self.used_serials.extend(mux.index_serial());
+        try!(mux.finish());
         Ok(())
     }
 }
@@ -535,6 +945,12 @@ pub trait BitstreamDecoder {
     /// Called for each packet in the stream. Returns the granule position of this packet
     fn process_packet(&mut self, packet: &[u8], last_granule: u64) -> u64;
 
+    /// Called after the packets of each page other than the first,
+    /// with the page's granule position (!0 if no packet ends on it).
+    /// Decoders that need to keep timing across lost pages can use
+    /// this to find their place.
+    fn page_done(&mut self, _granule: u64) {}
+
     /// There was a gap in the underlying page stream. May be called
     /// multiple times in a row; should therefore be idempotent.
     // This will happen when we regain sync on a page that contains a
@@ -563,6 +979,14 @@ struct StreamState<Desc> {
 
     /// EOS packet seen
     finished: bool,
+
+    /// The header packets, kept so the decoder can be set up again
+    /// after seeking
+    headers: Vec<Vec<u8>>,
+
+    /// Set after seeking, until the first page with a granule
+    /// position tells us where we are
+    resuming: bool,
     
     /// This holds user data associated with the stream, such as decode buffers
     user_data: Desc,
@@ -570,10 +994,15 @@ struct StreamState<Desc> {
 
 impl <Desc> StreamState<Desc> {
     pub fn process_page(&mut self, page: RefPage) -> Result<(), StreamError> {
-        let had_gap = page.page_sequence != self.last_page_seq + 1;
+        if self.resuming {
+            self.resume(page);
+            return Ok(());
+        }
+        let had_gap = page.page_sequence != self.last_page_seq.wrapping_add(1);
         if had_gap {
             if page.segment_table.iter().filter(|x| **x != 255).count() == 0 {
                 // This page doesn't have any packets that finish on it.
+                self.decoder.page_done(page.granule_position);
                 return Ok(());
             }
             self.decoder.notice_gap();
@@ -598,6 +1027,7 @@ impl <Desc> StreamState<Desc> {
             } else {
                 // Get a reference to the packet body
                 let packet_ref = if packet_continued {
+                    self.partial.extend_from_slice(packet);
                     &self.partial
                 } else {
                     packet
@@ -606,13 +1036,16 @@ impl <Desc> StreamState<Desc> {
                 // process it
                 if self.headers_remaining > 0 {
                     self.headers_remaining -= 1;
-                    self.decoder.process_header(packet);
+                    self.headers.push(packet_ref.to_owned());
+                    self.decoder.process_header(packet_ref);
                 } else {
                     self.hwm = self.decoder.process_packet(packet_ref, self.hwm);
                 }
             }
         }
 
+        self.decoder.page_done(page.granule_position);
+
         if page.flags.intersects(PAGE_EOS) {
             self.decoder.finish();
             self.finished = true;
@@ -620,66 +1053,284 @@ impl <Desc> StreamState<Desc> {
 
         Ok(())
     }
+
+    /// Pick up from the first page after seeking. The packets that
+    /// end on it come before the place we seeked to; only a packet
+    /// that carries on to the next page is kept.
+    fn resume(&mut self, page: RefPage) {
+        if page.granule_position == !0 {
+            // No telling where we are yet
+            return;
+        }
+        self.resuming = false;
+        self.last_page_seq = page.page_sequence;
+        self.partial.clear();
+        let ends_partial = page.segment_table.last().map_or(false, |&len| len == 255);
+        if ends_partial {
+            if let Some((packet, _)) = page.packets().last() {
+                self.partial.extend_from_slice(packet);
+            }
+        }
+        self.hwm = page.granule_position;
+        self.decoder.page_done(page.granule_position);
+        if page.flags.intersects(PAGE_EOS) {
+            self.decoder.finish();
+            self.finished = true;
+        }
+    }
 }
 
 
-pub struct OggPageSource<R> {
-    buffer: util::ShiftBuffer,
-    reader: R,
+/// Counts of the damage a demuxer has run into
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct DemuxDiagnostics {
+    /// Pages that were dropped because their CRC didn't match
+    pub crc_failures: u64,
+    /// Bytes skipped while looking for the next page
+    pub resync_bytes: u64,
+    /// The number of times pages were found again after skipping bytes
+    pub resyncs: u64,
+    /// Places where a stream's page sequence numbers jumped
+    pub sequence_gaps: u64,
+    /// Pages for streams that never had a BOS page
+    pub orphan_pages: u64,
+    /// BOS pages that turned up after a link's headers but before all
+    /// its streams ended. Their streams are ignored.
+    pub misplaced_bos_pages: u64,
+    /// Recoverable errors that were ignored in tolerant mode
+    pub recovered_errors: u64,
+}
+
+pub struct OggPageSource<I> {
+    input: I,
     dead_bytes: usize,
     eof: bool,
+    crc_failures: u64,
+    resync_bytes: u64,
+    resyncs: u64,
+    /// Set when bytes have been skipped since the last page
+    lost_sync: bool,
+    /// Bytes consumed from the input so far
+    position: u64,
+    /// Where the last page found starts
+    page_offset: u64,
 }
 
-// TODO: When nonlexical lifetimes land, kill this with fire.
-unsafe fn unalias<'a,'b, E: ?Sized>(elem: &'a E) -> &'b E {
-    &*(elem as *const E)
+impl <I> OggPageSource<I> {
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    /// Pages dropped so far because their CRC didn't match
+    pub fn crc_failures(&self) -> u64 {
+        self.crc_failures
+    }
+
+    /// Bytes skipped so far while looking for the next page
+    pub fn resync_bytes(&self) -> u64 {
+        self.resync_bytes
+    }
+
+    /// The number of times pages were found again after skipping
+    /// bytes
+    pub fn resyncs(&self) -> u64 {
+        self.resyncs
+    }
+
+    /// Where the page last returned starts, in bytes from where
+    /// reading began
+    pub fn page_offset(&self) -> u64 {
+        self.page_offset
+    }
 }
 
-impl <R: Read> OggPageSource<R> {
-    pub fn next_page(&mut self) -> Result<Option<RefPage>, StreamError> {
-        loop {
-            self.buffer.consume(self.dead_bytes);
-            self.dead_bytes = 0;
-            if try!(self.buffer.fill_max(&mut self.reader)) == 0 {
-                self.eof = true;
-                return Ok(None);
-            }
-            for i in 0..self.buffer.len() - 5 {
-                // Try to parse...  The unsafe unalias simply divorces
-                // the borrow of buffer from the lifetime of this
-                // function, so that the loop still works.
-                match RefPage::parse(unsafe{unalias(&self.buffer[i..])}) {
-                    ParseResult::No => { println!("Skipping"); continue; },
-                    ParseResult::InsufficientNoms(_) => {
-                        if i == 0 {
-                            if self.buffer.is_empty() {
-                                self.eof = true;
-                                return Ok(None);
-                            }
-                            //println!("EOF at offset {}; need {} had {} (contents {})", self.buffer.offset(), n, self.buffer.len(), &self.buffer[..].iter().map(|x| format!("{:02x}", x)).collect::<Vec<String>>().join(""));
-                            // We'll never be able to complete this page
-                            return Err(StreamError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "Incomplete page")));
-                        }
-                        self.dead_bytes = i;
-                        break;
-                    },
-                    ParseResult::Yay(n, page_res) => {
-                        // handle the page
-                        self.dead_bytes = i + n;
-                        return Ok(Some(page_res));
+impl <R: Read> OggPageSource<ReadInput<R>> {
+    /// Read raw pages from `reader`, skipping anything that isn't a
+    /// valid page
+    pub fn new(reader: R) -> Self {
+        Self::with_input(ReadInput::new(reader, 65536))
+    }
+
+    /// Copy as much of data into the buffer as will fit. Returns the
+    /// number of bytes taken.
+    pub fn feed(&mut self, data: &[u8]) -> usize {
+        self.drop_dead_bytes();
+        self.input.feed(data)
+    }
+
+    /// Forget what's buffered, as after seeking the reader to
+    /// `position`
+    fn reset(&mut self, position: u64) {
+        self.input.reset();
+        self.dead_bytes = 0;
+        self.lost_sync = false;
+        self.eof = false;
+        self.position = position;
+    }
+
+    /// Where the reader should be, going by what has been taken from
+    /// it
+    fn read_position(&self) -> u64 {
+        self.position + self.input.data().len() as u64
+    }
+}
+
+impl <'a> OggPageSource<SliceInput<'a>> {
+    /// Parse pages straight out of memory, without copying
+    pub fn from_slice(data: &'a [u8]) -> Self {
+        Self::with_input(SliceInput::new(data))
+    }
+}
+
+impl <I: Input> OggPageSource<I> {
+    pub fn with_input(input: I) -> Self {
+        OggPageSource{
+            input: input,
+            dead_bytes: 0,
+            eof: false,
+            crc_failures: 0,
+            resync_bytes: 0,
+            resyncs: 0,
+            lost_sync: false,
+            position: 0,
+            page_offset: 0,
+        }
+    }
+
+    fn drop_dead_bytes(&mut self) {
+        self.input.consume(self.dead_bytes);
+        self.position += self.dead_bytes as u64;
+        self.dead_bytes = 0;
+    }
+
+    /// Find the next page in the data that has already been
+    /// buffered, returning where it is
+    fn find_page(&mut self, at_eof: bool) -> Result<Option<(usize, usize)>, StreamError> {
+        self.drop_dead_bytes();
+        let data = self.input.data();
+        if data.is_empty() {
+            self.eof = at_eof;
+            return Ok(None);
+        }
+        let finder = memmem::Finder::new(b"OggS");
+        let mut start = 0;
+        // Only places that start with the capture pattern are worth
+        // parsing, and only those that parse get their CRC checked
+        while let Some(found) = finder.find(&data[start..]) {
+            let i = start + found;
+            match RefPage::parse(&data[i..]) {
+                ParseResult::No => (),
+                ParseResult::Yuck => {
+                    // Resync from the next byte, in case the length
+                    // was what got damaged
+                    self.crc_failures += 1;
+                },
+                ParseResult::InsufficientNoms(_) => {
+                    if i == 0 && at_eof {
+                        // We'll never be able to complete this page
+                        return Err(StreamError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "Incomplete page")));
                     }
+                    self.skip(i);
+                    return Ok(None);
+                },
+                ParseResult::Yay(n, _) => {
+                    self.skip(i);
+                    if self.lost_sync {
+                        self.resyncs += 1;
+                        self.lost_sync = false;
+                    }
+                    self.dead_bytes = i + n;
+                    self.page_offset = self.position + i as u64;
+                    return Ok(Some((i, n)));
                 }
             }
+            start = i + 1;
         }
+        if at_eof {
+            // Nothing but junk left
+            self.skip(data.len());
+            self.eof = true;
+        } else {
+            // Keep what could be the start of a capture pattern
+            self.skip(data.len().saturating_sub(3));
+        }
+        Ok(None)
     }
 
-    pub fn is_eof(&self) -> bool {
-        self.eof
+    /// Note that `count` bytes at the front of the buffer aren't part
+    /// of a page, and arrange to drop them
+    fn skip(&mut self, count: usize) {
+        if count > 0 {
+            self.dead_bytes = count;
+            self.resync_bytes += count as u64;
+            self.lost_sync = true;
+        }
+    }
+
+    /// The page that `find_page` found
+    fn page_at(&self, (start, len): (usize, usize)) -> RefPage {
+        RefPage::parse_unchecked(&self.input.data()[start..start + len])
+    }
+
+    /// Parse the next page out of the data that has already been
+    /// buffered. Returns None if more data is needed, or if at_eof is
+    /// set and there are no more pages.
+    pub fn buffered_page(&mut self, at_eof: bool) -> Result<Option<RefPage>, StreamError> {
+        match try!(self.find_page(at_eof)) {
+            Some(found) => Ok(Some(self.page_at(found))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn next_page(&mut self) -> Result<Option<RefPage>, StreamError> {
+        match try!(self.find_next_page()) {
+            Some(found) => Ok(Some(self.page_at(found))),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `next_page`, along with where the page starts, in bytes
+    /// from where reading began
+    pub fn next_page_at(&mut self) -> Result<Option<(u64, RefPage)>, StreamError> {
+        match try!(self.find_next_page()) {
+            Some(found) => Ok(Some((self.page_offset, self.page_at(found)))),
+            None => Ok(None),
+        }
+    }
+
+    /// Read until the next page turns up, returning where it is
+    fn find_next_page(&mut self) -> Result<Option<(usize, usize)>, StreamError> {
+        loop {
+            self.drop_dead_bytes();
+            try!(self.input.fill());
+            let at_eof = self.input.is_complete();
+            if let Some(found) = try!(self.find_page(at_eof)) {
+                return Ok(Some(found));
+            }
+            if self.eof {
+                return Ok(None);
+            }
+        }
     }
 }
 
 pub type StreamInitFn<StreamDesc> = Fn(&[u8]) -> Option<(Box<BitstreamDecoder>, StreamDesc)>;
 
+/// Something the caller of a demuxer should know about
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum DemuxEvent {
+    /// The BOS pages of a new chained link have been read, and the
+    /// descriptors for its streams are available from `streams()`.
+    /// `start_time` is when the link begins, in µs; timestamps from
+    /// the demuxer continue across links, while the link's own
+    /// decoders count from 0.
+    NewLink {
+        link: u32,
+        start_time: u64,
+        streams: Vec<u32>,
+    },
+}
+
 struct StreamMapper<StreamDesc>{
     streams: collections::HashMap<u32, StreamState<StreamDesc>>,
     discard_streams: collections::HashSet<u32>,
@@ -690,6 +1341,29 @@ struct StreamMapper<StreamDesc>{
     /// as in StreamState
     hwm: u64,
 
+    /// The current link of a chained stream, counting from 0
+    link: u32,
+
+    /// The time at which the current link started, in µs
+    time_base: u64,
+
+    /// Set when a new link has started, until its headers are done
+    pending_link: Option<DemuxEvent>,
+
+    events: collections::VecDeque<DemuxEvent>,
+
+    /// Where the current link's first page is
+    link_offset: Option<u64>,
+
+    /// The link's OggIDX stream, if it has one
+    index_serial: Option<u32>,
+
+    /// Streams whose pages turned up without a BOS page
+    orphan_streams: collections::HashSet<u32>,
+    sequence_gaps: u64,
+    orphan_pages: u64,
+    misplaced_bos_pages: u64,
+
     /// A function to identify a stream given its first header
     /// packet. Should return a decoder for that stream as well as a
     /// user-defined data value that must be the same type across all
@@ -698,13 +1372,57 @@ struct StreamMapper<StreamDesc>{
 }
 
 impl<StreamDesc> StreamMapper<StreamDesc> {
+    fn new(stream_init: Box<StreamInitFn<StreamDesc>>) -> Self {
+        StreamMapper{
+            streams: collections::HashMap::new(),
+            discard_streams: collections::HashSet::new(),
+            headers_read: false,
+            hwm: 0,
+            link: 0,
+            time_base: 0,
+            pending_link: Some(DemuxEvent::NewLink{
+                link: 0,
+                start_time: 0,
+                streams: Vec::new(),
+            }),
+            events: collections::VecDeque::new(),
+            link_offset: None,
+            index_serial: None,
+            orphan_streams: collections::HashSet::new(),
+            sequence_gaps: 0,
+            orphan_pages: 0,
+            misplaced_bos_pages: 0,
+            stream_init: stream_init,
+        }
+    }
+
     #[allow(needless_return)] // This is a long enough function that I'll give it a pass.
-    fn handle_page(&mut self, page: RefPage) -> Result<(),StreamError> {
+    fn handle_page(&mut self, page: RefPage, offset: u64) -> Result<(),StreamError> {
         use std::collections::hash_map::Entry;
+        if page.flags.intersects(PAGE_BOS) && self.headers_read {
+            if self.link_finished() {
+                self.start_link();
+            } else {
+                // Streams can only join a link before its first data
+                // page
+                self.discard_streams.insert(page.stream_serial);
+                self.misplaced_bos_pages += 1;
+                return Err(StreamError::Format(true, format!("Stream {:08x} begins in the middle of link {}", page.stream_serial, self.link)));
+            }
+        }
+        if page.flags.intersects(PAGE_BOS) && self.link_offset.is_none() {
+            self.link_offset = Some(offset);
+        }
         if self.discard_streams.contains(&page.stream_serial) {
+            if self.orphan_streams.contains(&page.stream_serial) {
+                self.orphan_pages += 1;
+            }
             return Ok(());
         }
         if page.flags.intersects(PAGE_BOS) {
+            if let Some(DemuxEvent::NewLink{ref mut streams, ..}) = self.pending_link {
+                streams.push(page.stream_serial);
+            }
             match self.streams.entry(page.stream_serial) {
                 Entry::Occupied(_) => return Err(StreamError::Format(true, "Stream has multiple BOS pages".to_owned())),
                 Entry::Vacant(e) => {
@@ -720,6 +1438,12 @@ impl<StreamDesc> StreamMapper<StreamDesc> {
                             return Err(StreamError::Format(true, "BOS page had no packet".to_owned()));
                         }
                     };
+                    if index::parse_header(packet).is_ok() {
+                        // We read the index ourselves, when seeking
+                        self.index_serial = Some(page.stream_serial);
+                        self.discard_streams.insert(page.stream_serial);
+                        return Ok(());
+                    }
                     if let Some((mut decoder, desc)) = (self.stream_init)(packet) {
                         if page.flags.intersects(PAGE_EOS) {
                             decoder.finish();
@@ -731,8 +1455,10 @@ impl<StreamDesc> StreamMapper<StreamDesc> {
                             hwm: 0,
                             last_page_seq: page.page_sequence,
                             user_data: desc,
-                            headers_remaining: num_headers - 1,
+                            headers_remaining: num_headers.saturating_sub(1),
                             finished: page.flags.intersects(PAGE_EOS),
+                            headers: vec![packet.to_owned()],
+                            resuming: false,
                         });
                         return Ok(());
                     } else {
@@ -743,52 +1469,258 @@ impl<StreamDesc> StreamMapper<StreamDesc> {
             }
         } else {
             self.headers_read = true;
+            if let Some(event) = self.pending_link.take() {
+                self.events.push_back(event);
+            }
             // Mid-stream
             if let Some(state) = self.streams.get_mut(&page.stream_serial) {
+                if !state.resuming && page.page_sequence != state.last_page_seq.wrapping_add(1) {
+                    self.sequence_gaps += 1;
+                }
                 try!(state.process_page(page));
-                let hwm = state.decoder.map_granule(state.hwm);
+                let hwm = self.time_base.saturating_add(state.decoder.map_granule(state.hwm));
                 self.hwm = ::std::cmp::max(self.hwm, hwm);
                 return Ok(());
             } else {
                 self.discard_streams.insert(page.stream_serial);
+                self.orphan_streams.insert(page.stream_serial);
+                self.orphan_pages += 1;
                 return Err(StreamError::Format(true, format!("Stream {} had no BOS packet", page.stream_serial)));
             }
         }
     }
 
     fn lwm(&self) -> u64 {
-        self.streams.values().map(|stream| stream.decoder.map_granule(stream.hwm)).min().unwrap_or(0)
+        self.streams.values().map(|stream| self.time_base.saturating_add(stream.decoder.map_granule(stream.hwm))).min().unwrap_or(self.time_base)
     }
 
     fn discard(&mut self, id: u32) {
+        self.streams.remove(&id);
         self.discard_streams.insert(id);
     }
+
+    fn link_finished(&self) -> bool {
+        self.streams.values().all(|stream| stream.finished)
+    }
+
+    /// Forget the streams of the previous link. Its streams all ended
+    /// by hwm, so that is where the new link starts.
+    fn start_link(&mut self) {
+        self.streams.clear();
+        self.discard_streams.clear();
+        self.orphan_streams.clear();
+        self.link_offset = None;
+        self.index_serial = None;
+        self.headers_read = false;
+        self.link += 1;
+        self.time_base = self.hwm;
+        self.pending_link = Some(DemuxEvent::NewLink{
+            link: self.link,
+            start_time: self.time_base,
+            streams: Vec::new(),
+        });
+    }
 }
 
 
 pub struct OggDemux<R, StreamDesc> {
-    source: OggPageSource<R>,
+    source: OggPageSource<ReadInput<R>>,
     mapper: StreamMapper<StreamDesc>,
+    /// Count and carry on after recoverable errors
+    tolerant: bool,
+    recovered_errors: u64,
+    /// What the last of those was
+    last_recovered_error: Option<String>,
+    /// The index of a link, once it has been looked for
+    seek_index: Option<(u32, Option<SeekIndex>)>,
+}
+
+impl <StreamDesc> OggDemux<io::Empty, StreamDesc> {
+    /// Create a demuxer that is driven entirely by `feed`. Nothing
+    /// has been read yet, so wait for the `NewLink` event of the first
+    /// link before looking at `streams()`.
+    pub fn new_feed<F>(stream_mapper: F) -> Self
+        where F: 'static + Fn(&[u8]) -> Option<(Box<BitstreamDecoder>, StreamDesc)>
+    {
+        OggDemux{
+            source: OggPageSource::new(io::empty()),
+            mapper: StreamMapper::new(Box::new(stream_mapper)),
+            tolerant: false,
+            recovered_errors: 0,
+            last_recovered_error: None,
+            seek_index: None,
+        }
+    }
+}
+
+impl <R, StreamDesc> OggDemux<R, StreamDesc> {
+    /// In tolerant mode, count recoverable errors and swallow them.
+    /// Nothing is printed; callers can report `diagnostics()` and
+    /// `last_recovered_error()` wherever suits them.
+    fn tolerate(&mut self, err: StreamError) -> Result<(), StreamError> {
+        if !self.tolerant {
+            return Err(err);
+        }
+        let desc = match err {
+            StreamError::Format(true, ref desc) => format!("damaged page: {}", desc),
+            StreamError::Io(ref ioerr) if ioerr.kind() == io::ErrorKind::UnexpectedEof => {
+                self.source.eof = true;
+                "truncated page at end of input".to_owned()
+            },
+            _ => return Err(err),
+        };
+        self.recovered_errors += 1;
+        self.last_recovered_error = Some(desc);
+        Ok(())
+    }
+
+    /// Keep going after recoverable errors, such as pages from
+    /// streams that never started, rather than returning them.
+    pub fn set_tolerant(&mut self, tolerant: bool) {
+        self.tolerant = tolerant;
+    }
+
+    /// The most recent error ignored in tolerant mode, if any
+    pub fn last_recovered_error(&self) -> Option<&str> {
+        self.last_recovered_error.as_ref().map(|desc| &desc[..])
+    }
+
+    pub fn diagnostics(&self) -> DemuxDiagnostics {
+        DemuxDiagnostics{
+            crc_failures: self.source.crc_failures,
+            resync_bytes: self.source.resync_bytes,
+            resyncs: self.source.resyncs,
+            sequence_gaps: self.mapper.sequence_gaps,
+            orphan_pages: self.mapper.orphan_pages,
+            misplaced_bos_pages: self.mapper.misplaced_bos_pages,
+            recovered_errors: self.recovered_errors,
+        }
+    }
+
+    pub fn is_eof(&self) -> bool {
+        self.source.is_eof()
+    }
+
+    pub fn streams<'a>(&'a mut self) -> DemuxStreams<StreamDesc> {
+        DemuxStreams(self.mapper.streams.iter_mut())
+    }
+
+    pub fn ignore_stream(&mut self, id: u32) {
+        self.mapper.discard(id)
+    }
+
+    /// Take the next event, if any. Events for a link are queued once
+    /// all of its BOS pages have been read. `new` handles the one for
+    /// the first link itself.
+    pub fn next_event(&mut self) -> Option<DemuxEvent> {
+        self.mapper.events.pop_front()
+    }
+
+    /// The current link of a chained stream, counting from 0
+    pub fn link(&self) -> u32 {
+        self.mapper.link
+    }
+
+    /// The serial of the current link's OggIDX stream, if it has one.
+    /// The demuxer reads the index itself, so the stream is never
+    /// passed to the stream mapper or listed by `streams()`.
+    pub fn index_serial(&self) -> Option<u32> {
+        self.mapper.index_serial
+    }
+
+    /// The latest time any stream has been decoded up to, in µs
+    pub fn high_water_mark(&self) -> u64 {
+        self.mapper.hwm
+    }
+
+    /// The underlying reader. Note that the demuxer reads ahead.
+    pub fn get_mut(&mut self) -> &mut R {
+        self.source.input.get_mut()
+    }
 }
 
 impl <R: Read, StreamDesc> OggDemux<R, StreamDesc> {
+    /// Hand the demuxer more input. Every complete page is processed
+    /// straight away, so decoders see their packets and events are
+    /// queued before this returns. Pages that cause an error are
+    /// skipped; the first such error is returned once all of data
+    /// has been processed.
+    pub fn feed(&mut self, mut data: &[u8]) -> Result<(), StreamError> {
+        let mut result = Ok(());
+        loop {
+            let taken = self.source.feed(data);
+            data = &data[taken..];
+            let pumped = self.pump_buffered(false);
+            if result.is_ok() {
+                result = pumped;
+            }
+            if data.is_empty() {
+                return result;
+            }
+        }
+    }
+
+    /// Signal that there is no more input, and process whatever is
+    /// left in the buffer.
+    pub fn end_of_input(&mut self) -> Result<(), StreamError> {
+        self.pump_buffered(true)
+    }
+
+    fn pump_buffered(&mut self, at_eof: bool) -> Result<(), StreamError> {
+        let mut result = Ok(());
+        loop {
+            let handled = match self.source.find_page(at_eof) {
+                Ok(Some(found)) => self.mapper.handle_page(self.source.page_at(found), self.source.page_offset),
+                Ok(None) => return result,
+                Err(err) => {
+                    // The only error here is an incomplete final page
+                    let tolerated = self.tolerate(err);
+                    return result.and(tolerated);
+                },
+            };
+            let handled = handled.or_else(|err| self.tolerate(err));
+            if result.is_ok() {
+                result = handled;
+            }
+        }
+    }
+
     pub fn new<F>(reader: R, stream_mapper: F) -> Result<Self, StreamError>
         where F: 'static + Fn(&[u8]) -> Option<(Box<BitstreamDecoder>, StreamDesc)>
     {
+        Self::open(reader, Box::new(stream_mapper), false)
+    }
+
+    /// Like `new`, but in tolerant mode from the start
+    pub fn new_tolerant<F>(reader: R, stream_mapper: F) -> Result<Self, StreamError>
+        where F: 'static + Fn(&[u8]) -> Option<(Box<BitstreamDecoder>, StreamDesc)>
+    {
+        Self::open(reader, Box::new(stream_mapper), true)
+    }
+
+    /// Like `new`, but streams are identified by the codecs in
+    /// `registry`. Streams it has no decoder for are ignored.
+    pub fn with_registry(reader: R, registry: Registry<StreamDesc>) -> Result<Self, StreamError>
+        where StreamDesc: 'static
+    {
+        Self::new(reader, move |header| registry.identify(header))
+    }
+
+    /// Like `with_registry`, but in tolerant mode from the start
+    pub fn with_registry_tolerant(reader: R, registry: Registry<StreamDesc>) -> Result<Self, StreamError>
+        where StreamDesc: 'static
+    {
+        Self::new_tolerant(reader, move |header| registry.identify(header))
+    }
+
+    fn open(reader: R, stream_mapper: Box<StreamInitFn<StreamDesc>>, tolerant: bool) -> Result<Self, StreamError> {
         let mut demux = OggDemux{
-            source: OggPageSource{
-                buffer: util::ShiftBuffer::new(65536),
-                reader: reader,
-                dead_bytes: 0,
-                eof: false,
-            },
-            mapper: StreamMapper{
-                streams: collections::HashMap::new(),
-                discard_streams: collections::HashSet::new(),
-                headers_read: false,
-                hwm: 0,
-                stream_init: Box::new(stream_mapper),
-            },
+            source: OggPageSource::new(reader),
+            mapper: StreamMapper::new(stream_mapper),
+            tolerant: tolerant,
+            recovered_errors: 0,
+            last_recovered_error: None,
+            seek_index: None,
         };
 
         //while !demux.mapper.headers_read && !demux.source.is_eof() {
@@ -796,10 +1728,31 @@ impl <R: Read, StreamDesc> OggDemux<R, StreamDesc> {
         //}
 
         try!(demux.internal_pump_until(|ogg| ogg.mapper.headers_read));
+        // The caller gets the streams of the first link from us
+        demux.mapper.events.clear();
 
         Ok(demux)
     }
 
+    /// Forget everything read so far and start again from the
+    /// reader's current position, usually after seeking it. Streams
+    /// are identified afresh, as in `new`.
+    pub fn restart(&mut self) -> Result<(), StreamError> {
+        self.seek_index = None;
+        self.restart_at(0)
+    }
+
+    /// Restart with the reader at `position`, in bytes from where
+    /// reading began
+    fn restart_at(&mut self, position: u64) -> Result<(), StreamError> {
+        let stream_init = ::std::mem::replace(&mut self.mapper.stream_init, Box::new(|_| None));
+        self.mapper = StreamMapper::new(stream_init);
+        self.source.reset(position);
+        try!(self.internal_pump_until(|ogg| ogg.mapper.headers_read));
+        self.mapper.events.clear();
+        Ok(())
+    }
+
     fn internal_pump_until<F>(&mut self, predicate: F) -> Result<(), StreamError>
         where F: Fn(&Self) -> bool
     {
@@ -809,21 +1762,13 @@ impl <R: Read, StreamDesc> OggDemux<R, StreamDesc> {
         Ok(())
     }
     
-    pub fn is_eof(&self) -> bool {
-        self.source.is_eof()
-    }
-    
     pub fn pump_page(&mut self) -> Result<(), StreamError> {
-        if let Some(page) = try!(self.source.next_page()) {
-            let page_ser = page.stream_serial;
-            try!(self.mapper.handle_page(page));
-            println!("Pumped a page for stream {:x}", page_ser);
-        }
-        Ok(())
-    }
-
-    pub fn streams<'a>(&'a mut self) -> DemuxStreams<StreamDesc> {
-        DemuxStreams(self.mapper.streams.iter_mut())
+        let result = match self.source.find_next_page() {
+            Ok(Some(found)) => self.mapper.handle_page(self.source.page_at(found), self.source.page_offset),
+            Ok(None) => Ok(()),
+            Err(err) => Err(err),
+        };
+        result.or_else(|err| self.tolerate(err))
     }
 
     /// Takes time in µs
@@ -831,12 +1776,249 @@ impl <R: Read, StreamDesc> OggDemux<R, StreamDesc> {
         try!(self.internal_pump_until(|ogg| ogg.mapper.lwm() >= time));
         Ok(self.mapper.hwm)
     }
+}
 
-    pub fn ignore_stream(&mut self, id: u32) {
-        self.mapper.discard(id)
+impl <R: Read + Seek, StreamDesc> OggDemux<R, StreamDesc> {
+    /// Carry on from `time`, in µs from the start of the file, within
+    /// the current link. Each stream resumes at a page where no packet
+    /// that ends after `time` has started yet, so the first packets
+    /// may be a little early.
+    ///
+    /// If the link has an OggIDX stream, it is read from the end of
+    /// the file the first time, and after that each seek costs a
+    /// single read. Otherwise the pages are found by bisection.
+    /// Either way, the streams' decoders are set up afresh, so look
+    /// at `streams()` again afterwards.
+    pub fn seek(&mut self, time: u64) -> Result<(), StreamError> {
+        // The streams need all their headers to start again
+        try!(self.internal_pump_until(|ogg| ogg.mapper.streams.values().all(|stream| stream.headers_remaining == 0)));
+        let link_offset = match self.mapper.link_offset {
+            Some(offset) => offset,
+            None => return Ok(()),
+        };
+        let (origin, end) = {
+            let read = self.source.read_position();
+            let reader = self.source.input.get_mut();
+            let origin = try!(reader.seek(SeekFrom::Current(0))).saturating_sub(read);
+            (origin, try!(reader.seek(SeekFrom::End(0))).saturating_sub(origin))
+        };
+        let link_time = time.saturating_sub(self.mapper.time_base);
+
+        let link = self.mapper.link;
+        if self.seek_index.as_ref().map(|&(indexed, _)| indexed) != Some(link) {
+            let index = match self.mapper.index_serial {
+                Some(serial) => try!(read_index(self.source.input.get_mut(), origin, link_offset, end, serial)),
+                None => None,
+            };
+            self.seek_index = Some((link, index));
+        }
+        let mut resume = Some(end);
+        // Streams that are over by then, with their last granule
+        let mut ended = collections::HashMap::new();
+        let serials : Vec<u32> = self.mapper.streams.keys().cloned().collect();
+        for serial in serials {
+            let found = {
+                let decoder = &self.mapper.streams[&serial].decoder;
+                let indexed = match self.seek_index {
+                    Some((_, Some(ref index))) if !index.entries(serial).is_empty() => Some(index),
+                    _ => None,
+                };
+                match indexed {
+                    // The last entry is always the EOS page
+                    Some(index) => index.entry_before(serial, link_time, |granule| decoder.map_granule(granule))
+                        .map(|entry| (link_offset + entry.offset, entry.granule, Some(&entry) == index.entries(serial).last())),
+                    None => try!(bisect(self.source.input.get_mut(), origin, (link_offset, end), serial,
+                                        |granule| decoder.map_granule(granule) <= link_time)),
+                }
+            };
+            resume = match (resume, found) {
+                (Some(_), Some((_, granule, true))) => {
+                    ended.insert(serial, granule);
+                    resume
+                },
+                (Some(resume), Some((offset, _, false))) => Some(::std::cmp::min(resume, offset)),
+                _ => None,
+            };
+        }
+
+        match resume {
+            Some(offset) => {
+                try!(self.source.input.get_mut().seek(SeekFrom::Start(origin + offset)));
+                self.source.reset(offset);
+                self.resume_streams(&ended);
+            },
+            None => {
+                // Some stream has nothing that early, so read the
+                // whole link again
+                try!(self.source.input.get_mut().seek(SeekFrom::Start(origin + link_offset)));
+                let (time_base, sequence_gaps, orphan_pages, misplaced_bos_pages) =
+                    (self.mapper.time_base, self.mapper.sequence_gaps, self.mapper.orphan_pages, self.mapper.misplaced_bos_pages);
+                try!(self.restart_at(link_offset));
+                self.mapper.link = link;
+                self.mapper.time_base = time_base;
+                self.mapper.hwm = time_base;
+                self.mapper.sequence_gaps += sequence_gaps;
+                self.mapper.orphan_pages += orphan_pages;
+                self.mapper.misplaced_bos_pages += misplaced_bos_pages;
+            },
+        }
+        Ok(())
+    }
+
+    /// Set up each stream's decoder again from its headers, ready to
+    /// pick up wherever its next page is. Those in `ended` are
+    /// finished at the given granule instead.
+    fn resume_streams(&mut self, ended: &collections::HashMap<u32, u64>) {
+        let mapper = &mut self.mapper;
+        let serials : Vec<u32> = mapper.streams.keys().cloned().collect();
+        for serial in serials {
+            let old = mapper.streams.remove(&serial).unwrap();
+            let (mut decoder, desc) = match (mapper.stream_init)(&old.headers[0]) {
+                Some(started) => started,
+                None => {
+                    mapper.discard_streams.insert(serial);
+                    continue;
+                },
+            };
+            for header in &old.headers[1..] {
+                decoder.process_header(header);
+            }
+            let ended = ended.get(&serial).cloned();
+            if let Some(granule) = ended {
+                decoder.page_done(granule);
+                decoder.finish();
+            }
+            mapper.streams.insert(serial, StreamState{
+                decoder: decoder,
+                partial: Vec::new(),
+                hwm: ended.unwrap_or(0),
+                last_page_seq: old.last_page_seq,
+                headers_remaining: 0,
+                finished: ended.is_some(),
+                headers: old.headers,
+                resuming: ended.is_none(),
+                user_data: desc,
+            });
+        }
+        mapper.hwm = mapper.time_base;
     }
 }
 
+/// How much of the end of the file to look at for the index at first
+const INDEX_TAIL: u64 = 64 * 1024;
+
+/// Below this many bytes, bisection gives way to reading every page
+const BISECT_CHUNK: u64 = 64 * 1024;
+
+/// The largest possible page
+const MAX_PAGE_SIZE: u64 = 27 + 255 + 255 * 255;
+
+/// Read up to `len` bytes at `offset`
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    try!(reader.seek(SeekFrom::Start(offset)));
+    let mut buf = Vec::new();
+    try!(reader.take(len).read_to_end(&mut buf));
+    Ok(buf)
+}
+
+/// Find the OggIDX packet of stream `serial` in the link that spans
+/// `start..end`. It's at the end, so look at more of the end until the
+/// whole packet is in view. Offsets are from `origin`.
+fn read_index<R: Read + Seek>(reader: &mut R, origin: u64, start: u64, end: u64, serial: u32) -> io::Result<Option<SeekIndex>> {
+    let mut size = INDEX_TAIL;
+    loop {
+        let from = ::std::cmp::max(start, end.saturating_sub(size));
+        let tail = try!(read_at(reader, origin + from, end - from));
+        let mut pages = OggPageSource::from_slice(&tail);
+        let mut packet = Vec::new();
+        let mut next_seq = None;
+        let mut seen = false;
+        while let Ok(Some(page)) = pages.next_page() {
+            if page.stream_serial != serial || page.flags.intersects(PAGE_BOS) {
+                continue;
+            }
+            seen = true;
+            match next_seq {
+                // The index is the first packet after the header
+                None if page.page_sequence != 1 => break,
+                Some(seq) if page.page_sequence != seq => return Ok(None),
+                _ => (),
+            }
+            packet.extend_from_slice(page.content);
+            if page.flags.intersects(PAGE_EOS) {
+                return Ok(SeekIndex::parse(&packet));
+            }
+            next_seq = Some(page.page_sequence.wrapping_add(1));
+        }
+        // If none of it is here, it isn't at the end at all
+        if !seen || from == start {
+            return Ok(None);
+        }
+        size = size.saturating_mul(4);
+    }
+}
+
+/// The first page of stream `serial` in `from..to` on which a packet
+/// ends, as its offset, granule position and whether it's the EOS
+/// page
+fn next_granule_page<R: Read + Seek>(reader: &mut R, origin: u64, (mut from, to): (u64, u64), serial: u32) -> io::Result<Option<(u64, u64, bool)>> {
+    while from < to {
+        let chunk = try!(read_at(reader, origin + from, BISECT_CHUNK + MAX_PAGE_SIZE));
+        let mut pages = OggPageSource::from_slice(&chunk);
+        while let Ok(Some(found)) = pages.find_next_page() {
+            let offset = from + pages.page_offset();
+            if offset >= to {
+                return Ok(None);
+            }
+            let page = pages.page_at(found);
+            // Header pages have granule 0, and can't be resumed from
+            if page.stream_serial == serial && page.granule_position != !0 && page.granule_position != 0 {
+                return Ok(Some((offset, page.granule_position, page.flags.intersects(PAGE_EOS))));
+            }
+        }
+        if (chunk.len() as u64) < BISECT_CHUNK + MAX_PAGE_SIZE {
+            return Ok(None);
+        }
+        from += BISECT_CHUNK;
+    }
+    Ok(None)
+}
+
+/// The last page of stream `serial` in `range` that `before` accepts,
+/// going by its granule position, as its offset, granule position
+/// and whether it's the EOS page. Pages must be accepted up to some
+/// point and rejected after it.
+fn bisect<R: Read + Seek, F: Fn(u64) -> bool>(reader: &mut R, origin: u64, (mut lo, mut hi): (u64, u64), serial: u32, before: F) -> io::Result<Option<(u64, u64, bool)>> {
+    let mut best = None;
+    while hi - lo > BISECT_CHUNK {
+        let mid = lo + (hi - lo) / 2;
+        match try!(next_granule_page(reader, origin, (mid, hi), serial)) {
+            Some((offset, granule, eos)) if before(granule) => {
+                best = Some((offset, granule, eos));
+                lo = offset + 1;
+            },
+            _ => hi = mid,
+        }
+    }
+    // Few enough pages left to look at them all
+    let chunk = try!(read_at(reader, origin + lo, hi - lo + MAX_PAGE_SIZE));
+    let mut pages = OggPageSource::from_slice(&chunk);
+    while let Ok(Some(found)) = pages.find_next_page() {
+        let offset = lo + pages.page_offset();
+        if offset >= hi {
+            break;
+        }
+        let page = pages.page_at(found);
+        if page.stream_serial == serial && page.granule_position != !0 && page.granule_position != 0 {
+            if !before(page.granule_position) {
+                break;
+            }
+            best = Some((offset, page.granule_position, page.flags.intersects(PAGE_EOS)));
+        }
+    }
+    Ok(best)
+}
+
 pub struct DemuxStreams<'a, Desc: 'a>(collections::hash_map::IterMut<'a, u32, StreamState<Desc>>);
 
 impl <'a, Desc> Iterator for DemuxStreams<'a, Desc> {
@@ -848,3 +2030,368 @@ impl <'a, Desc> Iterator for DemuxStreams<'a, Desc> {
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stream whose granule positions are milliseconds, with a
+    /// packet every 10ms
+    struct MsCoder(u64, u64);
+
+    impl BitstreamCoder for MsCoder {
+        fn headers(&self) -> Vec<Vec<u8>> {
+            vec![b"ms".to_vec()]
+        }
+
+        fn next_frame(&mut self) -> io::Result<Option<Packet>> {
+            if self.0 == 0 {
+                return Ok(None);
+            }
+            self.0 -= 1;
+            self.1 += 10;
+            Ok(Some(packet(self.1)))
+        }
+
+        fn map_granule(&self, granule: u64) -> u64 {
+            granule * 1000
+        }
+    }
+
+    fn packet(ms: u64) -> Packet {
+        Packet{content: vec![0; 10], timestamp: ms}
+    }
+
+    /// (serial, granule, flags) of each page in buf
+    fn pages(mut buf: &[u8]) -> Vec<(u32, u64, PageFlags)> {
+        let mut pages = Vec::new();
+        while let ParseResult::Yay(n, page) = RefPage::parse(buf) {
+            pages.push((page.stream_serial, page.granule_position, page.flags));
+            buf = &buf[n..];
+        }
+        assert!(buf.is_empty());
+        pages
+    }
+
+    #[test]
+    fn pages_wait_for_other_streams() {
+        let mut mux = IncrementalMux::new(Vec::new());
+        mux.set_max_page_duration(0);
+        let a = mux.add_stream(Box::new(MsCoder(0, 0)));
+        let b = mux.add_stream(Box::new(MsCoder(0, 0)));
+        mux.push_packet(a, packet(10)).unwrap();
+        mux.push_packet(a, packet(20)).unwrap();
+        // Only the headers; b might still produce a packet at 0
+        assert_eq!(pages(mux.get_ref()).len(), 2);
+        mux.push_packet(b, packet(15)).unwrap();
+        let granules : Vec<u64> = pages(mux.get_ref()).iter().skip(2).map(|p| p.1).collect();
+        assert_eq!(granules, vec![10, 15]);
+        let out = mux.finish().unwrap();
+        let pages = pages(&out);
+        let granules : Vec<u64> = pages.iter().skip(2).map(|p| p.1).collect();
+        assert_eq!(granules, vec![10, 15, 15, 20, 20]);
+        assert!(pages[0].2.contains(PAGE_BOS) && pages[1].2.contains(PAGE_BOS));
+        assert!(pages[4].2.contains(PAGE_EOS) && pages[6].2.contains(PAGE_EOS));
+    }
+
+    #[test]
+    fn stalled_streams_are_not_waited_for() {
+        let mut mux = IncrementalMux::new(Vec::new());
+        mux.set_max_page_duration(0);
+        mux.set_interleave_window(100_000);
+        let a = mux.add_stream(Box::new(MsCoder(0, 0)));
+        mux.add_stream(Box::new(MsCoder(0, 0)));
+        for ms in 0..11 {
+            mux.push_packet(a, packet(ms * 10)).unwrap();
+        }
+        // Pages up to 100ms behind the newest packet are out
+        assert_eq!(pages(mux.get_ref()).len(), 2 + 1);
+    }
+
+    #[test]
+    fn pages_close_after_max_duration() {
+        let mut mux = IncrementalMux::new(Vec::new());
+        mux.set_max_page_duration(50_000);
+        let a = mux.add_stream(Box::new(MsCoder(0, 0)));
+        for ms in 0..20 {
+            mux.push_packet(a, packet(ms * 10)).unwrap();
+        }
+        mux.flush_until(!0).unwrap();
+        let granules : Vec<u64> = pages(mux.get_ref()).iter().skip(1).map(|p| p.1).collect();
+        assert_eq!(granules, vec![50, 110, 170, 190]);
+    }
+
+    fn hashed_mux(key: u64, serial: Option<u32>) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut mux = OgkMux::new();
+        mux.set_serial_source(SerialSource::Hashed(key));
+        mux.add_stream(Box::new(MsCoder(100, 0)));
+        match serial {
+            Some(serial) => mux.add_stream_with_serial(Box::new(MsCoder(50, 0)), serial),
+            None => mux.add_stream(Box::new(MsCoder(50, 0))),
+        }
+        mux.write_to(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn hashed_serials_are_reproducible() {
+        let out = hashed_mux(1, None);
+        assert_eq!(out, hashed_mux(1, None));
+        assert!(out != hashed_mux(2, None));
+        // Streams with the same headers still get their own serials
+        let serials : Vec<u32> = pages(&out).iter().take(2).map(|p| p.0).collect();
+        assert!(serials[0] != serials[1]);
+        // A chosen serial is kept, and hashed ones steer clear of it
+        let chosen : Vec<u32> = pages(&hashed_mux(1, Some(serials[0]))).iter().take(2).map(|p| p.0).collect();
+        assert!(chosen.contains(&serials[0]));
+        assert!(chosen[0] != chosen[1]);
+    }
+
+    struct MsDecoder;
+
+    impl BitstreamDecoder for MsDecoder {
+        fn map_granule(&self, granule: u64) -> u64 { granule * 1000 }
+        fn num_headers(&self) -> usize { 1 }
+        fn process_header(&mut self, _: &[u8]) {}
+        fn process_packet(&mut self, _: &[u8], last_granule: u64) -> u64 { last_granule + 10 }
+        fn notice_gap(&mut self) {}
+        fn finish(&mut self) {}
+    }
+
+    #[test]
+    fn chained_links() {
+        let mut out = Vec::new();
+        let mut mux = OgkMux::new();
+        mux.add_stream(Box::new(MsCoder(100, 0)));
+        mux.add_stream(Box::new(MsCoder(50, 0)));
+        mux.write_to(&mut out).unwrap();
+        mux.add_stream(Box::new(MsCoder(30, 0)));
+        mux.write_to(&mut out).unwrap();
+
+        let mut demux = OggDemux::new(io::Cursor::new(out), |_| Some((Box::new(MsDecoder) as Box<BitstreamDecoder>, ()))).unwrap();
+        assert_eq!(demux.streams().count(), 2);
+        assert_eq!(demux.pump_until(500_000).unwrap(), 1000_000);
+        assert_eq!(demux.next_event(), None);
+        // The whole second link fits on one page
+        assert_eq!(demux.pump_until(1100_000).unwrap(), 1300_000);
+        match demux.next_event() {
+            Some(DemuxEvent::NewLink{link, start_time, streams}) => {
+                assert_eq!((link, start_time, streams.len()), (1, 1000_000, 1));
+            },
+            event => panic!("Expected a new link, got {:?}", event),
+        }
+        assert_eq!(demux.streams().count(), 1);
+        assert_eq!(demux.link(), 1);
+    }
+
+    /// Notes the granule position of each packet
+    struct RecordingDecoder(::std::rc::Rc<::std::cell::RefCell<Vec<u64>>>);
+
+    impl BitstreamDecoder for RecordingDecoder {
+        fn map_granule(&self, granule: u64) -> u64 { granule * 1000 }
+        fn num_headers(&self) -> usize { 1 }
+        fn process_header(&mut self, _: &[u8]) {}
+        fn process_packet(&mut self, _: &[u8], last_granule: u64) -> u64 {
+            self.0.borrow_mut().push(last_granule + 10);
+            last_granule + 10
+        }
+        fn notice_gap(&mut self) {}
+        fn finish(&mut self) {}
+    }
+
+    #[test]
+    fn seeks_with_and_without_index() {
+        for &write_index in &[true, false] {
+            let mut out = Vec::new();
+            let mut mux = OgkMux::new();
+            mux.set_write_index(write_index);
+            // 200s and 100s, long enough to bisect
+            mux.add_stream(Box::new(MsCoder(20000, 0)));
+            mux.add_stream(Box::new(MsCoder(10000, 0)));
+            mux.write_to(&mut out).unwrap();
+            assert_eq!(pages(&out).iter().filter(|page| page.2.intersects(PAGE_BOS)).count(), 2 + write_index as usize);
+
+            let mut demux = OggDemux::new(io::Cursor::new(out), |_| {
+                let seen = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+                Some((Box::new(RecordingDecoder(seen.clone())) as Box<BitstreamDecoder>, seen))
+            }).unwrap();
+            assert_eq!(demux.streams().count(), 2);
+            assert_eq!(demux.index_serial().is_some(), write_index);
+            for &ms in &[150_000, 20_000, 0, 99_995, 199_990] {
+                demux.seek(ms * 1000).unwrap();
+                demux.pump_until(ms * 1000 + 1000_000).unwrap();
+                for (_, seen) in demux.streams() {
+                    let seen = seen.borrow();
+                    if let Some(&last) = seen.last() {
+                        // Nothing is missed from the seek point on
+                        assert!(seen[0] <= ms + 10, "{} resumed at {}", ms, seen[0]);
+                        assert_eq!(last - seen[0], 10 * (seen.len() as u64 - 1));
+                        assert!(last > ms);
+                    } else {
+                        assert!(ms >= 99_995);
+                    }
+                }
+            }
+            assert_eq!(demux.seek_index.as_ref().map(|&(_, ref index)| index.is_some()), Some(write_index));
+        }
+    }
+
+    #[test]
+    fn feed_in_small_pieces() {
+        let mut out = Vec::new();
+        let mut mux = OgkMux::new();
+        mux.add_stream(Box::new(MsCoder(100, 0)));
+        mux.write_to(&mut out).unwrap();
+        mux.add_stream(Box::new(MsCoder(30, 0)));
+        mux.write_to(&mut out).unwrap();
+
+        let mut demux = OggDemux::new_feed(|_| Some((Box::new(MsDecoder) as Box<BitstreamDecoder>, ())));
+        let mut links = Vec::new();
+        for chunk in out.chunks(7) {
+            demux.feed(chunk).unwrap();
+            while let Some(DemuxEvent::NewLink{link, start_time, ..}) = demux.next_event() {
+                links.push((link, start_time));
+            }
+        }
+        demux.end_of_input().unwrap();
+        assert!(demux.is_eof());
+        assert_eq!(links, vec![(0, 0), (1, 1000_000)]);
+        assert_eq!(demux.high_water_mark(), 1300_000);
+    }
+
+    #[test]
+    fn slices_give_the_same_pages_as_readers() {
+        let mut mux = IncrementalMux::new(vec![0x55; 100]);
+        mux.set_max_page_duration(0);
+        let a = mux.add_stream(Box::new(MsCoder(0, 0)));
+        for ms in 1..11 {
+            mux.push_packet(a, packet(ms * 10)).unwrap();
+        }
+        let mut out = mux.finish().unwrap();
+        // Damage a page
+        let middle = out.len() / 2;
+        out[middle] ^= 1;
+
+        fn pages<I: Input>(source: &mut OggPageSource<I>) -> Vec<(u32, u64)> {
+            let mut pages = Vec::new();
+            while let Some(page) = source.next_page().unwrap() {
+                pages.push((page.page_sequence, page.granule_position));
+            }
+            pages
+        }
+        let mut from_reader = OggPageSource::new(&out[..]);
+        let mut from_slice = OggPageSource::from_slice(&out);
+        let read = pages(&mut from_reader);
+        let sliced = pages(&mut from_slice);
+        assert!(read.len() > 1);
+        assert_eq!(read, sliced);
+        assert_eq!(from_reader.crc_failures(), 1);
+        assert_eq!(from_slice.crc_failures(), 1);
+        assert_eq!(from_reader.resync_bytes(), from_slice.resync_bytes());
+    }
+
+    #[test]
+    fn tolerant_mode_counts_damage() {
+        let mut mux = IncrementalMux::new(Vec::new());
+        mux.set_max_page_duration(0);
+        let a = mux.add_stream(Box::new(MsCoder(0, 0)));
+        for ms in 1..11 {
+            mux.push_packet(a, packet(ms * 10)).unwrap();
+        }
+        let clean = mux.finish().unwrap();
+
+        // Split the stream back into pages
+        let mut pages = Vec::new();
+        let mut buf = &clean[..];
+        while let ParseResult::Yay(n, _) = RefPage::parse(buf) {
+            pages.push(buf[..n].to_vec());
+            buf = &buf[n..];
+        }
+
+        let mut damaged = Vec::new();
+        damaged.extend_from_slice(&pages[0]);
+        damaged.extend_from_slice(&pages[1]);
+        damaged.extend_from_slice(b"xyz");
+        let mut corrupt = pages[2].clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        damaged.extend_from_slice(&corrupt);
+        damaged.extend_from_slice(&pages[3]);
+        damaged.extend_from_slice(&pages[4]);
+        let mut orphan = Page::new(1234, 5);
+        orphan.add_packet(&packet(50), 0);
+        orphan.write_to(&mut damaged).unwrap();
+        for page in &pages[6..] {
+            damaged.extend_from_slice(page);
+        }
+
+        let mut demux = OggDemux::new_tolerant(io::Cursor::new(damaged), |_| Some((Box::new(MsDecoder) as Box<BitstreamDecoder>, ()))).unwrap();
+        demux.pump_until(!0).unwrap();
+        assert_eq!(demux.diagnostics(), DemuxDiagnostics{
+            crc_failures: 1,
+            resync_bytes: 3 + corrupt.len() as u64,
+            resyncs: 1,
+            sequence_gaps: 2,
+            orphan_pages: 1,
+            misplaced_bos_pages: 0,
+            recovered_errors: 1,
+        });
+        assert!(demux.last_recovered_error().unwrap().starts_with("damaged page"));
+
+        // A file cut off mid-page is also recovered from, not reported
+        let mut truncated = Vec::new();
+        for page in &pages[..3] {
+            truncated.extend_from_slice(page);
+        }
+        truncated.extend_from_slice(&pages[3][..pages[3].len() / 2]);
+        let mut demux = OggDemux::new_tolerant(io::Cursor::new(truncated), |_| Some((Box::new(MsDecoder) as Box<BitstreamDecoder>, ()))).unwrap();
+        demux.pump_until(!0).unwrap();
+        assert_eq!(demux.diagnostics().recovered_errors, 1);
+        assert_eq!(demux.last_recovered_error(), Some("truncated page at end of input"));
+    }
+
+    #[test]
+    fn bos_pages_in_the_middle_of_a_link_are_errors() {
+        let mut mux = IncrementalMux::new(Vec::new());
+        mux.set_max_page_duration(0);
+        let a = mux.add_stream(Box::new(MsCoder(0, 0)));
+        for ms in 1..6 {
+            mux.push_packet(a, packet(ms * 10)).unwrap();
+        }
+        let clean = mux.finish().unwrap();
+
+        let mut pages = Vec::new();
+        let mut buf = &clean[..];
+        while let ParseResult::Yay(n, _) = RefPage::parse(buf) {
+            pages.push(buf[..n].to_vec());
+            buf = &buf[n..];
+        }
+
+        let mut late = Vec::new();
+        for page in &pages[..3] {
+            late.extend_from_slice(page);
+        }
+        let mut bos = Page::new(1234, 0);
+        bos.flags = PAGE_BOS;
+        bos.add_packet(&packet(0), 0);
+        bos.write_to(&mut late).unwrap();
+        let mut data = Page::new(1234, 1);
+        data.add_packet(&packet(30), 0);
+        data.write_to(&mut late).unwrap();
+        for page in &pages[3..] {
+            late.extend_from_slice(page);
+        }
+
+        let mut demux = OggDemux::new(io::Cursor::new(late.clone()), |_| Some((Box::new(MsDecoder) as Box<BitstreamDecoder>, ()))).unwrap();
+        match demux.pump_until(!0) {
+            Err(StreamError::Format(true, ref desc)) => assert!(desc.ends_with("000004d2 begins in the middle of link 0")),
+            other => panic!("expected a recoverable format error, not {:?}", other.map(|_| ())),
+        }
+
+        // Tolerated, the stream is ignored along with its pages
+        let mut demux = OggDemux::new_tolerant(io::Cursor::new(late), |_| Some((Box::new(MsDecoder) as Box<BitstreamDecoder>, ()))).unwrap();
+        demux.pump_until(!0).unwrap();
+        let diagnostics = demux.diagnostics();
+        assert_eq!((diagnostics.misplaced_bos_pages, diagnostics.orphan_pages, diagnostics.recovered_errors), (1, 0, 1));
+        assert_eq!(demux.streams().count(), 1);
+    }
+}
@@ -0,0 +1,183 @@
+//! The codecs that can appear in an OGK file, looked up by the magic
+//! at the start of each stream's first header.
+
+use ogg::StreamInitFn;
+use cdg::CdgHeader;
+use mp3::Mp3Header;
+use util::HeaderError;
+use index;
+
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub enum ContentType {
+    Audio,
+    Video,
+    /// Describes the other streams rather than carrying content
+    Index,
+}
+
+/// What the registry needs to know from a stream's first header
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub struct StreamHeader {
+    pub version: (u8, u8),
+    /// The number of header packets, including the first
+    pub num_headers: usize,
+}
+
+pub struct Codec<Desc> {
+    pub name: &'static str,
+    /// The first header packet of every stream starts with this
+    pub magic: &'static [u8],
+    pub content_type: ContentType,
+    /// Fails if the header is damaged or has a major version we
+    /// can't read
+    pub parse_header: fn(&[u8]) -> Result<StreamHeader, HeaderError>,
+    /// Starts decoding a stream, given its first header packet
+    pub decoder: Option<Box<StreamInitFn<Desc>>>,
+}
+
+/// A set of codecs. `Desc` is the per-stream value the decoders hand
+/// to the demuxer, as for `OggDemux::new`.
+pub struct Registry<Desc = ()> {
+    codecs: Vec<Codec<Desc>>,
+}
+
+fn parse_cdg_header(header: &[u8]) -> Result<StreamHeader, HeaderError> {
+    CdgHeader::parse(header).map(|cdg_header| StreamHeader{
+        version: cdg_header.version,
+        num_headers: cdg_header.num_headers(),
+    })
+}
+
+fn parse_mp3_header(header: &[u8]) -> Result<StreamHeader, HeaderError> {
+    Mp3Header::parse(header).map(|mp3_header| StreamHeader{
+        version: mp3_header.version,
+        num_headers: 1 + mp3_header.aux_headers,
+    })
+}
+
+fn parse_index_header(header: &[u8]) -> Result<StreamHeader, HeaderError> {
+    index::parse_header(header).map(|version| StreamHeader{
+        version: version,
+        num_headers: 1,
+    })
+}
+
+impl <Desc> Registry<Desc> {
+    /// A registry with no codecs at all
+    pub fn new() -> Self {
+        Registry{
+            codecs: Vec::new(),
+        }
+    }
+
+    /// OggCDG and OggMP3, with no decoders, and OggIDX,
+    /// which the demuxer reads itself
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        registry.register(Codec{
+            name: "OggCDG",
+            magic: b"OggCDG\0\0",
+            content_type: ContentType::Video,
+            parse_header: parse_cdg_header,
+            decoder: None,
+        });
+        registry.register(Codec{
+            name: "OggMP3",
+            magic: b"OggMP3\0\0",
+            content_type: ContentType::Audio,
+            parse_header: parse_mp3_header,
+            decoder: None,
+        });
+        registry.register(Codec{
+            name: "OggIDX",
+            magic: index::INDEX_MAGIC,
+            content_type: ContentType::Index,
+            parse_header: parse_index_header,
+            decoder: None,
+        });
+        registry
+    }
+
+    /// Add a codec. One registered earlier with the same name is
+    /// replaced.
+    pub fn register(&mut self, codec: Codec<Desc>) {
+        self.codecs.retain(|other| other.name != codec.name);
+        self.codecs.push(codec);
+    }
+
+    /// Set the decoder of an already registered codec. Returns false
+    /// if there's no codec by that name.
+    pub fn set_decoder(&mut self, name: &str, decoder: Box<StreamInitFn<Desc>>) -> bool {
+        match self.codecs.iter_mut().find(|codec| codec.name == name) {
+            Some(codec) => {
+                codec.decoder = Some(decoder);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn codecs(&self) -> &[Codec<Desc>] {
+        &self.codecs
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&Codec<Desc>> {
+        self.codecs.iter().find(|codec| codec.name == name)
+    }
+
+    /// The codec of a stream, given its first header packet
+    pub fn find(&self, header: &[u8]) -> Option<&Codec<Desc>> {
+        self.codecs.iter().find(|codec| header.starts_with(codec.magic))
+    }
+
+    /// Start decoding a stream, if its codec is known, its header is
+    /// usable and there's a decoder for it. Suitable for passing to
+    /// `OggDemux::new`.
+    pub fn identify(&self, header: &[u8]) -> Option<(Box<::ogg::BitstreamDecoder>, Desc)> {
+        self.find(header)
+            .and_then(|codec| (codec.parse_header)(header).ok().and(codec.decoder.as_ref()))
+            .and_then(|decoder| decoder(header))
+    }
+}
+
+impl <Desc> Default for Registry<Desc> {
+    fn default() -> Self { Self::builtin() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use ogg::{BitstreamCoder,BitstreamDecoder};
+    use cdg::OggCdgCoder;
+    use super::*;
+
+    struct NullDecoder;
+
+    impl BitstreamDecoder for NullDecoder {
+        fn map_granule(&self, granule: u64) -> u64 { granule }
+        fn num_headers(&self) -> usize { 1 }
+        fn process_header(&mut self, _: &[u8]) {}
+        fn process_packet(&mut self, _: &[u8], last_granule: u64) -> u64 { last_granule }
+        fn notice_gap(&mut self) {}
+        fn finish(&mut self) {}
+    }
+
+    #[test]
+    fn identifies_streams_by_magic() {
+        let mut registry = Registry::<&'static str>::builtin();
+        assert!(registry.set_decoder("OggCDG", Box::new(|_| Some((Box::new(NullDecoder) as Box<BitstreamDecoder>, "cdg")))));
+        assert!(!registry.set_decoder("OggVorbis", Box::new(|_| None)));
+
+        assert_eq!(registry.by_name("OggCDG").map(|codec| codec.content_type), Some(ContentType::Video));
+        let coder = OggCdgCoder::new(Cursor::new(vec![0; 96]));
+        let header = coder.headers().remove(0);
+        let codec = registry.find(&header).unwrap();
+        assert_eq!(codec.name, "OggCDG");
+        assert_eq!((codec.parse_header)(&header), Ok(StreamHeader{version: (0, 0), num_headers: 1}));
+        assert_eq!(registry.identify(&header).map(|(_, desc)| desc), Some("cdg"));
+
+        // Known, but nothing to decode it with
+        assert!(registry.identify(b"OggMP3\0\0\0\0\0\0\xFF\xFB\x90\x00\0\0\0\0\0\0\0\0").is_none());
+        assert!(registry.find(b"OggVorbis").is_none());
+    }
+}
@@ -3,8 +3,13 @@
 
 use std::io::prelude::*;
 use std::io;
-use std::ops::Index;
-use std::ops::{Range,RangeTo,RangeFrom,RangeFull};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::cmp::min;
+use std::error::Error;
+use std::fmt;
+use std::ops::Deref;
 
 // A shiftbuffer works like a VecDeque but guarantees that the data
 // contained is slicable. It is also only a unidirectional queue.
@@ -25,11 +30,8 @@ pub struct ShiftBuffer {
 
 impl ShiftBuffer {
     pub fn new(max_block: usize) -> Self {
-        let mut vec = Vec::with_capacity(max_block * 2);
-        unsafe { vec.set_len(max_block * 2); } // The u8's are undefined so far...
-        
         ShiftBuffer{
-            content: vec,
+            content: vec![0; max_block * 2],
             max_block: max_block,
             rptr: 0,
             wptr: 0,
@@ -39,11 +41,9 @@ impl ShiftBuffer {
     }
 
     fn shift(&mut self) {
-        use std::ptr;
         // This condition guarantees at most a single copy per element.
         if self.rptr >= self.max_block {
-            let buf = self.content.as_mut_ptr();
-            unsafe {ptr::copy_nonoverlapping(buf.offset(self.rptr as isize), buf, self.len());}
+            self.content.copy_within(self.rptr..self.wptr, 0);
             self.wptr = self.len();
             self.rptr = 0;
         }
@@ -57,19 +57,29 @@ impl ShiftBuffer {
         self.wptr == self.rptr
     }
 
+    /// The most data the buffer will hold at once
+    pub fn capacity(&self) -> usize {
+        self.max_block
+    }
+
+    /// Read at most `len` bytes, or as many as there's room for.
+    /// Returns 0 at the end of the input or if the buffer is full.
     pub fn fill<R: Read>(&mut self, reader: &mut R, len: usize) -> io::Result<usize> {
         // We only attempt to shift when writing, to reduce needless
         // shifts Further, shift only actually shifts when it can
         // shift by more than max_block
         self.shift();
-        if len + self.len() > self.max_block {
-            panic!("Tried to read too much into a buffer");
-        }
-        reader.read(&mut self.content[self.wptr..self.wptr+len]).map(|count| {self.wptr += count; self.passed_data += count; count})
+        let len = min(len, self.max_block - self.len());
+        let count = try!(reader.read(&mut self.content[self.wptr..self.wptr+len]));
+        self.wptr += count;
+        self.passed_data += count;
+        Ok(count)
     }
 
+    /// Fill the buffer to at least `target` bytes, if the input
+    /// lasts that long
     pub fn fill_to<R: Read>(&mut self, reader: &mut R, target: usize) -> io::Result<usize> {
-        assert!(target <= self.max_block);
+        let target = min(target, self.max_block);
         let mut read = 0;
         while self.len() < target {
             let len = self.len();
@@ -101,51 +111,252 @@ impl ShiftBuffer {
     }
 }
 
+impl Deref for ShiftBuffer {
+    type Target = [u8];
 
-impl Index<usize> for ShiftBuffer {
-    type Output = u8;
+    fn deref(&self) -> &[u8] {
+        &self.content[self.rptr..self.wptr]
+    }
+}
+
+/// Where `OggPageSource` and `Mp3Stream` get their bytes from: a
+/// window onto the input that can be topped up and consumed from the
+/// front.
+pub trait Input {
+    /// Top up the window as far as it goes
+    fn fill(&mut self) -> io::Result<()>;
+
+    /// Everything in the window
+    fn data(&self) -> &[u8];
+
+    /// Drop `amount` bytes from the front of the window, returning
+    /// them. They stay valid until the next `fill`.
+    fn consume(&mut self, amount: usize) -> &[u8];
+
+    /// True once the window reaches the end of the input
+    fn is_complete(&self) -> bool;
+}
 
-    fn index(&self, idx: usize) -> &u8 {
-        if idx + self.rptr >= self.wptr {
-            panic!("Index out of bounds");
+/// Input read through a `ShiftBuffer`
+pub struct ReadInput<R> {
+    reader: R,
+    buffer: ShiftBuffer,
+    complete: bool,
+}
+
+impl <R> ReadInput<R> {
+    /// `window` is the largest number of bytes that need to be seen
+    /// at once
+    pub fn new(reader: R, window: usize) -> Self {
+        ReadInput{
+            reader: reader,
+            buffer: ShiftBuffer::new(window),
+            complete: false,
         }
-        &self.content[idx + self.rptr]
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Copy as much of `data` into the window as will fit, instead of
+    /// reading. Returns the number of bytes taken.
+    pub fn feed(&mut self, mut data: &[u8]) -> usize {
+        let room = self.buffer.capacity() - self.buffer.len();
+        self.buffer.fill(&mut data, room).expect("Reading from a slice can't fail")
+    }
+
+    /// Throw away the window, as after seeking the reader
+    pub fn reset(&mut self) {
+        self.buffer = ShiftBuffer::new(self.buffer.capacity());
+        self.complete = false;
+    }
+}
+
+impl <R: Read> Input for ReadInput<R> {
+    fn fill(&mut self) -> io::Result<()> {
+        try!(self.buffer.fill_max(&mut self.reader));
+        // fill_max only stops short of a full buffer at the end of
+        // the input
+        self.complete = self.buffer.len() < self.buffer.capacity();
+        Ok(())
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    fn consume(&mut self, amount: usize) -> &[u8] {
+        self.buffer.consume(amount)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.complete
+    }
+}
+
+/// Input that is already in memory, such as a memory-mapped file.
+/// Nothing is copied.
+pub struct SliceInput<'a> {
+    data: &'a [u8],
+}
+
+impl <'a> SliceInput<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        SliceInput{
+            data: data,
+        }
+    }
+}
+
+impl <'a> Input for SliceInput<'a> {
+    fn fill(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn data(&self) -> &[u8] {
+        self.data
+    }
+
+    fn consume(&mut self, amount: usize) -> &[u8] {
+        let (consumed, rest) = self.data.split_at(amount);
+        self.data = rest;
+        consumed
+    }
+
+    fn is_complete(&self) -> bool {
+        true
     }
 }
 
-impl Index<Range<usize>> for ShiftBuffer {
-    type Output = [u8];
-    
-    fn index(&self, idx: Range<usize>) -> &[u8] {
-        let min = idx.start + self.rptr;
-        let max = idx.end + self.rptr;
-        
-        if min >= self.wptr || max > self.wptr || min > max {
-            panic!("Index out of bounds");
+struct DecodeChannel<T> {
+    items: VecDeque<T>,
+    finished: bool,
+}
+
+/// Where a library decoder puts its output. The decoder itself is
+/// owned by the demuxer, so keep a clone of its queue to read what it
+/// produces.
+pub struct DecodeQueue<T>(Rc<RefCell<DecodeChannel<T>>>);
+
+impl <T> Clone for DecodeQueue<T> {
+    fn clone(&self) -> Self {
+        DecodeQueue(self.0.clone())
+    }
+}
+
+impl <T> Default for DecodeQueue<T> {
+    fn default() -> Self { Self::new() }
+}
+
+impl <T> DecodeQueue<T> {
+    pub fn new() -> Self {
+        DecodeQueue(Rc::new(RefCell::new(DecodeChannel{
+            items: VecDeque::new(),
+            finished: false,
+        })))
+    }
+
+    pub fn push(&self, item: T) {
+        self.0.borrow_mut().items.push_back(item);
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        self.0.borrow_mut().items.pop_front()
+    }
+
+    /// Put back an item that was taken too early
+    pub fn unpop(&self, item: T) {
+        self.0.borrow_mut().items.push_front(item);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.borrow().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().items.is_empty()
+    }
+
+    /// Mark the end of the stream
+    pub fn finish(&self) {
+        self.0.borrow_mut().finished = true;
+    }
+
+    /// True once the stream has ended. There may still be items to
+    /// take.
+    pub fn is_finished(&self) -> bool {
+        self.0.borrow().finished
+    }
+}
+
+/// Why the first header packet of an OggCDG or OggMP3 stream can't be
+/// used
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum HeaderError {
+    /// The packet doesn't start with the format's magic
+    WrongMagic,
+    /// The header is cut short or has a field we can't accept
+    Malformed(&'static str),
+    /// The header has a major version other than the one we read. A
+    /// higher minor version is never a reason to reject a header.
+    UnsupportedVersion{found: (u8, u8), supported: u8},
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeaderError::UnsupportedVersion{found: (major, minor), supported} =>
+                write!(fmt, "format version {}.{} is not supported (only {}.x can be read)", major, minor, supported),
+            _ => write!(fmt, "{}", self.description()),
         }
-        &self.content[min..max]
     }
 }
 
-impl Index<RangeTo<usize>> for ShiftBuffer {
-    type Output = [u8];
-    
-    fn index(&self, idx: RangeTo<usize>) -> &[u8] {
-        self.index(0..idx.end)
+impl Error for HeaderError {
+    fn description(&self) -> &str {
+        match *self {
+            HeaderError::WrongMagic => "not a header of this format",
+            HeaderError::Malformed(desc) => desc,
+            HeaderError::UnsupportedVersion{..} => "unsupported format version",
+        }
     }
 }
 
-impl Index<RangeFrom<usize>> for ShiftBuffer {
-    type Output = [u8];
-    
-    fn index(&self, idx: RangeFrom<usize>) -> &[u8] {
-        self.index(idx.start..self.len())
+/// Check the magic and major version at the start of a stream header,
+/// which are laid out the same for every format. Returns the version.
+pub fn check_header_version(header: &[u8], magic: &[u8], major: u8) -> Result<(u8, u8), HeaderError> {
+    if !header.starts_with(magic) {
+        return Err(HeaderError::WrongMagic);
     }
+    if header.len() < magic.len() + 2 {
+        return Err(HeaderError::Malformed("header is too short"));
+    }
+    let version = (header[magic.len()], header[magic.len() + 1]);
+    if version.0 != major {
+        return Err(HeaderError::UnsupportedVersion{found: version, supported: major});
+    }
+    Ok(version)
 }
-impl Index<RangeFull> for ShiftBuffer {
-    type Output = [u8];
-    
-    fn index(&self, _: RangeFull) -> &[u8] {
-        &self.content[self.rptr..self.wptr]
+
+/// The starting value for `fnv1a`
+pub const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// 64-bit FNV-1a, continuing from `hash`. Unlike std's hashers, the
+/// result never changes between Rust versions or machines, so it's
+/// safe to store in files.
+pub fn fnv1a(mut hash: u64, data: &[u8]) -> u64 {
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
     }
+    hash
 }
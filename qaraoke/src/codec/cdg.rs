@@ -1,13 +1,11 @@
-use cdg;
 use cdg_renderer;
 use image;
 use glium;
 use std::borrow::Cow;
-use std::collections::VecDeque;
 use std::rc::Rc;
-use std::cell::RefCell;
 use ogk::ogg;
-use ogk;
+use ogk::cdg::{OggCdgDecoder,TimedCommand};
+use ogk::util::DecodeQueue;
 use types;
 
 
@@ -71,29 +69,14 @@ impl CdgPlayerRsrc {
     }
 }
 
-struct DecodeChannel {
-    queue: VecDeque<(u32, cdg::Command)>,
-    finished: bool,
-}
-
-impl Default for DecodeChannel {
-    fn default() -> Self {
-        DecodeChannel{
-            queue: Default::default(),
-            finished: false,
-        }
-    }
-}
-
-type CommandQueue = Rc<RefCell<DecodeChannel>>;
-
 pub struct CdgPlayer {
-    // The first element of the pair is the 75fps frame in which the
-    // command should be executed.
-    cdg_stream: CommandQueue,
+    cdg_stream: DecodeQueue<TimedCommand>,
     interp: cdg_renderer::CdgInterpreter,
 
-    current_sector: u32,
+    current_sector: u64,
+    /// How much later than the audio to show the graphics, in
+    /// seconds
+    sync_offset: f64,
 
     out_buffer: image::RgbaImage,
     render_resources: Option<CdgPlayerRsrc>,
@@ -101,12 +84,13 @@ pub struct CdgPlayer {
 
 
 impl CdgPlayer {
-    fn new(queue: CommandQueue) -> Self {
+    fn new(queue: DecodeQueue<TimedCommand>) -> Self {
         CdgPlayer{
             cdg_stream: queue,
             interp: cdg_renderer::CdgInterpreter::new(),
 
             current_sector: 0,
+            sync_offset: 0.,
 
             out_buffer: image::RgbaImage::new(300,216),
             render_resources: None,
@@ -118,16 +102,22 @@ impl CdgPlayer {
     /// # Returns
     /// 
     fn update(&mut self, time: f64) {
-        let target_sector = (time * 75. + 0.5) as u32;
-        let mut stream = self.cdg_stream.borrow_mut();
+        let time = (time - self.sync_offset).max(0.);
+        let target_sector = (time * 75. + 0.5) as u64;
         while self.current_sector < target_sector {
-            if let Some((ts, cmd)) = stream.queue.pop_front() {
-                if ts > target_sector {
-                    stream.queue.push_front((ts, cmd));
-                    break;
-                }
-                self.interp.handle_cmd(cmd);
-                self.current_sector = ts;
+            match self.cdg_stream.pop() {
+                Some(timed) => {
+                    // Commands in sector n run once n+1 sectors have
+                    // gone by
+                    if timed.sector >= target_sector {
+                        self.cdg_stream.unpop(timed);
+                        break;
+                    }
+                    self.current_sector = timed.sector;
+                    self.interp.handle_cmd(timed.command);
+                },
+                // Nothing decoded that far yet
+                None => break,
             }
         }
     }
@@ -143,6 +133,12 @@ impl <S: glium::Surface> types::VideoCodec<S> for CdgPlayer {
         self.render_resources = Some(CdgPlayerRsrc::new(ctx));
     }
     
+    fn set_sync_offset(&mut self, offset: f64) {
+        // Moving earlier can't undo commands already run; the
+        // graphics catch up with the next screen redraw
+        self.sync_offset = offset;
+    }
+
     fn render_frame(&mut self, ctx: &Rc<glium::backend::Context>, target: &mut S, when: f64) {
         self.update(when);
         self.render();
@@ -166,66 +162,10 @@ impl <S: glium::Surface> types::VideoCodec<S> for CdgPlayer {
     }
 }
 
-struct CdgDecoder {
-    header: ogk::cdg::CdgHeader,
-    queue: CommandQueue,
-}
-
-impl ogg::BitstreamDecoder for CdgDecoder {
-    fn map_granule(&self, granule: u64) -> u64 { (granule >> 20) * 40_000 / 3 }
-
-    fn num_headers(&self) -> usize { 1 }
-
-    fn process_header(&mut self, _: &[u8]) { }
-    fn process_packet(&mut self, packet: &[u8], last_granule: u64) -> u64 {
-        use ogk::cdg::PacketType;
-        let last_sector = last_granule >> 20;
-        let last_keyframe = last_granule & 0xFFFFF;
-        let mut cur_sector = 0;
-        let mut queue = self.queue.borrow_mut();
-        match self.header.decode_packet(packet) {
-            Some((PacketType::Command, cmds)) => {
-                for sector in cmds.chunks(96) {
-                    cur_sector += 1;
-                    for cmd in cdg::SectorIter::new(sector) {
-                        queue.queue.push_back( ((last_sector + cur_sector) as u32, cmd) );
-                    }
-                }
-                println!("Processed sector {}; queue at {} commands", last_sector + cur_sector, queue.queue.len());
-                (last_sector + cur_sector) << 20 | (last_keyframe + cur_sector) & 0xFFFF
-            },
-            Some((PacketType::Keyframe, _)) => {
-                println!("keyframe?");
-                // We ignore the keyframe
-                last_sector & !0xFFFFF
-            },
-            None => {
-                println!("Parse failure");
-                last_granule
-            },
-            Some((PacketType::Other(n), _)) => {
-                println!("WTF is cdg packet {}", n);
-                last_granule
-            },
-        }
-    }
-    fn notice_gap(&mut self) {}
-    fn finish(&mut self) {
-        self.queue.borrow_mut().finished = true;
-    }
-}
-
 pub fn try_start_stream<S: glium::Surface>(raw_header: &[u8]) -> Option<(Box<ogg::BitstreamDecoder>, types::StreamDesc<S>)> {
-    use ogk::cdg::*;
-    use std::default::Default;
-    CdgHeader::from_bytes(raw_header).map(|header| {
-        let queue : CommandQueue = Default::default();
-        let decoder = Box::new(CdgDecoder{
-            header: header,
-            queue: queue.clone(),
-        }) as Box<ogg::BitstreamDecoder>;
-        let sd = types::StreamDesc::Video(Some(Box::new(CdgPlayer::new(queue))));
-        (decoder, sd)
+    OggCdgDecoder::new(raw_header).map(|decoder| {
+        let sd = types::StreamDesc::Video(Some(Box::new(CdgPlayer::new(decoder.output()))));
+        (Box::new(decoder) as Box<ogg::BitstreamDecoder>, sd)
     })
 }
 
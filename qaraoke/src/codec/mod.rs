@@ -1,4 +1,4 @@
-use ogk::ogg;
+use ogk::registry::Registry;
 use glium;
 
 use types;
@@ -6,7 +6,10 @@ use types;
 pub mod cdg;
 pub mod mp3;
 
-pub fn identify_header<S: glium::Surface>(header: &[u8]) -> Option<(Box<ogg::BitstreamDecoder>, types::StreamDesc<S>)> {
-    None.or_else(|| cdg::try_start_stream(header))
-        .or_else(|| mp3::try_start_stream(header))
+/// The built-in codecs, hooked up to the player's decoders
+pub fn registry<S: glium::Surface + 'static>() -> Registry<types::StreamDesc<S>> {
+    let mut registry = Registry::builtin();
+    registry.set_decoder("OggCDG", Box::new(cdg::try_start_stream::<S>));
+    registry.set_decoder("OggMP3", Box::new(mp3::try_start_stream::<S>));
+    registry
 }
@@ -1,4 +1,6 @@
-use ogk::ogg;
+use ogk::ogg::{self,BitstreamDecoder};
+use ogk::mp3::{AudioRole,OggMp3Decoder,TimedFrame};
+use ogk::util::DecodeQueue;
 use mpg123;
 use types;
 use glium;
@@ -11,15 +13,28 @@ use soxr;
 struct Mp3Decoder {
     queue_sender: mpsc::Sender<Vec<types::Sample>>,
     decoder: mpg123::Handle<f32>,
+    /// Splits the stream into frames and keeps track of timing
+    frames: OggMp3Decoder,
+    frame_queue: DecodeQueue<TimedFrame>,
     sample_frequency: u32,
-    aux_headers: usize,
     soxr: soxr::Soxr<types::Sample, types::Sample>,
+
+    /// Encoder delay, in samples, that still needs to be dropped
+    /// from the start of the stream
+    delay_remaining: u32,
+    /// Number of samples of padding at the end of the stream
+    padding: u32,
+    /// Decoded samples (interleaved) that might turn out to be padding
+    held_samples: Vec<f32>,
 }
 
 struct Mp3DecoderFrontend {
     receiver: mpsc::Receiver<Vec<types::Sample>>,
     ringbuffer: Option<ringbuffer::Writer<types::Sample>>,
     queued_samples: Option<vec::IntoIter<types::Sample>>,
+    role: AudioRole,
+    /// Set once the decoder has sent its last samples
+    finished: bool,
 }
 
 fn as_interlaced<T>(buf: &mut [[T; 2]]) -> &mut [T] {
@@ -62,7 +77,34 @@ impl Mp3Decoder {
         }
     }
 
+    /// Trim the encoder delay and padding from freshly decoded
+    /// samples before passing them on to the resampler.
+    fn handle_decoded(&mut self, rate: u32, buf: &[f32]) {
+        use std::cmp::min;
+        let mut buf = buf;
+        if self.delay_remaining > 0 {
+            let skip = min(self.delay_remaining as usize, buf.len() / 2);
+            self.delay_remaining -= skip as u32;
+            buf = &buf[skip * 2..];
+        }
+
+        // We can't know which samples are padding until the stream
+        // ends, so always hold back that many.
+        let hold = self.padding as usize * 2;
+        if hold == 0 {
+            self.handle_samples(rate, buf);
+            return;
+        }
+        self.held_samples.extend_from_slice(buf);
+        if self.held_samples.len() > hold {
+            let ready : Vec<f32> = self.held_samples.drain(..self.held_samples.len() - hold).collect();
+            self.handle_samples(rate, &ready);
+        }
+    }
+
     fn handle_finish(&mut self) {
+        // Whatever is left over is padding.
+        self.held_samples.clear();
         loop {
             let mut obuf = vec![[0.;2]; 512];
             if let Ok(odone) = self.soxr.process(None, &mut obuf[..]) {
@@ -78,22 +120,17 @@ impl Mp3Decoder {
     }
 }
 
-impl ogg::BitstreamDecoder for Mp3Decoder {
-    fn map_granule(&self, timestamp: u64) -> u64 { 1000_000 * timestamp / self.sample_frequency as u64 }
-    fn num_headers(&self) -> usize { self.aux_headers + 1 }
-    fn process_header(&mut self, _: &[u8]) { }
-    fn process_packet(&mut self, packet: &[u8], last_granule: u64) -> u64 {
-        
-        if let Err(e) = self.decoder.feed(packet) {
-            println!("Encountered mp3 error at granule {}: {:?}", last_granule, e);
+impl Mp3Decoder {
+    fn decode_frame(&mut self, timed: &TimedFrame) {
+        if let Err(e) = self.decoder.feed(&timed.frame) {
+            println!("Encountered mp3 error at granule {}: {:?}", timed.sample, e);
         }
         // Move what data we can out
         let mut buf : [f32;2304] = [0.0;2304];
         let res = self.decoder.shit(&mut buf);
         match res {
             Err(e) => {
-                println!("Encountered mp3 decode error at granule {}: {:?}", last_granule, e);
-                return last_granule+1;
+                println!("Encountered mp3 decode error at granule {}: {:?}", timed.sample, e);
             },
             Ok((rate, channels, nsamples)) => {
                 if nsamples > 0 {
@@ -102,15 +139,31 @@ impl ogg::BitstreamDecoder for Mp3Decoder {
                     }
                     
                     //let mut obuf : Vec<f32> = vec![0.0; (2304. * 48000. / rate as f64 + 0.5) as usize];
-                    self.handle_samples(rate, &buf[..]);
+                    self.handle_decoded(rate, &buf[..nsamples]);
                 }
             },
         };
-        last_granule + 1
     }
+}
 
+impl ogg::BitstreamDecoder for Mp3Decoder {
+    fn map_granule(&self, timestamp: u64) -> u64 { self.frames.map_granule(timestamp) }
+    fn num_headers(&self) -> usize { self.frames.num_headers() }
+    fn process_header(&mut self, _: &[u8]) { }
+    fn process_packet(&mut self, packet: &[u8], last_granule: u64) -> u64 {
+        let granule = self.frames.process_packet(packet, last_granule);
+        while let Some(timed) = self.frame_queue.pop() {
+            self.decode_frame(&timed);
+        }
+        granule
+    }
+
+    fn page_done(&mut self, granule: u64) { self.frames.page_done(granule); }
     fn notice_gap(&mut self) {}
-    fn finish(&mut self) { self.handle_finish(); }
+    fn finish(&mut self) {
+        self.frames.finish();
+        self.handle_finish();
+    }
 }
 
 impl types::AudioCodec for Mp3DecoderFrontend {
@@ -122,6 +175,10 @@ impl types::AudioCodec for Mp3DecoderFrontend {
 
     fn min_buffer_size(&self) -> u32 { 1152 }
 
+    fn role(&self) -> AudioRole { self.role }
+
+    fn is_finished(&self) -> bool { self.finished }
+
     fn do_needful(&mut self) {
         if self.ringbuffer.is_none() {
             return
@@ -153,18 +210,21 @@ impl types::AudioCodec for Mp3DecoderFrontend {
             }
         }
         if drop_ringbuffer {
+            self.finished = true;
             self.ringbuffer.take();
         }
     }
 }
 
 pub fn try_start_stream<S: glium::Surface>(raw_header: &[u8]) -> Option<(Box<ogg::BitstreamDecoder>, types::StreamDesc<S>)> {
-    use byteorder::{ByteOrder, LittleEndian};
-    if &raw_header[0..9] != b"OggMP3\0\0\0" {
-        return None;
-    }
-    let aux_headers = raw_header[11] as usize;
-    let sample_freq = LittleEndian::read_u32(&raw_header[16..20]);
+    let frames = match OggMp3Decoder::new(raw_header) {
+        Some(frames) => frames,
+        None => return None,
+    };
+    let sample_freq = frames.representative().sample_rate;
+    let delay = frames.clock().delay;
+    let padding = frames.padding();
+    let role = frames.role();
     let (sq_sender, sq_receiver) = mpsc::channel();
 
     // I would like to pass VR as the only quality flag to neable
@@ -183,8 +243,12 @@ pub fn try_start_stream<S: glium::Surface>(raw_header: &[u8]) -> Option<(Box<ogg
             handle.open_feed().unwrap();
             handle
         },
+        frame_queue: frames.output(),
+        frames: frames,
         sample_frequency: sample_freq,
-        aux_headers: aux_headers,
+        delay_remaining: delay,
+        padding: padding,
+        held_samples: Vec::new(),
     }) as Box<ogg::BitstreamDecoder>;
 
     let frontend = types::StreamDesc::Audio(
@@ -192,6 +256,8 @@ pub fn try_start_stream<S: glium::Surface>(raw_header: &[u8]) -> Option<(Box<ogg
             receiver: sq_receiver,
             ringbuffer: None,
             queued_samples: None,
+            role: role,
+            finished: false,
         }))
     );
 
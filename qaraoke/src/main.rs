@@ -19,9 +19,12 @@ extern crate glium_pib;
 pub mod rt;
 mod codec;
 mod ao;
+mod mix;
 
+use std::collections::BTreeMap;
 use std::rc::Rc;
 use std::error::Error;
+use std::sync::mpsc;
 
 use glium::backend::Facade;
 
@@ -30,6 +33,7 @@ pub mod types {
     use glium;
     use std::rc::Rc;
     use rt::ringbuffer;
+    use ogk::mp3::AudioRole;
     
     pub type Sample = [f32; 2];
 
@@ -57,6 +61,12 @@ pub mod types {
         /// Return the size of chunks that are produced into the buffer.
         fn min_buffer_size(&self) -> u32;
 
+        /// What part of the song this stream holds
+        fn role(&self) -> AudioRole;
+
+        /// True once every sample has been written to the ringbuffer
+        fn is_finished(&self) -> bool;
+
         /// Fill up the output buffer as much as possible.  Must be
         /// called at least once per buffer period.
         fn do_needful(&mut self);
@@ -66,6 +76,9 @@ pub mod types {
         /// Do pre-playback initialization. Compile shaders, set up
         /// textures, etc.
         fn initialize(&mut self, context: &Rc<glium::backend::Context>);
+        /// Show the video `offset` seconds later than the audio (or
+        /// earlier, if negative). May be changed during playback.
+        fn set_sync_offset(&mut self, offset: f64);
         /// Render a frame. initialize will be called first.
         /// when is measured in milliseconds since the start of playback.
         fn render_frame(&mut self, context: &Rc<glium::backend::Context>, target: &mut Surface, when: f64);
@@ -78,33 +91,74 @@ pub mod types {
     }
 }
 
+/// The codecs for one link of a chained file
+struct Link<S> {
+    /// Which link of the file it is, counting from 0
+    number: usize,
+    /// Start time, in seconds
+    start: f64,
+    audio: Option<mix::Mixer>,
+    video: Option<Box<types::VideoCodec<S>>>,
+}
+
 struct KaraokeSource<R, S> {
     demux: ogk::ogg::OggDemux<R, types::StreamDesc<S>>,
-    audio: Option<Box<types::AudioCodec>>,
+    audio: Option<mix::Mixer>,
     video: Option<Box<types::VideoCodec<S>>>,
+    /// Which link is playing, counting from 0
+    link: usize,
+    /// Start time of the current link, in seconds
+    link_start: f64,
+    /// The next link, once the demuxer has reached it
+    next_link: Option<Link<S>>,
+    /// How many links the demuxer has reached
+    links_found: usize,
 }
 
 impl <R: std::io::Read, S: glium::Surface + 'static> KaraokeSource<R, S> {
     pub fn from_stream(reader: R) -> Result<Self, Box<Error>> {
-        use types::StreamDesc;
         let mut source = KaraokeSource{
-            demux: try!(ogk::ogg::OggDemux::new(reader, codec::identify_header)),
+            demux: try!(ogk::ogg::OggDemux::with_registry_tolerant(reader, codec::registry())),
             audio: None,
             video: None,
+            link: 0,
+            link_start: 0.,
+            next_link: None,
+            links_found: 1,
         };
+        let (audio, video) = source.select_streams();
+        source.audio = audio;
+        source.video = video;
+
+        // Get the first chunk of packets processed
+        try!(source.demux.pump_until(1000_000));
+        source.poll_events();
+        Ok(source)
+    }
 
+    /// Pick the codecs to play from the current link and ignore the
+    /// rest of its streams. Each audio role gets its best stream.
+    fn select_streams(&mut self) -> (Option<mix::Mixer>, Option<Box<types::VideoCodec<S>>>) {
+        use types::StreamDesc;
         //let mut video = None;
-        source.audio = source.demux.streams()
-            .filter_map(|(_stream_id, stream)| match stream {
-                &mut StreamDesc::Audio(ref mut codec @ Some(_)) => Some(codec),
-                _ => None,
-            })
-            .max_by_key(|x| x.as_ref().unwrap().quality())
-            .map_or_else(
-                || None,
-                |stream| stream.take()
-            );
-        source.video = source.demux.streams()
+        let mut audio : Vec<&mut Option<Box<types::AudioCodec>>> = Vec::new();
+        for (_stream_id, stream) in self.demux.streams() {
+            if let &mut StreamDesc::Audio(ref mut codec @ Some(_)) = stream {
+                let (role, quality) = {
+                    let codec = codec.as_ref().unwrap();
+                    (codec.role(), codec.quality())
+                };
+                match audio.iter().position(|other| other.as_ref().unwrap().role() == role) {
+                    Some(i) => if quality > audio[i].as_ref().unwrap().quality() {
+                        audio[i] = codec;
+                    },
+                    None => audio.push(codec),
+                }
+            }
+        }
+        let audio : Vec<_> = audio.into_iter().filter_map(|codec| codec.take()).collect();
+        let audio = if audio.is_empty() { None } else { Some(mix::Mixer::new(audio)) };
+        let video = self.demux.streams()
             .filter_map(|(_stream_id, stream)| match stream {
                 &mut StreamDesc::Video(ref mut codec @ Some(_)) => Some(codec),
                 _ => None,
@@ -116,7 +170,7 @@ impl <R: std::io::Read, S: glium::Surface + 'static> KaraokeSource<R, S> {
             );
 
         // Close off the excess streams...
-        let discard_streams : Vec<_> = source.demux.streams()
+        let discard_streams : Vec<_> = self.demux.streams()
             .filter_map(|(id, stream)| {
                 match stream {
                     &mut StreamDesc::Audio(Some(_)) => Some(id),
@@ -126,13 +180,162 @@ impl <R: std::io::Read, S: glium::Surface + 'static> KaraokeSource<R, S> {
             })
             .collect();
         for stream in discard_streams {
-            source.demux.ignore_stream(stream)
+            self.demux.ignore_stream(stream)
         }
+        (audio, video)
+    }
 
-        // Get the first chunk of packets processed
-        try!(source.demux.pump_until(1000));
-        Ok(source)
+    fn poll_events(&mut self) {
+        use ogk::ogg::DemuxEvent;
+        while let Some(event) = self.demux.next_event() {
+            match event {
+                DemuxEvent::NewLink{start_time, ..} => {
+                    let (audio, video) = self.select_streams();
+                    self.next_link = Some(Link{
+                        number: self.links_found,
+                        start: start_time as f64 / 1000_000.,
+                        audio: audio,
+                        video: video,
+                    });
+                    self.links_found += 1;
+                },
+            }
+        }
+    }
+
+    /// Take the next link once playback has reached it
+    fn take_due_link(&mut self, time: f64) -> Option<Link<S>> {
+        if self.next_link.as_ref().map_or(false, |link| time >= link.start) {
+            self.next_link.take()
+        } else {
+            None
+        }
+    }
+}
+
+/// The sync offset of each song in a file, in seconds. They're kept
+/// beside the file in FILENAME.offset, one line per link of a chained
+/// file: the link number and a whole number of milliseconds. A line
+/// with only a number, as older versions wrote, applies to every link
+/// not listed.
+///
+/// The sidecar doesn't travel with the file. To fix a song for good,
+/// remux it with `ogk mux --cdg-offset`.
+struct SyncOffsets {
+    path: String,
+    default: f64,
+    links: BTreeMap<usize, f64>,
+}
+
+impl SyncOffsets {
+    fn load(filename: &str) -> Self {
+        use std::io::Read;
+        let mut offsets = SyncOffsets{
+            path: format!("{}.offset", filename),
+            default: 0.,
+            links: BTreeMap::new(),
+        };
+        let mut text = String::new();
+        if std::fs::File::open(&offsets.path).and_then(|mut f| f.read_to_string(&mut text)).is_err() {
+            return offsets;
+        }
+        for line in text.lines() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match (words.get(0).map(|w| w.parse::<usize>()), words.get(1).map(|w| w.parse::<f64>())) {
+                (Some(Ok(link)), Some(Ok(ms))) => { offsets.links.insert(link, ms / 1000.); },
+                (Some(_), None) => if let Ok(ms) = words[0].parse::<f64>() {
+                    offsets.default = ms / 1000.;
+                },
+                _ => (),
+            }
+        }
+        offsets
+    }
+
+    fn get(&self, link: usize) -> f64 {
+        *self.links.get(&link).unwrap_or(&self.default)
+    }
+
+    /// Change the offset of one link and save them all
+    fn set(&mut self, link: usize, offset: f64) {
+        use std::io::Write;
+        self.links.insert(link, offset);
+        let result = std::fs::File::create(&self.path).and_then(|mut f| {
+            if self.default != 0. {
+                try!(writeln!(f, "{}", (self.default * 1000.).round() as i64));
+            }
+            for (link, offset) in &self.links {
+                try!(writeln!(f, "{} {}", link, (offset * 1000.).round() as i64));
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            println!("Failed to save sync offset to {}: {}", self.path, e);
+        }
+    }
+}
+
+enum Control {
+    Nudge(f64),
+    ResetSync,
+    ToggleGuide,
+    Gain(usize, f32),
+}
+
+/// Read playback controls from stdin. Each "+" or "-" on a line moves
+/// the current song's graphics 10ms later or earlier; "0" puts them
+/// back; "g" turns
+/// the guide vocal on or off. A line "gain N X" sets the gain of
+/// audio stream N to X.
+fn read_controls() -> mpsc::Receiver<Control> {
+    use std::io::BufRead;
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let controls = if words.first() == Some(&"gain") {
+                match (words.get(1).and_then(|n| n.parse().ok()), words.get(2).and_then(|x| x.parse().ok())) {
+                    (Some(n), Some(x)) => vec![Control::Gain(n, x)],
+                    _ => {
+                        println!("Usage: gain STREAM GAIN");
+                        continue;
+                    },
+                }
+            } else {
+                line.chars().filter_map(|c| match c {
+                    '+' => Some(Control::Nudge(0.01)),
+                    '-' => Some(Control::Nudge(-0.01)),
+                    '0' => Some(Control::ResetSync),
+                    'g' => Some(Control::ToggleGuide),
+                    _ => None,
+                }).collect()
+            };
+            for control in controls {
+                if tx.send(control).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Hook up a link's audio streams and list them, so that their gains
+/// can be set by number
+fn start_audio(mixer: &mut mix::Mixer, guide: bool) -> rt::ringbuffer::Reader<types::Sample> {
+    for (i, role) in mixer.roles().into_iter().enumerate() {
+        println!("Audio stream {}: {}", i, role.name());
     }
+    mixer.set_guide(guide);
+    let (rd, wr) = rt::ringbuffer::new(96000);
+    mixer.set_ringbuffer(wr);
+    mixer.do_needful();
+    rd
 }
 
 // TODO: Add glium_pib for bare metal Raspberry Pi support
@@ -170,6 +373,10 @@ fn main() {
     let args: Vec<String> = std::env::args().collect();
     let filename = args.get(1).expect("Usage: $0 filename");
     let mut player = KaraokeSource::from_stream(fs::File::open(filename).unwrap()).unwrap();
+    let mut sync_offsets = SyncOffsets::load(filename);
+    let mut sync_offset = sync_offsets.get(0);
+    let mut guide = false;
+    let controls = read_controls();
     use glium::DisplayBuild;
 
     let display = glium::glutin::WindowBuilder::new().build_glium();
@@ -187,15 +394,13 @@ fn main() {
     {
         // Set up a stream
         if let Some(ref mut vcodec) = player.video {
-            vcodec.initialize(display.get_context())
+            vcodec.initialize(display.get_context());
+            vcodec.set_sync_offset(sync_offset);
         }
-        if let Some(ref mut acodec) = player.audio {
+        if let Some(ref mut mixer) = player.audio {
             // We cheat here and always initialize ring buffers to half a
             // second.
-            let (rd, wr) = rt::ringbuffer::new(96000);
-            acodec.set_ringbuffer(wr);
-            acodec.do_needful();
-            ao_driver.change_stream(Some(rd)).unwrap();
+            ao_driver.change_stream(Some(start_audio(mixer, guide))).unwrap();
         } else {
             ao_driver.change_stream(None).unwrap();
         }
@@ -212,17 +417,65 @@ fn main() {
     loop {
         // Do updates
         let time = ao_driver.timestamp();
+        if let Some(link) = player.take_due_link(time) {
+            // Switch to the codecs of the next chained link, and its
+            // own sync offset
+            player.link = link.number;
+            player.link_start = link.start;
+            sync_offset = sync_offsets.get(link.number);
+            player.video = link.video;
+            player.audio = link.audio;
+            if let Some(ref mut vcodec) = player.video {
+                vcodec.initialize(display.get_context());
+                vcodec.set_sync_offset(sync_offset);
+            }
+            if let Some(ref mut mixer) = player.audio {
+                ao_driver.change_stream(Some(start_audio(mixer, guide))).unwrap();
+            } else {
+                ao_driver.change_stream(None).unwrap();
+            }
+            ao_driver.commit().unwrap();
+        }
+        while let Ok(control) = controls.try_recv() {
+            match control {
+                Control::Nudge(delta) => sync_offset += delta,
+                Control::ResetSync => sync_offset = 0.,
+                Control::ToggleGuide => {
+                    guide = !guide;
+                    println!("Guide vocal {}", if guide { "on" } else { "off" });
+                    if let Some(ref mut mixer) = player.audio {
+                        mixer.set_guide(guide);
+                    }
+                    continue;
+                },
+                Control::Gain(stream, gain) => {
+                    if let Some(ref mut mixer) = player.audio {
+                        mixer.set_gain(stream, gain);
+                    }
+                    continue;
+                },
+            }
+            println!("Sync offset for link {}: {} ms (saved beside the file; bake it in with ogk mux --cdg-offset)",
+                     player.link, (sync_offset * 1000.).round());
+            sync_offsets.set(player.link, sync_offset);
+            if let Some(ref mut vcodec) = player.video {
+                vcodec.set_sync_offset(sync_offset);
+            }
+        }
         if let Some(ref mut vcodec) = player.video {
             let mut target = glium::Frame::new(
                 display.clone(),
                 display.get_framebuffer_dimensions(),
             );
-            vcodec.render_frame(display.get_context(), &mut target, time);
+            vcodec.render_frame(display.get_context(), &mut target, time - player.link_start);
             target.finish().unwrap();
         }
-        player.demux.pump_until((time * 1000. + 1000.) as u64).unwrap();
-        if let Some(ref mut acodec) = player.audio {
-            acodec.do_needful()
+        // Graphics shown early need to be decoded early too
+        let horizon = time + 1. + (-sync_offset).max(0.);
+        player.demux.pump_until((horizon * 1000_000.) as u64).unwrap();
+        player.poll_events();
+        if let Some(ref mut mixer) = player.audio {
+            mixer.do_needful()
         }
         // Handle events
         /*
@@ -0,0 +1,251 @@
+/// Mixes several audio streams of one song, each with its own gain.
+
+use ogk::mp3::AudioRole;
+use rt::ringbuffer;
+use types::{self, AudioCodec};
+
+/// How far a gain may move per sample, so that changes ramp over
+/// about 50ms instead of clicking
+const GAIN_STEP: f32 = 1. / 2400.;
+
+struct Input {
+    codec: Box<AudioCodec>,
+    reader: ringbuffer::Reader<types::Sample>,
+    gain: f32,
+    target_gain: f32,
+}
+
+pub struct Mixer {
+    inputs: Vec<Input>,
+    output: Option<ringbuffer::Writer<types::Sample>>,
+    scratch: Vec<types::Sample>,
+}
+
+/// The gain a stream starts with: the guide vocal is off until asked
+/// for, and roles we don't know are left out.
+fn default_gain(role: AudioRole) -> f32 {
+    match role {
+        AudioRole::GuideVocal | AudioRole::Other(_) => 0.,
+        _ => 1.,
+    }
+}
+
+impl Mixer {
+    pub fn new(codecs: Vec<Box<AudioCodec>>) -> Self {
+        let inputs = codecs.into_iter().map(|mut codec| {
+            // Half a second each, like the output
+            let (rd, wr) = ringbuffer::new(96000);
+            codec.set_ringbuffer(wr);
+            let gain = default_gain(codec.role());
+            Input{
+                codec: codec,
+                reader: rd,
+                gain: gain,
+                target_gain: gain,
+            }
+        }).collect();
+        Mixer{
+            inputs: inputs,
+            output: None,
+            scratch: Vec::new(),
+        }
+    }
+
+    pub fn roles(&self) -> Vec<AudioRole> {
+        self.inputs.iter().map(|input| input.codec.role()).collect()
+    }
+
+    pub fn set_gain(&mut self, index: usize, gain: f32) {
+        if let Some(input) = self.inputs.get_mut(index) {
+            input.target_gain = gain.max(0.);
+        }
+    }
+
+    /// Turn every guide vocal stream on or off
+    pub fn set_guide(&mut self, on: bool) {
+        for input in &mut self.inputs {
+            if input.codec.role() == AudioRole::GuideVocal {
+                input.target_gain = if on { 1. } else { 0. };
+            }
+        }
+    }
+
+    /// Sets the output ringbuffer, which expects to receive audio
+    /// samples at 48kHz.
+    pub fn set_ringbuffer(&mut self, ringbuffer: ringbuffer::Writer<types::Sample>) {
+        self.output = Some(ringbuffer);
+    }
+
+    /// Fill up the output buffer as much as possible.
+    pub fn do_needful(&mut self) {
+        for input in &mut self.inputs {
+            input.codec.do_needful();
+        }
+        let output = match self.output {
+            Some(ref mut output) => output,
+            None => return,
+        };
+
+        // Only mix what every stream has ready. Once a stream has
+        // finished, the others carry on without it.
+        let mut count = output.size();
+        let mut all_finished = true;
+        for input in &self.inputs {
+            if !input.codec.is_finished() {
+                all_finished = false;
+                count = count.min(input.reader.size());
+            }
+        }
+        if all_finished {
+            count = count.min(self.inputs.iter().map(|input| input.reader.size()).max().unwrap_or(0));
+        }
+        if count == 0 {
+            return;
+        }
+
+        self.scratch.clear();
+        self.scratch.resize(count, [0.; 2]);
+        for input in &mut self.inputs {
+            for (out, sample) in self.scratch.iter_mut().zip(input.reader.iter().take(count)) {
+                if input.gain < input.target_gain {
+                    input.gain = (input.gain + GAIN_STEP).min(input.target_gain);
+                } else if input.gain > input.target_gain {
+                    input.gain = (input.gain - GAIN_STEP).max(input.target_gain);
+                }
+                out[0] += sample[0] * input.gain;
+                out[1] += sample[1] * input.gain;
+            }
+        }
+        output.extender().extend(self.scratch.drain(..));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use ogk::mp3::AudioRole;
+    use rt::ringbuffer;
+    use types::{self, AudioCodec};
+    use super::Mixer;
+
+    /// Plays a constant level, at most `per_call` samples each time
+    /// it's asked to
+    struct Stub {
+        role: AudioRole,
+        pending: VecDeque<types::Sample>,
+        per_call: usize,
+        output: Option<ringbuffer::Writer<types::Sample>>,
+    }
+
+    fn stub(role: AudioRole, level: f32, len: usize, per_call: usize) -> Box<AudioCodec> {
+        Box::new(Stub{
+            role: role,
+            pending: vec![[level; 2]; len].into_iter().collect(),
+            per_call: per_call,
+            output: None,
+        })
+    }
+
+    impl AudioCodec for Stub {
+        fn quality(&self) -> u32 { 0 }
+
+        fn set_ringbuffer(&mut self, ringbuffer: ringbuffer::Writer<types::Sample>) {
+            self.output = Some(ringbuffer);
+        }
+
+        fn min_buffer_size(&self) -> u32 { 1 }
+
+        fn role(&self) -> AudioRole { self.role }
+
+        fn is_finished(&self) -> bool { self.pending.is_empty() }
+
+        fn do_needful(&mut self) {
+            let output = self.output.as_mut().unwrap();
+            for _ in 0..self.per_call {
+                match self.pending.pop_front() {
+                    Some(sample) => output.push(sample).unwrap(),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Mix once, and take whatever came out
+    fn mix(mixer: &mut Mixer, output: &mut ringbuffer::Reader<types::Sample>) -> Vec<f32> {
+        mixer.do_needful();
+        output.iter().map(|sample| {
+            assert_eq!(sample[0], sample[1]);
+            sample[0]
+        }).collect()
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-3, "{} is not {}", actual, expected);
+    }
+
+    #[test]
+    fn gain_changes_ramp_over_50ms() {
+        let mut mixer = Mixer::new(vec![stub(AudioRole::Main, 1., 6000, 6000)]);
+        let (mut rd, wr) = ringbuffer::new(8192);
+        mixer.set_ringbuffer(wr);
+        mixer.set_gain(0, 0.);
+        let out = mix(&mut mixer, &mut rd);
+        assert_eq!(out.len(), 6000);
+        // 2400 samples at 48kHz
+        assert_near(out[0], 1. - 1. / 2400.);
+        assert_near(out[1199], 0.5);
+        assert!(out[2398] > 0.);
+        assert!(out[2399..].iter().all(|&level| level == 0.));
+    }
+
+    #[test]
+    fn guide_vocals_fade_in_and_out_mid_stream() {
+        let mut mixer = Mixer::new(vec![
+            stub(AudioRole::Instrumental, 0.25, 12000, 12000),
+            stub(AudioRole::GuideVocal, 0.5, 12000, 12000),
+        ]);
+        let (mut rd, wr) = ringbuffer::new(4000);
+        mixer.set_ringbuffer(wr);
+
+        // The guide starts off
+        let before = mix(&mut mixer, &mut rd);
+        assert_eq!(before.len(), 4095);
+        assert!(before.iter().all(|&level| level == 0.25));
+
+        mixer.set_guide(true);
+        let during = mix(&mut mixer, &mut rd);
+        assert_eq!(during.len(), 4095);
+        assert_near(during[1199], 0.5);
+        assert!(during[2399..].iter().all(|&level| level == 0.75));
+
+        mixer.set_guide(false);
+        let after = mix(&mut mixer, &mut rd);
+        assert_eq!(after.len(), 12000 - 2 * 4095);
+        assert_near(after[1199], 0.5);
+        assert!(after[2399..].iter().all(|&level| level == 0.25));
+    }
+
+    #[test]
+    fn finished_streams_stop_holding_up_the_rest() {
+        // The main stream is all decoded up front; the vocals trickle
+        // in and finish first
+        let mut mixer = Mixer::new(vec![
+            stub(AudioRole::Main, 1., 3000, 3000),
+            stub(AudioRole::BackingVocals, 0.5, 1200, 600),
+        ]);
+        let (mut rd, wr) = ringbuffer::new(8192);
+        mixer.set_ringbuffer(wr);
+
+        // Only what both have ready
+        let first = mix(&mut mixer, &mut rd);
+        assert_eq!(first, vec![1.5; 600]);
+
+        // Then the rest of the main stream, once the vocals are done
+        let second = mix(&mut mixer, &mut rd);
+        assert_eq!(second.len(), 2400);
+        assert!(second[..600].iter().all(|&level| level == 1.5));
+        assert!(second[600..].iter().all(|&level| level == 1.));
+
+        assert!(mix(&mut mixer, &mut rd).is_empty());
+    }
+}